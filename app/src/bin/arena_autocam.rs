@@ -36,7 +36,7 @@ fn main() -> Result<()> {
 
     match create_pipeline(&config)
         .and_then(|res| {
-            let hardware = init_hardware_systems()?;
+            let hardware = init_hardware_systems(&config)?;
             configure_pipeline(&config, hardware, res)
         })
         .and_then(run_main_loop)
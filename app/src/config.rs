@@ -1,8 +1,10 @@
+use std::collections::BTreeSet;
 use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
 
 use anyhow::*;
 use chrono::{DateTime, Duration, Local};
-use clap::{ArgGroup, Args};
+use clap::{ArgGroup, Args, ValueEnum};
 use figment::providers::{Format, Serialized, Toml};
 use figment::value::magic::RelativePathBuf;
 use figment::Figment;
@@ -22,7 +24,7 @@ pub(self) static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
 });
 
 // The application's configuration.
-#[derive(Args, Debug, Deserialize, Serialize)]
+#[derive(Args, Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
     #[command(flatten)]
     pub source: SourceConfig,
@@ -32,17 +34,42 @@ pub struct Config {
 
     #[command(flatten)]
     pub video_storage: VideoStorageConfig,
+
+    #[command(flatten)]
+    pub encoder: EncoderConfig,
+
+    #[command(flatten)]
+    pub recording: RecordingConfig,
+
+    #[command(flatten)]
+    pub preview: PreviewConfig,
+
+    #[command(flatten)]
+    pub upload: UploadConfig,
+
+    #[command(flatten)]
+    pub tracking: TrackingConfig,
+
+    #[command(flatten)]
+    pub hardware: HardwareConfig,
 }
 
 impl Validate for Config {
     fn validate(&self) -> Result<&Self> {
+        self.source.validate()?;
         self.detection.validate()?;
         self.video_storage.validate()?;
+        self.encoder.validate()?;
+        self.recording.validate()?;
+        self.preview.validate()?;
+        self.upload.validate()?;
+        self.tracking.validate()?;
+        self.hardware.validate()?;
         Ok(&self)
     }
 }
 
-#[derive(Args, Debug, Deserialize, Serialize)]
+#[derive(Args, Clone, Debug, Deserialize, Serialize)]
 pub struct SourceConfig {
     /// The preferred width of the record stream (in pixels)
     #[arg(long, default_value_t = 1280)]
@@ -68,10 +95,48 @@ pub struct SourceConfig {
     /// This is primarily for debugging
     #[arg(long, value_name = "FILE")]
     pub debug_source_video_path: Option<String>,
+
+    /// If provided along with `--debug-source-video-path`, the pipeline will only see a
+    /// viewport of the debug video, sized as this fraction of the video's dimensions.
+    ///
+    /// The viewport is moved around the video by the simulated pan/tilt motors, which
+    /// allows the full tracking loop to be exercised without hardware.
+    #[arg(long, requires = "debug_source_video_path")]
+    pub debug_viewport_fraction: Option<f64>,
+
+    /// The number of seconds the source can go without producing frames before it's
+    /// torn down and rebuilt. 0 disables the watchdog.
+    #[arg(long, default_value_t = 10)]
+    pub source_stall_timeout_secs: u64,
+
+    /// If provided along with `--debug-source-video-path`, the debug video stops
+    /// producing frames this many seconds after it starts, to simulate a stalled camera.
+    #[arg(long, requires = "debug_source_video_path")]
+    pub debug_simulate_stall_secs: Option<f64>,
+}
+
+impl SourceConfig {
+    pub fn source_stall_timeout(&self) -> Option<std::time::Duration> {
+        match self.source_stall_timeout_secs {
+            0 => None,
+            secs => Some(std::time::Duration::from_secs(secs)),
+        }
+    }
 }
 
 impl Validate for SourceConfig {
     fn validate(&self) -> Result<&Self> {
+        if let Some(fraction) = self.debug_viewport_fraction {
+            if !(fraction > 0.0 && fraction <= 1.0) {
+                return Err(anyhow!("source.debug_viewport_fraction must be >0 and <=1"));
+            }
+        }
+        if let Some(secs) = self.debug_simulate_stall_secs {
+            if !(secs >= 0.0 && secs.is_finite()) {
+                return Err(anyhow!("source.debug_simulate_stall_secs must be >=0"));
+            }
+        }
+
         match self.debug_source_video_path {
             Some(ref path) => {
                 if PathBuf::from(path).is_file() {
@@ -89,7 +154,7 @@ impl Validate for SourceConfig {
 ///
 /// In general, this configures Tensorflow Lite, but it can also be configured to use
 /// a color detection algorithm for debugging with less complexity.
-#[derive(Args, Debug, Deserialize, Serialize)]
+#[derive(Args, Clone, Debug, Deserialize, Serialize)]
 #[command(group(
     ArgGroup::new("debug_color_detection_group")
         .args(["color_detection_pixel_threshold"])
@@ -101,6 +166,17 @@ pub struct DetectionConfig {
     #[arg(long, value_name = "FILE", default_value = "./")]
     pub model_path: RelativePathBuf,
 
+    /// If provided, a second Tensorflow Lite model that's compared against `model_path`.
+    /// The two models are run on alternating frames, and their stats are logged
+    /// periodically.
+    #[serde(serialize_with = "serialize_optional_relative")]
+    #[arg(long, value_name = "FILE")]
+    pub model_b_path: Option<RelativePathBuf>,
+
+    /// If true, models are reloaded when their files change.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub watch_model_files: bool,
+
     /// The maximum number of results returned by the model per inference run.
     #[arg(long, default_value_t = 6)]
     pub max_results: u32,
@@ -109,6 +185,16 @@ pub struct DetectionConfig {
     #[arg(long, default_value_t = 0.2)]
     pub score_threshold: f32,
 
+    /// The number of threads each model uses for inference. Defaults to the number of
+    /// CPU cores.
+    #[arg(long)]
+    pub inference_threads: Option<u32>,
+
+    /// The hardware that runs inference. If the delegate fails to initialize, inference
+    /// falls back to the CPU.
+    #[arg(long, value_enum, default_value_t = InferenceDelegate::Cpu)]
+    pub inference_delegate: InferenceDelegate,
+
     #[arg(long, default_value_t = 5.0)]
     pub rate_per_second: f32,
 
@@ -121,6 +207,47 @@ pub struct DetectionConfig {
     /// detection, if `--debug-use-color-detection` is enabled.
     #[arg(long, default_value_t = 10)]
     pub color_detection_pixel_threshold: u32,
+
+    /// Decides which of a frame's tracked objects the camera should follow.
+    #[arg(long, value_enum, default_value_t = TargetSelectionPolicy::ClosestToPrevious)]
+    pub target_selection_policy: TargetSelectionPolicy,
+
+    /// The label of riders, whose boxes are combined with the horse they overlap when
+    /// using the `merged-horse-rider` policy.
+    #[arg(long, default_value = "person")]
+    pub rider_label: String,
+
+    /// The fraction of a rider's box that must overlap a horse's box for the two to be
+    /// merged by the `merged-horse-rider` policy.
+    #[arg(long, default_value_t = 0.3)]
+    pub rider_merge_min_overlap: f64,
+}
+
+/// The hardware that runs inference
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum InferenceDelegate {
+    /// The CPU, accelerated by XNNPACK if tflite-support was built with its `xnnpack`
+    /// feature
+    Cpu,
+    /// A Coral Edge TPU. Requires the `coral-tpu` feature.
+    Coral,
+}
+
+/// The rules used to choose a single target from the objects tracked in a frame.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TargetSelectionPolicy {
+    /// The target with the largest bounding box
+    LargestBox,
+    /// The target with the highest detection score
+    HighestScore,
+    /// The target whose center is closest to the previously selected target. Falls back
+    /// to the highest score when there is no previous target.
+    ClosestToPrevious,
+    /// The highest scoring horse, with the boxes of any overlapping riders merged into
+    /// its own.
+    MergedHorseRider,
 }
 
 impl DetectionConfig {
@@ -128,6 +255,13 @@ impl DetectionConfig {
         Duration::milliseconds((1000.0 / self.rate_per_second) as i64)
     }
 
+    /// The number of threads each model uses for inference
+    pub fn inference_threads(&self) -> u32 {
+        self.inference_threads.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, |n| n.get() as u32)
+        })
+    }
+
     /// `true` if the application is configured to use machine learning
     pub fn is_ml(&self) -> bool {
         !self.debug_use_color_detection
@@ -139,11 +273,30 @@ impl Validate for DetectionConfig {
         if !self.debug_use_color_detection && !self.model_path.relative().is_file() {
             return Err(anyhow!(r"inference.model_path: file not found"));
         }
+        if self.inference_threads == Some(0) {
+            return Err(anyhow!("detection.inference_threads must be >0"));
+        }
+        if self.inference_delegate == InferenceDelegate::Coral && !cfg!(feature = "coral-tpu")
+        {
+            return Err(anyhow!(
+                "detection.inference_delegate: coral requires the coral-tpu feature"
+            ));
+        }
+        if let Some(ref path) = self.model_b_path {
+            if !path.relative().is_file() {
+                return Err(anyhow!(r"inference.model_b_path: file not found"));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.rider_merge_min_overlap) {
+            return Err(anyhow!(
+                "detection.rider_merge_min_overlap must be between 0 and 1"
+            ));
+        }
         return Ok(self);
     }
 }
 
-#[derive(Args, Debug, Deserialize, Serialize)]
+#[derive(Args, Clone, Debug, Deserialize, Serialize)]
 pub struct VideoStorageConfig {
     /// The path where videos are stored locally before uploading.
     #[serde(serialize_with = "RelativePathBuf::serialize_relative")]
@@ -164,6 +317,93 @@ pub struct VideoStorageConfig {
     /// The number of seconds of video written to a chunk before creating a new one.
     #[arg(long, default_value_t = 240)]
     pub video_chunk_duration_secs: u64,
+
+    /// The container that video chunks are written in. This also decides the chunks'
+    /// file extension.
+    #[arg(long, value_enum, default_value_t = ContainerFormat::Mp4)]
+    pub container_format: ContainerFormat,
+
+    /// How MP4 chunks are laid out. Only valid when `--container-format` is `mp4`.
+    ///
+    /// Fragmented files remain playable if recording is interrupted, while faststart
+    /// files are more widely supported by players and editors.
+    #[arg(long, value_enum)]
+    pub mp4_layout: Option<Mp4Layout>,
+
+    /// The most disk space recorded chunks may use, in MiB. If not provided, usage is
+    /// only limited by `--min-free-space-mib`.
+    #[arg(long)]
+    pub max_disk_usage_mib: Option<u64>,
+
+    /// The free space that must remain on the storage device, in MiB.
+    ///
+    /// Old chunks are deleted as free space approaches this limit. If deleting isn't
+    /// enough, recording switches to `--low-space-bitrate-kbps`, then pauses once the
+    /// limit is reached.
+    #[arg(long, default_value_t = 1024)]
+    pub min_free_space_mib: u64,
+
+    /// The number of hours a chunk is kept before it's deleted. If not provided, chunks
+    /// are only deleted to make space.
+    #[arg(long)]
+    pub max_chunk_age_hours: Option<u64>,
+
+    /// The encoder bitrate used while space is low, in kbit/s. If not provided, or the
+    /// V4L2 encoder is used, recording continues at its usual bitrate until it is paused.
+    #[arg(long)]
+    pub low_space_bitrate_kbps: Option<u32>,
+
+    /// If true, uploaded chunks are kept locally until their space is needed, rather
+    /// than being deleted as soon as they're uploaded.
+    #[arg(long, default_value_t = false)]
+    pub keep_uploaded_chunks: bool,
+
+    /// The number of seconds between checks of disk usage.
+    #[arg(long, default_value_t = 30)]
+    pub retention_check_interval_secs: u64,
+}
+
+/// The container formats that video chunks can be written in
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContainerFormat {
+    /// MPEG-4 Part 14
+    Mp4,
+    /// Matroska
+    Mkv,
+    /// MPEG transport stream
+    Mpegts,
+}
+
+impl ContainerFormat {
+    /// The extension given to files written in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ContainerFormat::Mp4 => "mp4",
+            ContainerFormat::Mkv => "mkv",
+            ContainerFormat::Mpegts => "ts",
+        }
+    }
+
+    /// The name of the GStreamer element factory that writes this format
+    pub fn muxer_factory(&self) -> &'static str {
+        match self {
+            ContainerFormat::Mp4 => "mp4mux",
+            ContainerFormat::Mkv => "matroskamux",
+            ContainerFormat::Mpegts => "mpegtsmux",
+        }
+    }
+}
+
+/// The ways an MP4 file's metadata can be arranged
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mp4Layout {
+    /// Metadata is written alongside the video in fragments
+    #[default]
+    Fragmented,
+    /// Metadata is written at the start of the file once the chunk is complete
+    Faststart,
 }
 
 impl VideoStorageConfig {
@@ -177,12 +417,40 @@ impl VideoStorageConfig {
             session_datetime => ts.format("%Y%m%dT%H%M%S").to_string(),
             chunk_number => "%04d")?;
         path.push(basename);
-        path.set_extension("mp4");
+        path.set_extension(self.container_format.extension());
         Ok(path
             .to_str()
             .expect("Conversion between path and string failed")
             .to_string())
     }
+
+    /// Returns the path of the metadata sidecar written for the session started at `ts`.
+    pub fn session_metadata_path_for_datetime(&self, ts: DateTime<Local>) -> Result<PathBuf> {
+        let mut path = self.temp_dir_path.relative().canonicalize()?;
+        let basename = strfmt!(self.video_filename_basename.as_str(),
+            session_datetime => ts.format("%Y%m%dT%H%M%S").to_string(),
+            chunk_number => "metadata")?;
+        path.push(basename);
+        path.set_extension("json");
+        Ok(path)
+    }
+
+    pub fn max_disk_usage_bytes(&self) -> Option<u64> {
+        self.max_disk_usage_mib.map(|mib| mib * 1024 * 1024)
+    }
+
+    pub fn min_free_space_bytes(&self) -> u64 {
+        self.min_free_space_mib * 1024 * 1024
+    }
+
+    pub fn max_chunk_age(&self) -> Option<std::time::Duration> {
+        self.max_chunk_age_hours
+            .map(|hours| std::time::Duration::from_secs(hours * 60 * 60))
+    }
+
+    pub fn retention_check_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.retention_check_interval_secs)
+    }
 }
 
 impl Validate for VideoStorageConfig {
@@ -209,6 +477,545 @@ impl Validate for VideoStorageConfig {
             ));
         }
 
+        if self.retention_check_interval_secs == 0 {
+            return Err(anyhow!(
+                "video_storage.retention_check_interval_secs must be >0"
+            ));
+        }
+        if self.low_space_bitrate_kbps == Some(0) {
+            return Err(anyhow!("video_storage.low_space_bitrate_kbps must be >0"));
+        }
+        if self.low_space_bitrate_kbps > Some(MAX_ENCODER_BITRATE_KBPS) {
+            return Err(anyhow!(
+                "video_storage.low_space_bitrate_kbps must be <={}",
+                MAX_ENCODER_BITRATE_KBPS
+            ));
+        }
+
+        if self.mp4_layout.is_some() && self.container_format != ContainerFormat::Mp4 {
+            return Err(anyhow!(
+                "video_storage.mp4_layout: only valid with the mp4 container_format, not {}",
+                self.container_format.extension()
+            ));
+        }
+
+        // The application adds the extension, so an explicit one would either be
+        // duplicated, or contradict the container
+        if let Some(extension) = PathBuf::from(&self.video_filename_basename).extension() {
+            return Err(anyhow!(
+                "video_storage.video_filename_basename: must not have an extension (found \
+                 .{}), as .{} is added for the {:?} container_format",
+                extension.to_string_lossy(),
+                self.container_format.extension(),
+                self.container_format
+            ));
+        }
+
+        Ok(self)
+    }
+}
+
+/// Configures the encoder that compresses video for recording.
+#[derive(Args, Clone, Debug, Deserialize, Serialize)]
+pub struct EncoderConfig {
+    /// The H.264 encoder used for recordings.
+    #[arg(long, value_enum, default_value_t = VideoEncoder::Auto)]
+    pub encoder: VideoEncoder,
+
+    /// The encoder's target bitrate, in kbit/s.
+    #[arg(long, default_value_t = 4000)]
+    pub encoder_bitrate_kbps: u32,
+
+    /// The most frames between keyframes. Chunks can only be split at keyframes.
+    #[arg(long, default_value_t = 60)]
+    pub encoder_gop_size: u32,
+
+    /// The H.264 profile of the encoded video.
+    #[arg(long, value_enum, default_value_t = H264Profile::High)]
+    pub encoder_profile: H264Profile,
+
+    /// The H.264 level of the encoded video, eg. `4` or `4.1`.
+    #[arg(long, default_value = "4")]
+    pub encoder_level: String,
+}
+
+/// The H.264 encoders that can be used for recordings
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum VideoEncoder {
+    /// The first encoder available, preferring hardware encoders (V4L2, VA-API, then
+    /// NVENC) over software ones
+    Auto,
+    /// The V4L2 hardware encoder found on the Raspberry Pi
+    V4l2,
+    /// The x264 software encoder
+    X264,
+    /// Cisco's OpenH264 software encoder
+    Openh264,
+    /// VA-API hardware encoding, on Intel and AMD GPUs
+    Vaapi,
+    /// NVENC hardware encoding, on NVIDIA GPUs
+    Nvenc,
+}
+
+impl VideoEncoder {
+    /// The name of the GStreamer element factory that implements this encoder, or
+    /// `None` for `Auto`
+    pub fn factory_name(&self) -> Option<&'static str> {
+        match self {
+            VideoEncoder::Auto => None,
+            VideoEncoder::V4l2 => Some("v4l2h264enc"),
+            VideoEncoder::X264 => Some("x264enc"),
+            VideoEncoder::Openh264 => Some("openh264enc"),
+            VideoEncoder::Vaapi => Some("vaapih264enc"),
+            VideoEncoder::Nvenc => Some("nvh264enc"),
+        }
+    }
+}
+
+/// The H.264 profiles that recordings can be encoded with
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum H264Profile {
+    ConstrainedBaseline,
+    Baseline,
+    Main,
+    High,
+}
+
+impl H264Profile {
+    /// The profile's name in H.264 caps
+    pub fn caps_name(&self) -> &'static str {
+        match self {
+            H264Profile::ConstrainedBaseline => "constrained-baseline",
+            H264Profile::Baseline => "baseline",
+            H264Profile::Main => "main",
+            H264Profile::High => "high",
+        }
+    }
+}
+
+/// The highest bitrate accepted by every encoder, in kbit/s
+pub const MAX_ENCODER_BITRATE_KBPS: u32 = 2_048_000;
+
+/// The H.264 levels, as they're named in caps
+const H264_LEVELS: [&str; 20] = [
+    "1", "1b", "1.1", "1.2", "1.3", "2", "2.1", "2.2", "3", "3.1", "3.2", "4", "4.1", "4.2",
+    "5", "5.1", "5.2", "6", "6.1", "6.2",
+];
+
+impl Validate for EncoderConfig {
+    fn validate(&self) -> Result<&Self> {
+        if self.encoder_bitrate_kbps == 0 {
+            return Err(anyhow!("encoder.encoder_bitrate_kbps must be >0"));
+        }
+        if self.encoder_bitrate_kbps > MAX_ENCODER_BITRATE_KBPS {
+            return Err(anyhow!(
+                "encoder.encoder_bitrate_kbps must be <={}",
+                MAX_ENCODER_BITRATE_KBPS
+            ));
+        }
+        if self.encoder_gop_size == 0 {
+            return Err(anyhow!("encoder.encoder_gop_size must be >0"));
+        }
+        if !H264_LEVELS.contains(&self.encoder_level.as_str()) {
+            return Err(anyhow!(
+                "encoder.encoder_level: {:?} isn't an H.264 level",
+                self.encoder_level
+            ));
+        }
+        Ok(self)
+    }
+}
+
+/// Configures when recording sessions start and stop.
+#[derive(Args, Clone, Debug, Deserialize, Serialize)]
+pub struct RecordingConfig {
+    /// Decides when recording sessions are started and stopped.
+    #[arg(long, value_enum, default_value_t = RecordMode::Continuous)]
+    pub record_mode: RecordMode,
+
+    /// The number of seconds that target labels must be detected before the `activity`
+    /// mode starts a session.
+    #[arg(long, default_value_t = 3.0)]
+    pub activity_start_secs: f64,
+
+    /// The number of seconds without target label detections before the `activity`
+    /// mode stops a session.
+    #[arg(long, default_value_t = 30.0)]
+    pub activity_stop_secs: f64,
+
+    /// The number of seconds of video from before a session starts that are included in
+    /// its recording, in the `activity` mode. Everything written to disk is delayed by
+    /// this amount.
+    #[arg(long, default_value_t = 5.0)]
+    pub preroll_secs: f64,
+}
+
+/// The ways recording sessions can be started and stopped
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum RecordMode {
+    /// A session is started when the application starts, and runs until it exits
+    Continuous,
+    /// Sessions are only started and stopped on request
+    Manual,
+    /// Sessions are started when a target is seen, and stopped once it has gone
+    Activity,
+}
+
+impl RecordingConfig {
+    pub fn activity_start_duration(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(self.activity_start_secs)
+    }
+
+    pub fn activity_stop_duration(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(self.activity_stop_secs)
+    }
+
+    pub fn preroll_duration(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(self.preroll_secs)
+    }
+}
+
+impl Validate for RecordingConfig {
+    fn validate(&self) -> Result<&Self> {
+        for (name, value) in [
+            ("activity_start_secs", self.activity_start_secs),
+            ("activity_stop_secs", self.activity_stop_secs),
+            ("preroll_secs", self.preroll_secs),
+        ] {
+            if !(value >= 0.0 && value.is_finite()) {
+                return Err(anyhow!("recording.{} must be >=0", name));
+            }
+        }
+        Ok(self)
+    }
+}
+
+/// Configures how the live display stream is previewed.
+#[derive(Args, Clone, Debug, Deserialize, Serialize)]
+pub struct PreviewConfig {
+    /// Where the live display stream is shown.
+    #[arg(long, value_enum, default_value_t = PreviewMode::Window)]
+    pub preview_mode: PreviewMode,
+
+    /// If true, detections are drawn over the preview.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub preview_overlay: bool,
+
+    /// The width of the network preview (in pixels). Its height preserves the display
+    /// stream's aspect ratio.
+    #[arg(long, default_value_t = 640)]
+    pub preview_width: i32,
+
+    /// The maximum frame rate of the `mjpeg` preview.
+    #[arg(long, default_value_t = 10)]
+    pub preview_max_fps: i32,
+
+    /// The JPEG quality (0-100) of the `mjpeg` preview.
+    #[arg(long, default_value_t = 70)]
+    pub preview_jpeg_quality: i32,
+
+    /// The signalling server used by the `webrtc` preview. If not provided,
+    /// webrtcsink's own default is used.
+    #[arg(long)]
+    pub preview_webrtc_signaller_uri: Option<String>,
+}
+
+/// The places the live display stream can be previewed
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum PreviewMode {
+    /// The stream isn't previewed
+    None,
+    /// A local window, which requires a display
+    Window,
+    /// Low-latency H.264 streamed over WebRTC, via webrtcsink
+    Webrtc,
+    /// A Motion JPEG stream served by the web server, for clients without WebRTC
+    Mjpeg,
+}
+
+impl Validate for PreviewConfig {
+    fn validate(&self) -> Result<&Self> {
+        if self.preview_width < 2 {
+            return Err(anyhow!("preview.preview_width must be >=2"));
+        }
+        if self.preview_max_fps < 1 {
+            return Err(anyhow!("preview.preview_max_fps must be >=1"));
+        }
+        if !(0..=100).contains(&self.preview_jpeg_quality) {
+            return Err(anyhow!(
+                "preview.preview_jpeg_quality must be between 0 and 100"
+            ));
+        }
+        Ok(self)
+    }
+}
+
+/// Configures where finished video chunks are uploaded.
+#[derive(Args, Clone, Debug, Deserialize, Serialize)]
+pub struct UploadConfig {
+    /// The kind of storage that finished video chunks are uploaded to. If not provided,
+    /// chunks are left in `--temp-dir-path`.
+    #[arg(long, value_enum)]
+    pub upload_backend: Option<UploadBackendKind>,
+
+    /// Where chunks are uploaded to. Depending on the backend, this is:
+    ///
+    ///     directory: the path of an existing directory
+    ///     webdav:    the URL of a collection, like https://host/videos/
+    ///     s3:        a path-style bucket URL, like https://host:9000/bucket/prefix
+    #[arg(long, value_name = "URL")]
+    pub upload_destination: Option<String>,
+
+    /// The username used to authenticate with WebDAV, or the access key ID used with S3
+    #[arg(long)]
+    pub upload_username: Option<String>,
+
+    /// The password used to authenticate with WebDAV, or the secret access key used with
+    /// S3
+    #[arg(long)]
+    pub upload_password: Option<String>,
+
+    /// The region that S3 requests are signed for
+    #[arg(long, default_value = "us-east-1")]
+    pub upload_s3_region: String,
+
+    /// The size of each part of an S3 multipart upload, in MiB. Interrupted uploads
+    /// resume from the last complete part.
+    #[arg(long, default_value_t = 8)]
+    pub upload_s3_part_size_mib: u64,
+
+    /// The longest the uploader will wait between attempts to upload a chunk, in
+    /// seconds. Waits double after each failure, up to this limit.
+    #[arg(long, default_value_t = 300)]
+    pub upload_max_retry_delay_secs: u64,
+}
+
+/// The kinds of storage that video chunks can be uploaded to
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum UploadBackendKind {
+    /// A directory on a local or mounted filesystem
+    Directory,
+    /// A WebDAV collection
+    Webdav,
+    /// An S3-compatible bucket
+    S3,
+}
+
+impl UploadConfig {
+    pub fn s3_part_size_bytes(&self) -> u64 {
+        self.upload_s3_part_size_mib * 1024 * 1024
+    }
+
+    pub fn max_retry_delay(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.upload_max_retry_delay_secs)
+    }
+}
+
+impl Validate for UploadConfig {
+    fn validate(&self) -> Result<&Self> {
+        let backend = match self.upload_backend {
+            Some(backend) => backend,
+            None => return Ok(self),
+        };
+        let destination = self.upload_destination.as_ref().ok_or(anyhow!(
+            "upload.upload_destination: required by {:?}",
+            backend
+        ))?;
+
+        match backend {
+            UploadBackendKind::Directory => {
+                if !PathBuf::from(destination).is_dir() {
+                    return Err(anyhow!("upload.upload_destination: directory not found"));
+                }
+            }
+            UploadBackendKind::Webdav | UploadBackendKind::S3 => {
+                if !destination.starts_with("http://") && !destination.starts_with("https://")
+                {
+                    return Err(anyhow!(
+                        "upload.upload_destination: must be an http(s) URL for {:?}",
+                        backend
+                    ));
+                }
+            }
+        }
+
+        if backend == UploadBackendKind::S3 {
+            if self.upload_username.is_none() || self.upload_password.is_none() {
+                return Err(anyhow!(
+                    "upload: S3 requires upload_username and upload_password"
+                ));
+            }
+            // S3 rejects non-final parts smaller than 5MiB
+            if self.upload_s3_part_size_mib < 5 {
+                return Err(anyhow!("upload.upload_s3_part_size_mib must be >=5"));
+            }
+        }
+
+        Ok(self)
+    }
+}
+
+/// Configures how detections are turned into camera movement.
+#[derive(Args, Clone, Debug, Deserialize, Serialize)]
+pub struct TrackingConfig {
+    /// The detection labels that the camera will follow. Detections with other labels
+    /// are ignored by the pan controller.
+    #[arg(long, value_delimiter = ',', default_value = "horse")]
+    pub target_labels: Vec<String>,
+
+    /// The number of pan steps required to move the camera's view by the full width of
+    /// the frame.
+    #[arg(long, default_value_t = 400.0)]
+    pub pan_steps_per_frame_width: f64,
+
+    /// The fraction of the correction applied on each inference frame. Lower values
+    /// produce gentler, slower corrections.
+    #[arg(long, default_value_t = 0.5)]
+    pub pan_gain: f64,
+
+    /// Horizontal offsets from the frame's center (as a fraction of the frame width)
+    /// smaller than this value will not move the camera.
+    #[arg(long, default_value_t = 0.05)]
+    pub pan_dead_zone: f64,
+
+    /// The number of tilt steps required to move the camera's view by the full height of
+    /// the frame.
+    #[arg(long, default_value_t = 225.0)]
+    pub tilt_steps_per_frame_height: f64,
+
+    /// The fraction of the tilt correction applied on each inference frame.
+    #[arg(long, default_value_t = 0.3)]
+    pub tilt_gain: f64,
+
+    /// Vertical offsets from the frame's center (as a fraction of the frame height)
+    /// smaller than this value will not move the camera.
+    #[arg(long, default_value_t = 0.1)]
+    pub tilt_dead_zone: f64,
+
+    /// The minimum intersection-over-union between a track's predicted bounds and a
+    /// detection for the two to be associated.
+    #[arg(long, default_value_t = 0.3)]
+    pub track_min_iou: f64,
+
+    /// The number of frames an object must be detected in before it is reported as a
+    /// track.
+    #[arg(long, default_value_t = 2)]
+    pub track_min_hits: u32,
+
+    /// The number of consecutive frames a track can go without a matching detection
+    /// before it is considered lost.
+    #[arg(long, default_value_t = 5)]
+    pub track_max_missed_frames: u32,
+}
+
+impl Validate for TrackingConfig {
+    fn validate(&self) -> Result<&Self> {
+        if self.target_labels.is_empty() {
+            return Err(anyhow!("tracking.target_labels must not be empty"));
+        }
+        if self.pan_steps_per_frame_width <= 0.0 {
+            return Err(anyhow!("tracking.pan_steps_per_frame_width must be > 0"));
+        }
+        if !(0.0..=1.0).contains(&self.pan_gain) {
+            return Err(anyhow!("tracking.pan_gain must be between 0 and 1"));
+        }
+        if !(0.0..0.5).contains(&self.pan_dead_zone) {
+            return Err(anyhow!("tracking.pan_dead_zone must be >=0 and <0.5"));
+        }
+        if self.tilt_steps_per_frame_height <= 0.0 {
+            return Err(anyhow!("tracking.tilt_steps_per_frame_height must be > 0"));
+        }
+        if !(0.0..=1.0).contains(&self.tilt_gain) {
+            return Err(anyhow!("tracking.tilt_gain must be between 0 and 1"));
+        }
+        if !(0.0..0.5).contains(&self.tilt_dead_zone) {
+            return Err(anyhow!("tracking.tilt_dead_zone must be >=0 and <0.5"));
+        }
+        if !(0.0..=1.0).contains(&self.track_min_iou) {
+            return Err(anyhow!("tracking.track_min_iou must be between 0 and 1"));
+        }
+        if self.track_min_hits == 0 {
+            return Err(anyhow!("tracking.track_min_hits must be >=1"));
+        }
+        Ok(self)
+    }
+}
+
+/// Configures the pan/tilt hardware.
+#[derive(Args, Clone, Debug, Deserialize, Serialize)]
+pub struct HardwareConfig {
+    /// The lowest step the pan axis may move to, relative to its home position.
+    #[arg(long, requires = "pan_max_step", allow_negative_numbers = true)]
+    pub pan_min_step: Option<f64>,
+
+    /// The highest step the pan axis may move to, relative to its home position.
+    #[arg(long, requires = "pan_min_step", allow_negative_numbers = true)]
+    pub pan_max_step: Option<f64>,
+
+    /// The lowest step the tilt axis may move to, relative to its home position.
+    #[arg(long, requires = "tilt_max_step", allow_negative_numbers = true)]
+    pub tilt_min_step: Option<f64>,
+
+    /// The highest step the tilt axis may move to, relative to its home position.
+    #[arg(long, requires = "tilt_min_step", allow_negative_numbers = true)]
+    pub tilt_max_step: Option<f64>,
+
+    /// If true, the pan axis is driven to its limit switch at startup to find its home
+    /// position.
+    #[arg(long, default_value_t = false)]
+    pub home_on_start: bool,
+
+    /// If provided, stepper drivers are put to sleep after their motor has been at rest
+    /// for this many seconds. Sleeping motors draw almost no current, but do not hold
+    /// their position.
+    #[arg(long)]
+    pub stepper_sleep_after_idle_secs: Option<u64>,
+}
+
+impl HardwareConfig {
+    pub fn pan_soft_limits(&self) -> Option<(f64, f64)> {
+        self.pan_min_step.zip(self.pan_max_step)
+    }
+
+    pub fn tilt_soft_limits(&self) -> Option<(f64, f64)> {
+        self.tilt_min_step.zip(self.tilt_max_step)
+    }
+
+    pub fn stepper_sleep_after_idle(&self) -> Option<std::time::Duration> {
+        self.stepper_sleep_after_idle_secs
+            .map(std::time::Duration::from_secs)
+    }
+}
+
+impl Validate for HardwareConfig {
+    fn validate(&self) -> Result<&Self> {
+        for (axis, min, max) in [
+            ("pan", self.pan_min_step, self.pan_max_step),
+            ("tilt", self.tilt_min_step, self.tilt_max_step),
+        ] {
+            match (min, max) {
+                (Some(min), Some(max)) => {
+                    if !(min <= 0.0 && 0.0 <= max) {
+                        return Err(anyhow!(
+                            "hardware.{axis}_min_step and hardware.{axis}_max_step must \
+                             contain the home position (0)"
+                        ));
+                    }
+                }
+                (None, None) => {}
+                _ => {
+                    return Err(anyhow!(
+                        "hardware.{axis}_min_step and hardware.{axis}_max_step must be \
+                         provided together"
+                    ))
+                }
+            }
+        }
         Ok(self)
     }
 }
@@ -227,8 +1034,102 @@ impl Config {
         cfg.extract_validated().map_err(|e| anyhow!(e))
     }
 
+    /// Returns the configuration as TOML, with any secrets redacted.
     pub fn to_toml_string(&self) -> Result<String> {
-        toml::to_string_pretty(&self).map_err(|e| anyhow!(e))
+        toml::to_string_pretty(&self.to_redacted_value()?).map_err(|e| anyhow!(e))
+    }
+
+    /// Returns the configuration as a serializable value, with any secrets redacted.
+    pub fn to_redacted_value(&self) -> Result<toml::Value> {
+        let mut value = toml::Value::try_from(&self)?;
+        if let Some(password) = value
+            .get_mut("upload")
+            .and_then(|upload| upload.get_mut("upload_password"))
+        {
+            *password = toml::Value::String(REDACTED.into());
+        }
+        Ok(value)
+    }
+
+    /// Returns a copy of the configuration with `patch` merged over it, after validating
+    /// the result. Tables are merged recursively, so `patch` need only contain the
+    /// settings being changed. Redacted secrets in `patch` are ignored.
+    ///
+    /// Only the `LIVE_SETTINGS` can be changed, since changes to the rest wouldn't take
+    /// effect until the application restarts.
+    pub fn patched(&self, mut patch: serde_json::Value) -> Result<Self> {
+        if let Some(upload) = patch.get_mut("upload").and_then(|u| u.as_object_mut()) {
+            if upload.get("upload_password").and_then(|p| p.as_str()) == Some(REDACTED) {
+                upload.remove("upload_password");
+            }
+        }
+
+        let patched: Self = Figment::from(Serialized::defaults(self))
+            .merge(Serialized::defaults(patch))
+            .extract_validated()?;
+
+        let startup_only: Vec<String> = changed_settings(self, &patched)?
+            .into_iter()
+            .filter(|setting| !LIVE_SETTINGS.contains(&setting.as_str()))
+            .collect();
+        if !startup_only.is_empty() {
+            return Err(anyhow!(
+                "Only read on startup, can't be patched: {}",
+                startup_only.join(", ")
+            ));
+        }
+        Ok(patched)
+    }
+}
+
+/// The settings that are re-read while the application runs, and so can be patched
+const LIVE_SETTINGS: &[&str] = &[
+    "detection.model_path",
+    "detection.model_b_path",
+    "detection.watch_model_files",
+    "tracking.pan_steps_per_frame_width",
+    "tracking.pan_gain",
+    "tracking.pan_dead_zone",
+    "tracking.tilt_steps_per_frame_height",
+    "tracking.tilt_gain",
+    "tracking.tilt_dead_zone",
+];
+
+/// Returns the names of the settings that differ between `before` and `after`, like
+/// `tracking.pan_gain`, in order.
+fn changed_settings(before: &Config, after: &Config) -> Result<BTreeSet<String>> {
+    let before = serde_json::to_value(before)?;
+    let after = serde_json::to_value(after)?;
+
+    let mut changed = BTreeSet::new();
+    for config in [&before, &after] {
+        for (section, settings) in config.as_object().into_iter().flatten() {
+            for (setting, _) in settings.as_object().into_iter().flatten() {
+                if before[section][setting] != after[section][setting] {
+                    changed.insert(format!("{}.{}", section, setting));
+                }
+            }
+        }
+    }
+    Ok(changed)
+}
+
+/// The configuration shared by the running application, which may be patched while it
+/// runs. Most settings are only read on startup.
+pub type SharedConfig = Arc<RwLock<Config>>;
+
+/// Replaces secrets when the configuration is displayed
+const REDACTED: &str = "<redacted>";
+
+/// Serializes an optional path as it was written, like
+/// `RelativePathBuf::serialize_relative`
+fn serialize_optional_relative<S: serde::Serializer>(
+    path: &Option<RelativePathBuf>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match path {
+        Some(path) => path.serialize_relative(serializer),
+        None => serializer.serialize_none(),
     }
 }
 
@@ -250,3 +1151,143 @@ impl ValidatedFigment for Figment {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+    use crate::foundation::testing::config_from_args;
+
+    /// Returns the default configuration, with an upload backend whose password is
+    /// redacted when displayed.
+    fn config() -> Config {
+        config_from_args(&[
+            "--debug-use-color-detection",
+            "--upload-backend=webdav",
+            "--upload-destination=https://example.com/videos/",
+            "--upload-username=user",
+            "--upload-password=secret",
+        ])
+    }
+
+    #[test]
+    fn test_patch_live_settings() {
+        let config = config();
+        let patched = config
+            .patched(json!({ "tracking": { "pan_gain": 0.75, "tilt_dead_zone": 0.2 } }))
+            .unwrap();
+
+        assert_eq!(patched.tracking.pan_gain, 0.75);
+        assert_eq!(patched.tracking.tilt_dead_zone, 0.2);
+        assert_eq!(
+            patched.tracking.pan_dead_zone,
+            config.tracking.pan_dead_zone
+        );
+        assert_eq!(patched.upload.upload_password.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn test_patch_keeps_redacted_password() {
+        let config = config();
+        let mut patch = serde_json::to_value(config.to_redacted_value().unwrap()).unwrap();
+        assert_eq!(patch["upload"]["upload_password"], REDACTED);
+        patch["tracking"]["pan_gain"] = json!(0.25);
+
+        let patched = config.patched(patch).unwrap();
+        assert_eq!(patched.upload.upload_password.as_deref(), Some("secret"));
+        assert_eq!(patched.tracking.pan_gain, 0.25);
+        assert_eq!(
+            patched.to_redacted_value().unwrap()["upload"]["upload_password"].as_str(),
+            Some(REDACTED)
+        );
+    }
+
+    #[test]
+    fn test_patch_rejects_invalid_settings() {
+        let config = config();
+        assert!(config
+            .patched(json!({ "tracking": { "pan_gain": "fast" } }))
+            .is_err());
+        assert_eq!(
+            config
+                .patched(json!({ "tracking": { "pan_gain": 1.5 } }))
+                .unwrap_err()
+                .to_string(),
+            "tracking.pan_gain must be between 0 and 1"
+        );
+
+        let err = config
+            .patched(json!({ "detection": { "model_b_path": "/missing/model.tflite" } }))
+            .unwrap_err();
+        assert!(err.to_string().contains("model_b_path"), "{}", err);
+    }
+
+    #[test]
+    fn test_patch_rejects_startup_only_settings() {
+        let config = config();
+        let err = config
+            .patched(json!({
+                "tracking": { "pan_gain": 0.75 },
+                "encoder": { "encoder_bitrate_kbps": 2000 },
+                "upload": { "upload_password": "changed" },
+            }))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Only read on startup, can't be patched: encoder.encoder_bitrate_kbps, \
+             upload.upload_password"
+        );
+
+        // Restating a startup setting's current value changes nothing
+        assert!(config
+            .patched(json!({ "encoder": { "encoder_bitrate_kbps": 4000 } }))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_encoder_validation() {
+        let valid = config().encoder;
+        assert!(valid.validate().is_ok());
+
+        let invalid = [
+            EncoderConfig {
+                encoder_bitrate_kbps: 0,
+                ..valid.clone()
+            },
+            EncoderConfig {
+                encoder_bitrate_kbps: MAX_ENCODER_BITRATE_KBPS + 1,
+                ..valid.clone()
+            },
+            EncoderConfig {
+                encoder_gop_size: 0,
+                ..valid.clone()
+            },
+            EncoderConfig {
+                encoder_level: "4.3".into(),
+                ..valid.clone()
+            },
+        ];
+        for encoder in invalid {
+            assert!(encoder.validate().is_err(), "{:?}", encoder);
+        }
+
+        let level = EncoderConfig {
+            encoder_level: "5.1".into(),
+            ..valid
+        };
+        assert!(level.validate().is_ok());
+    }
+
+    #[test]
+    fn test_low_space_bitrate_validation() {
+        let mut video_storage = config().video_storage;
+        video_storage.low_space_bitrate_kbps = Some(MAX_ENCODER_BITRATE_KBPS);
+        assert!(video_storage.validate().is_ok());
+
+        video_storage.low_space_bitrate_kbps = Some(0);
+        assert!(video_storage.validate().is_err());
+        video_storage.low_space_bitrate_kbps = Some(MAX_ENCODER_BITRATE_KBPS + 1);
+        assert!(video_storage.validate().is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! Controls the running application from outside of the pipeline, such as from the web
+//! server.
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use aa_sys::pantilt::PanTiltSystem;
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use gst::prelude::*;
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+
+use crate::config::{PreviewMode, SharedConfig};
+use crate::logging::*;
+use crate::message::AAMessage;
+use crate::metrics::Metrics;
+use crate::pipeline::ConfiguredPipeline;
+use crate::preview::PreviewFrames;
+use crate::session::SessionManager;
+use crate::upload::{upload_queue_path, UploadQueue};
+
+pub(self) static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "AA_CONTROL",
+        gst::DebugColorFlags::FG_MAGENTA,
+        Some("Auto-Arena Control"),
+    )
+});
+
+/// The operations available to control surfaces, like the web server's API.
+pub trait Control: Send + Sync {
+    /// Starts a recording session, returning its ID. Fails if a session is already
+    /// recording.
+    fn start_session(&self) -> Result<String>;
+
+    /// Stops the active recording session. Fails if no session is recording.
+    fn stop_session(&self) -> Result<()>;
+
+    fn session_status(&self) -> SessionStatus;
+
+    /// Returns the live configuration, with secrets redacted.
+    fn config(&self) -> Result<serde_json::Value>;
+
+    /// Merges `patch` into the live configuration, returning the result with secrets
+    /// redacted. Fails without changing anything if the result doesn't validate, or if
+    /// `patch` changes settings that are only read on startup.
+    fn patch_config(&self, patch: serde_json::Value) -> Result<serde_json::Value>;
+
+    /// Lists the recorded chunks in the storage directory, oldest first.
+    fn chunks(&self) -> Result<Vec<ChunkSummary>>;
+
+    fn pantilt_status(&self) -> PanTiltStatus;
+
+    /// Moves the pan target by `steps`. Positive values pan right.
+    fn nudge_pan(&self, steps: f64) -> Result<PanTiltStatus>;
+
+    /// Returns where the live display stream is previewed.
+    fn preview_mode(&self) -> PreviewMode;
+
+    /// Returns the preview's frames, if the `mjpeg` preview is enabled.
+    fn preview_frames(&self) -> Option<PreviewFrames>;
+
+    /// Returns the application's metrics in the Prometheus text format.
+    fn metrics(&self) -> Result<String>;
+
+    /// Calls `listener` with every `AAMessage` posted to the pipeline's bus, from the
+    /// thread that dispatches bus messages. Listeners should return quickly.
+    fn subscribe(&self, listener: MessageListener);
+}
+
+pub type MessageListener = Box<dyn Fn(&AAMessage) + Send + Sync>;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SessionStatus {
+    pub active_session_id: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ChunkSummary {
+    pub path: PathBuf,
+    pub size_bytes: u64,
+    pub modified_at: String,
+    /// `true` if the chunk is waiting to be uploaded
+    pub upload_pending: bool,
+}
+
+/// The pan/tilt axes' positions and targets, in steps relative to home
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct PanTiltStatus {
+    pub pan_step: f64,
+    pub tilt_step: f64,
+    pub pan_target: f64,
+    pub tilt_target: f64,
+}
+
+/// Controls a configured pipeline and its hardware
+#[derive(Clone)]
+pub struct AppControl {
+    config: SharedConfig,
+    sessions: Arc<SessionManager>,
+    pantilt: Arc<PanTiltSystem>,
+    preview: Option<PreviewFrames>,
+    metrics: Arc<Metrics>,
+    bus: gst::Bus,
+}
+
+impl AppControl {
+    pub fn new(configured: &ConfiguredPipeline) -> Self {
+        Self {
+            bus: configured
+                .pipeline
+                .bus()
+                .expect("Pipeline without bus. Shouldn't happen!"),
+            config: configured.config.clone(),
+            sessions: configured.sessions.clone(),
+            pantilt: configured.hardware.pantilt.clone(),
+            preview: configured.preview.clone(),
+            metrics: configured.metrics.clone(),
+        }
+    }
+}
+
+impl Control for AppControl {
+    fn start_session(&self) -> Result<String> {
+        info!(CAT, "Starting session on request");
+        self.sessions.start()
+    }
+
+    fn stop_session(&self) -> Result<()> {
+        info!(CAT, "Stopping session on request");
+        self.sessions.stop()
+    }
+
+    fn session_status(&self) -> SessionStatus {
+        SessionStatus {
+            active_session_id: self.sessions.active_session_id(),
+        }
+    }
+
+    fn config(&self) -> Result<serde_json::Value> {
+        let value = self.config.read().unwrap().to_redacted_value()?;
+        Ok(serde_json::to_value(value)?)
+    }
+
+    fn patch_config(&self, patch: serde_json::Value) -> Result<serde_json::Value> {
+        let mut config = self.config.write().unwrap();
+        let patched = config.patched(patch)?;
+        info!(CAT, "Applying configuration patch");
+        *config = patched;
+        Ok(serde_json::to_value(config.to_redacted_value()?)?)
+    }
+
+    fn chunks(&self) -> Result<Vec<ChunkSummary>> {
+        let config = self.config.read().unwrap();
+        let dir = config.video_storage.temp_dir_path.relative();
+        let extension = config.video_storage.container_format.extension();
+        let queued: Vec<PathBuf> = if config.upload.upload_backend.is_some() {
+            UploadQueue::load(&upload_queue_path(&config))?
+                .paths()
+                .collect()
+        } else {
+            vec![]
+        };
+
+        let mut chunks = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(extension) {
+                continue;
+            }
+
+            let metadata = fs::metadata(&path)?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let modified = metadata.modified()?;
+            chunks.push((
+                modified,
+                ChunkSummary {
+                    upload_pending: queued.contains(&path),
+                    size_bytes: metadata.len(),
+                    modified_at: DateTime::<Local>::from(modified).to_rfc3339(),
+                    path,
+                },
+            ));
+        }
+        chunks.sort_by_key(|(modified, _)| *modified);
+        Ok(chunks.into_iter().map(|(_, chunk)| chunk).collect())
+    }
+
+    fn pantilt_status(&self) -> PanTiltStatus {
+        let position = self.pantilt.position();
+        PanTiltStatus {
+            pan_step: position.pan_step,
+            tilt_step: position.tilt_step,
+            pan_target: position.pan_target,
+            tilt_target: position.tilt_target,
+        }
+    }
+
+    fn nudge_pan(&self, steps: f64) -> Result<PanTiltStatus> {
+        debug!(CAT, "Nudging pan target by {:+.1} steps", steps);
+        self.pantilt.nudge_target(steps, 0.0)?;
+        Ok(self.pantilt_status())
+    }
+
+    fn preview_mode(&self) -> PreviewMode {
+        self.config.read().unwrap().preview.preview_mode
+    }
+
+    fn preview_frames(&self) -> Option<PreviewFrames> {
+        self.preview.clone()
+    }
+
+    fn metrics(&self) -> Result<String> {
+        self.metrics.export()
+    }
+
+    fn subscribe(&self, listener: MessageListener) {
+        debug!(CAT, "Subscribing message listener");
+        self.bus.connect("message", true, move |args| {
+            let msg = args[1].get::<gst::Message>().unwrap();
+            if let Ok(message) = AAMessage::from_gst_message(&msg) {
+                listener(&message);
+            }
+            None
+        });
+    }
+}
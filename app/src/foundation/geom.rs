@@ -1,113 +1,169 @@
-use glib::ObjectExt;
+//! Plain geometry values. Unless noted otherwise, coordinates are normalized, as
+//! fractions of the frame's dimensions, with the origin at the top left.
 use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
 
-glib::wrapper! {
-    pub struct Rect(ObjectSubclass<imp::Rect>) @extends gst::Object;
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
 }
 
-impl Rect {
-    pub fn new(x: f64, y: f64, width: f64, height: f64) -> Self {
-        glib::Object::new(&[("x", &x), ("y", &y), ("width", &width), ("height", &height)])
+impl Point {
+    pub const fn new(x: f64, y: f64) -> Self {
+        Self { x, y }
     }
 
-    pub fn x(&self) -> f64 {
-        self.property("x")
+    pub fn distance_to(&self, other: &Point) -> f64 {
+        (other.x - self.x).hypot(other.y - self.y)
     }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Size {
+    pub width: f64,
+    pub height: f64,
+}
 
-    pub fn y(&self) -> f64 {
-        self.property("y")
+impl Size {
+    pub const fn new(width: f64, height: f64) -> Self {
+        Self { width, height }
     }
 
-    pub fn width(&self) -> f64 {
-        self.property("width")
+    pub fn area(&self) -> f64 {
+        self.width * self.height
     }
 
-    pub fn height(&self) -> f64 {
-        self.property("height")
+    /// Returns the ratio of width to height, or 0 if there's no height.
+    pub fn aspect(&self) -> f64 {
+        if self.height == 0.0 {
+            0.0
+        } else {
+            self.width / self.height
+        }
     }
 }
 
-impl Default for Rect {
-    fn default() -> Self {
-        Self::new(0.0, 0.0, 0.0, 0.0)
-    }
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Rect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
 }
 
-mod imp {
-    use std::sync::Mutex;
-
-    use gst::glib::prelude::*;
-    use gst::subclass::prelude::*;
-    use once_cell::sync::Lazy;
-
-    #[derive(Debug, Default)]
-    pub struct Rect {
-        props_storage: Mutex<PropsStorage>,
-    }
-
-    // This trait registers our type with the GObject object system and
-    // provides the entry points for creating a new instance and setting
-    // up the class data
-    #[glib::object_subclass]
-    impl ObjectSubclass for Rect {
-        const NAME: &'static str = "Rect";
-        type Type = super::Rect;
-        type ParentType = gst::Object;
-    }
-
-    impl ObjectImpl for Rect {
-        fn properties() -> &'static [glib::ParamSpec] {
-            static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
-                vec![
-                    glib::ParamSpecDouble::builder("x")
-                        .default_value(0.0)
-                        .build(),
-                    glib::ParamSpecDouble::builder("y")
-                        .default_value(0.0)
-                        .build(),
-                    glib::ParamSpecDouble::builder("width")
-                        .default_value(0.0)
-                        .build(),
-                    glib::ParamSpecDouble::builder("height")
-                        .default_value(0.0)
-                        .build(),
-                ]
-            });
-
-            PROPERTIES.as_ref()
+impl Rect {
+    pub const fn new(x: f64, y: f64, width: f64, height: f64) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
         }
+    }
 
-        fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
-            let props_guard = self.props_storage.lock().unwrap();
-            match pspec.name() {
-                "x" => props_guard.x.to_value(),
-                "y" => props_guard.y.to_value(),
-                "width" => props_guard.width.to_value(),
-                "height" => props_guard.height.to_value(),
-                _ => unimplemented!(),
-            }
+    pub fn from_origin_size(origin: Point, size: Size) -> Self {
+        Self::new(origin.x, origin.y, size.width, size.height)
+    }
+
+    /// Returns a rect of `size` centered on `center`.
+    pub fn from_center_size(center: Point, size: Size) -> Self {
+        Self::new(
+            center.x - size.width / 2.0,
+            center.y - size.height / 2.0,
+            size.width,
+            size.height,
+        )
+    }
+
+    pub fn origin(&self) -> Point {
+        Point::new(self.x, self.y)
+    }
+
+    pub fn size(&self) -> Size {
+        Size::new(self.width, self.height)
+    }
+
+    pub fn center(&self) -> Point {
+        Point::new(self.x + self.width / 2.0, self.y + self.height / 2.0)
+    }
+
+    pub fn right(&self) -> f64 {
+        self.x + self.width
+    }
+
+    pub fn bottom(&self) -> f64 {
+        self.y + self.height
+    }
+
+    pub fn area(&self) -> f64 {
+        self.size().area()
+    }
+
+    pub fn aspect(&self) -> f64 {
+        self.size().aspect()
+    }
+
+    /// Returns the area shared by `self` and `other`, or `None` if they don't overlap.
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let width = self.right().min(other.right()) - x;
+        let height = self.bottom().min(other.bottom()) - y;
+        if width <= 0.0 || height <= 0.0 {
+            None
+        } else {
+            Some(Rect::new(x, y, width, height))
         }
+    }
+
+    pub fn intersection_area(&self, other: &Rect) -> f64 {
+        self.intersection(other).map_or(0.0, |r| r.area())
+    }
+
+    /// Returns the smallest rect containing both `self` and `other`.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect::new(
+            x,
+            y,
+            self.right().max(other.right()) - x,
+            self.bottom().max(other.bottom()) - y,
+        )
+    }
 
-        fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
-            let mut props_guard = self.props_storage.lock().unwrap();
-            match pspec.name() {
-                "x" => props_guard.x = value.get().expect("type checked upstream"),
-                "y" => props_guard.y = value.get().expect("type checked upstream"),
-                "width" => props_guard.width = value.get().expect("type checked upstream"),
-                "height" => props_guard.height = value.get().expect("type checked upstream"),
-                _ => unimplemented!(),
-            }
+    /// Returns the intersection over union of `self` and `other`, from 0 (disjoint) to
+    /// 1 (identical).
+    pub fn iou(&self, other: &Rect) -> f64 {
+        let intersection = self.intersection_area(other);
+        let union = self.area() + other.area() - intersection;
+        if union <= 0.0 {
+            0.0
+        } else {
+            intersection / union
         }
     }
 
-    impl GstObjectImpl for Rect {}
+    /// Converts a normalized rect into pixels in a frame of `frame_size` pixels.
+    pub fn to_pixels(&self, frame_size: Size) -> Rect {
+        Rect::new(
+            self.x * frame_size.width,
+            self.y * frame_size.height,
+            self.width * frame_size.width,
+            self.height * frame_size.height,
+        )
+    }
 
-    #[derive(Debug, Default)]
-    struct PropsStorage {
-        x: f64,
-        y: f64,
-        width: f64,
-        height: f64,
+    /// Converts a rect in pixels, in a frame of `frame_size` pixels, into a normalized
+    /// rect.
+    pub fn to_normalized(&self, frame_size: Size) -> Rect {
+        Rect::new(
+            self.x / frame_size.width,
+            self.y / frame_size.height,
+            self.width / frame_size.width,
+            self.height / frame_size.height,
+        )
     }
 }
 
@@ -119,3 +175,35 @@ pub fn random_fraction_rect() -> Rect {
     let y = r.gen_range(0.0..(1.0 - h));
     Rect::new(x, y, w, h)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_intersection_and_union() {
+        // Values are exact in binary, so that edges can be compared exactly
+        let a = Rect::new(0.0, 0.0, 0.5, 0.5);
+        let b = Rect::new(0.25, 0.25, 0.5, 0.5);
+
+        assert_eq!(a.intersection(&b), Some(Rect::new(0.25, 0.25, 0.25, 0.25)));
+        assert_eq!(a.union(&b), Rect::new(0.0, 0.0, 0.75, 0.75));
+        assert!((a.iou(&b) - 0.0625 / 0.4375).abs() < 1e-9);
+
+        let c = Rect::new(0.625, 0.625, 0.125, 0.125);
+        assert_eq!(a.intersection(&c), None);
+        assert_eq!(a.iou(&c), 0.0);
+    }
+
+    #[test]
+    fn test_pixel_conversion() {
+        let frame = Size::new(1280.0, 720.0);
+        let pixels = Rect::new(320.0, 180.0, 640.0, 360.0);
+
+        let normalized = pixels.to_normalized(frame);
+        assert_eq!(normalized, Rect::new(0.25, 0.25, 0.5, 0.5));
+        assert_eq!(normalized.to_pixels(frame), pixels);
+        assert_eq!(normalized.center(), Point::new(0.5, 0.5));
+        assert_eq!(pixels.aspect(), 16.0 / 9.0);
+    }
+}
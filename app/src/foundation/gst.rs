@@ -50,3 +50,24 @@ pub fn request_state_and_wait_for_change(
         Err(e) => Result::Err(anyhow!(e)),
     }
 }
+
+/// The name of the message posted by `splitmuxsink` when it starts writing a file
+pub const FRAGMENT_OPENED: &str = "splitmuxsink-fragment-opened";
+/// The name of the message posted by `splitmuxsink` when it finishes writing a file
+pub const FRAGMENT_CLOSED: &str = "splitmuxsink-fragment-closed";
+
+/// Returns the file location from a `splitmuxsink` element message named
+/// `message_name`, or `None` if `msg` is any other kind of message.
+pub fn splitmux_fragment_location(msg: &gst::Message, message_name: &str) -> Option<String> {
+    match msg.view() {
+        gst::MessageView::Element(element) => {
+            let structure = element.structure()?;
+            if structure.name() == message_name {
+                structure.get::<String>("location").ok()
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
@@ -2,6 +2,9 @@
 pub mod debug;
 pub mod geom;
 pub mod gst;
+pub mod structure;
+#[cfg(test)]
+pub mod testing;
 
 use ::gst::{DebugCategory, DebugColorFlags};
 use once_cell::sync::Lazy;
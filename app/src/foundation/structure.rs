@@ -0,0 +1,535 @@
+//! A serde data format for `gst::Structure`, so that types can be carried by GStreamer
+//! messages without mapping each of their fields by hand.
+//!
+//! Structs and maps become structures, with each field stored as its closest GLib type.
+//! Nested structs and maps become nested structures, sequences become `gst::Array`s, unit
+//! enum variants become strings, and `None` fields are left out.
+use std::fmt::{self, Display};
+
+use glib::{SendValue, StaticType, ToSendValue};
+use serde::de::value::StringDeserializer;
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor,
+};
+use serde::ser::{self, Impossible, Serialize};
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+fn unsupported<T>(what: &str) -> Result<T, Error> {
+    Err(Error(format!("{} can't be stored in a structure", what)))
+}
+
+/// Serializes `value`, which must serialize as a struct or a map, into a structure named
+/// after its type.
+pub fn to_structure<T: Serialize + ?Sized>(value: &T) -> Result<gst::Structure, Error> {
+    value
+        .serialize(ValueSerializer)?
+        .and_then(|value| value.get::<gst::Structure>().ok())
+        .ok_or_else(|| Error("Only structs and maps can be serialized as structures".into()))
+}
+
+/// Deserializes a `T` from the fields of `structure`. The structure's name is ignored.
+pub fn from_structure<T: DeserializeOwned>(
+    structure: &gst::StructureRef,
+) -> Result<T, Error> {
+    T::deserialize(ValueDeserializer(structure.to_owned().to_send_value()))
+}
+
+/// Serializes a value into a `SendValue`, or `None` if it has no representation (like
+/// `None`, or `()`)
+struct ValueSerializer;
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Option<SendValue>;
+    type Error = Error;
+    type SerializeSeq = SeqSerializer;
+    type SerializeTuple = SeqSerializer;
+    type SerializeTupleStruct = SeqSerializer;
+    type SerializeTupleVariant = Impossible<Self::Ok, Error>;
+    type SerializeMap = StructureSerializer;
+    type SerializeStruct = StructureSerializer;
+    type SerializeStructVariant = Impossible<Self::Ok, Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Error> {
+        Ok(Some(v.to_send_value()))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Error> {
+        self.serialize_i32(v as i32)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Error> {
+        self.serialize_i32(v as i32)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Error> {
+        Ok(Some(v.to_send_value()))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Error> {
+        Ok(Some(v.to_send_value()))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Error> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Error> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Error> {
+        Ok(Some(v.to_send_value()))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Error> {
+        Ok(Some(v.to_send_value()))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Self::Ok, Error> {
+        Ok(Some(v.to_send_value()))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Self::Ok, Error> {
+        Ok(Some(v.to_send_value()))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Error> {
+        self.serialize_str(&v.to_string())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Error> {
+        Ok(Some(v.to_send_value()))
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<Self::Ok, Error> {
+        unsupported("Bytes")
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Self::Ok, Error> {
+        unsupported("Externally tagged enum variants")
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Ok(SeqSerializer(Vec::with_capacity(len.unwrap_or(0))))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        unsupported("Externally tagged enum variants")
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Ok(StructureSerializer::new("map"))
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Error> {
+        Ok(StructureSerializer::new(name))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        unsupported("Externally tagged enum variants")
+    }
+}
+
+struct SeqSerializer(Vec<SendValue>);
+
+impl SeqSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        match value.serialize(ValueSerializer)? {
+            Some(value) => {
+                self.0.push(value);
+                Ok(())
+            }
+            None => unsupported("Sequences of empty values"),
+        }
+    }
+
+    fn finish(self) -> Result<Option<SendValue>, Error> {
+        Ok(Some(gst::Array::from_values(self.0).to_send_value()))
+    }
+}
+
+impl ser::SerializeSeq for SeqSerializer {
+    type Ok = Option<SendValue>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SeqSerializer {
+    type Ok = Option<SendValue>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqSerializer {
+    type Ok = Option<SendValue>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        self.finish()
+    }
+}
+
+struct StructureSerializer {
+    structure: gst::Structure,
+    /// The key of the map entry whose value is serialized next
+    key: Option<String>,
+}
+
+impl StructureSerializer {
+    fn new(name: &str) -> Self {
+        Self {
+            structure: gst::Structure::new_empty(name),
+            key: None,
+        }
+    }
+
+    fn set<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<(), Error> {
+        if let Some(value) = value.serialize(ValueSerializer)? {
+            self.structure.set_value(key, value);
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<SendValue>, Error> {
+        Ok(Some(self.structure.to_send_value()))
+    }
+}
+
+impl ser::SerializeMap for StructureSerializer {
+    type Ok = Option<SendValue>;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        let key = key
+            .serialize(ValueSerializer)?
+            .and_then(|key| key.get::<String>().ok())
+            .ok_or_else(|| Error("Map keys must be strings".into()))?;
+        self.key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| Error("Map value serialized before its key".into()))?;
+        self.set(&key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for StructureSerializer {
+    type Ok = Option<SendValue>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.set(key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        self.finish()
+    }
+}
+
+/// Deserializes from a `SendValue` holding one of the types written by `ValueSerializer`
+struct ValueDeserializer(SendValue);
+
+impl ValueDeserializer {
+    fn get<T: for<'a> glib::value::FromValue<'a> + 'static>(&self) -> Result<T, Error> {
+        self.0.get::<T>().map_err(|err| Error(err.to_string()))
+    }
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let type_ = self.0.type_();
+        match type_ {
+            glib::Type::BOOL => visitor.visit_bool(self.get()?),
+            glib::Type::I32 => visitor.visit_i32(self.get()?),
+            glib::Type::I64 => visitor.visit_i64(self.get()?),
+            glib::Type::U32 => visitor.visit_u32(self.get()?),
+            glib::Type::U64 => visitor.visit_u64(self.get()?),
+            glib::Type::F32 => visitor.visit_f32(self.get()?),
+            glib::Type::F64 => visitor.visit_f64(self.get()?),
+            glib::Type::STRING => visitor.visit_string(self.get()?),
+            _ if type_ == gst::Structure::static_type() => {
+                let structure = self.get::<gst::Structure>()?;
+                visitor.visit_map(StructureAccess {
+                    fields: structure
+                        .iter()
+                        .map(|(key, value)| (key.to_owned(), value.clone()))
+                        .collect::<Vec<_>>()
+                        .into_iter(),
+                    value: None,
+                })
+            }
+            _ if type_ == gst::Array::static_type() => {
+                let array = self.get::<gst::Array>()?;
+                visitor.visit_seq(ArrayAccess(array.as_slice().to_vec().into_iter()))
+            }
+            _ => Err(Error(format!(
+                "Values of type {} can't be deserialized",
+                type_
+            ))),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        // Missing fields are `None`, so any value that's present is `Some`
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let variant: StringDeserializer<Error> = self.get::<String>()?.into_deserializer();
+        visitor.visit_enum(variant)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+struct StructureAccess {
+    fields: std::vec::IntoIter<(String, SendValue)>,
+    /// The value of the field whose key was most recently visited
+    value: Option<SendValue>,
+}
+
+impl<'de> MapAccess<'de> for StructureAccess {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.fields.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                let key: StringDeserializer<Error> = key.into_deserializer();
+                seed.deserialize(key).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Error> {
+        let value = self
+            .value
+            .take()
+            .ok_or_else(|| Error("Structure value visited before its key".into()))?;
+        seed.deserialize(ValueDeserializer(value))
+    }
+}
+
+struct ArrayAccess(std::vec::IntoIter<SendValue>);
+
+impl<'de> SeqAccess<'de> for ArrayAccess {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        self.0
+            .next()
+            .map(|value| seed.deserialize(ValueDeserializer(value)))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_derive::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    #[serde(rename_all = "kebab-case")]
+    enum Gait {
+        Walk,
+        Canter,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Ride {
+        horse: String,
+        gaits: Vec<Gait>,
+        jumps: Option<u32>,
+        notes: Option<String>,
+        arena: Arena,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Arena {
+        width_m: f64,
+        covered: bool,
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        gst::init().unwrap();
+
+        let ride = Ride {
+            horse: "Dobbin".into(),
+            gaits: vec![Gait::Walk, Gait::Canter],
+            jumps: Some(12),
+            notes: None,
+            arena: Arena {
+                width_m: 20.5,
+                covered: true,
+            },
+        };
+        let structure = to_structure(&ride).unwrap();
+        assert_eq!(structure.name(), "Ride");
+        assert_eq!(structure.get::<u32>("jumps").unwrap(), 12);
+        assert!(!structure.has_field("notes"));
+        assert!(structure
+            .get::<gst::Structure>("arena")
+            .unwrap()
+            .get::<bool>("covered")
+            .unwrap());
+
+        assert_eq!(from_structure::<Ride>(&structure).unwrap(), ride);
+    }
+
+    #[test]
+    fn test_rejects_non_structs() {
+        gst::init().unwrap();
+        assert!(to_structure(&42u32).is_err());
+        assert!(to_structure(&vec!["walk"]).is_err());
+    }
+}
@@ -0,0 +1,35 @@
+//! Helpers shared by the crate's tests.
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+
+use crate::config::Config;
+
+#[derive(Parser)]
+struct TestArgs {
+    #[command(flatten)]
+    config: Config,
+}
+
+/// Returns the configuration given by the command line arguments `args`.
+pub fn config_from_args(args: &[&str]) -> Config {
+    gst::init().unwrap();
+    let args = TestArgs::parse_from(std::iter::once("aa-app").chain(args.iter().copied()));
+    Config::new(None, args.config).unwrap()
+}
+
+/// Dispatches the default main context's sources until `done`, or the timeout. Returns
+/// `false` if the timeout passed first.
+pub fn dispatch_until(timeout: Duration, mut done: impl FnMut() -> bool) -> bool {
+    let context = glib::MainContext::default();
+    let deadline = Instant::now() + timeout;
+    while !done() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        // Returns immediately if another test's thread owns the context
+        context.iteration(false);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
@@ -37,7 +37,7 @@ mod imp {
     use palette::Lab;
 
     use super::CAT;
-    use crate::foundation::geom::Rect;
+    use crate::foundation::geom::{Rect, Size};
     use crate::logging::*;
     use crate::message::{AAMessage, DetectionDetails};
 
@@ -95,8 +95,7 @@ mod imp {
             let frame =
                 VideoFrameRef::from_buffer_ref_readable(readable.buffer(), video_info)
                     .unwrap();
-            let w = frame.width();
-            let h = frame.height();
+            let frame_size = Size::new(frame.width() as f64, frame.height() as f64);
             let flat_samples = image::FlatSamples::<Vec<u8>> {
                 samples: frame.plane_data(0).unwrap().into(),
                 layout: image::flat::SampleLayout {
@@ -123,11 +122,12 @@ mod imp {
                 .filter(|r| r.count > detection_pixel_threshold)
                 .map(|r| {
                     let fractional_bounds = Rect::new(
-                        r.x() as f64 / w as f64,
-                        r.y() as f64 / h as f64,
-                        r.width() as f64 / w as f64,
-                        r.height() as f64 / h as f64,
-                    );
+                        r.x() as f64,
+                        r.y() as f64,
+                        r.width() as f64,
+                        r.height() as f64,
+                    )
+                    .to_normalized(frame_size);
 
                     DetectionDetails {
                         label: "Color".into(),
@@ -306,6 +306,7 @@ mod imp {
                     dts: dts,
                     detection_count: res.len() as i32,
                     duration: start_ts.elapsed(),
+                    dropped_frames: 0,
                 }
                 .to_gst_message()
                 // TODO(shyndman): We really need to report the error text
@@ -16,12 +16,15 @@ static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
 });
 
 use crate::config::Config;
+use crate::foundation::geom::Size;
 use crate::logging::*;
-use crate::message::{AAMessage, DetectionDetails};
+use crate::message::{AAMessage, DetectionDetails, TargetDetails};
 
 struct State {
     info: Option<gst_video::VideoInfo>,
     detections: VecDeque<DetectionDetails>,
+    /// The most recently selected target, which is drawn distinctly from the detections
+    target: Option<TargetDetails>,
 }
 
 pub fn build_detection_overlay(
@@ -42,6 +45,7 @@ pub fn build_detection_overlay(
     let state = Arc::new(Mutex::new(State {
         info: None,
         detections: VecDeque::new(),
+        target: None,
     }));
     let state_clone = state.clone();
 
@@ -73,10 +77,17 @@ pub fn build_detection_overlay(
                 return None;
             };
 
-        if let AAMessage::InferObjectDetection(details) = app_msg {
-            let guard = &mut state_clone.lock().unwrap();
-            let detections = &mut guard.detections;
-            detections.push_back(details);
+        match app_msg {
+            AAMessage::InferObjectDetection(details) => {
+                let guard = &mut state_clone.lock().unwrap();
+                let detections = &mut guard.detections;
+                detections.push_back(details);
+            }
+            AAMessage::TargetSelected(target) => {
+                let guard = &mut state_clone.lock().unwrap();
+                guard.target = Some(target);
+            }
+            _ => {}
         }
 
         None
@@ -88,26 +99,34 @@ pub fn build_detection_overlay(
         let ctx = args[1].get::<cairo::Context>().unwrap();
         let ts = args[2].get::<gst::ClockTime>().unwrap();
         let _dur = args[3].get::<gst::ClockTime>().unwrap();
-        let state_guard = &mut state_clone.lock().unwrap();
+        let mut guard = state_clone.lock().unwrap();
+        let state_guard = &mut *guard;
 
         debug!(CAT, "Starting overlay frame {:?}", ts);
 
-        let (w, h) = {
+        let frame_size = {
             let info = state_guard.info.as_ref().unwrap();
-            (info.width() as f64, info.height() as f64)
+            Size::new(info.width() as f64, info.height() as f64)
         };
 
-        let detections = &mut state_guard.detections;
-        if detections.is_empty() {
-            return None;
-        }
-
         let life_elapsed = |frame_ts: ClockTime, detect_ts: ClockTime| -> f64 {
             let f_ts = frame_ts.mseconds() as f64;
             let d_ts = detect_ts.mseconds() as f64;
             (f_ts - d_ts) / detection_lifetime_ms
         };
 
+        // Expire the old target
+        if let Some(ref target) = state_guard.target {
+            if life_elapsed(ts, target.pts) >= 1.0 {
+                state_guard.target = None;
+            }
+        }
+
+        let detections = &mut state_guard.detections;
+        if detections.is_empty() && state_guard.target.is_none() {
+            return None;
+        }
+
         // Expire old detections
         let mut delete_count = 0u16;
         while !detections.is_empty() {
@@ -122,7 +141,7 @@ pub fn build_detection_overlay(
         }
         log!(CAT, "Removed {} detections", delete_count);
 
-        if detections.is_empty() {
+        if detections.is_empty() && state_guard.target.is_none() {
             return None;
         }
         ctx.save().expect("Could not save Cairo state");
@@ -134,28 +153,28 @@ pub fn build_detection_overlay(
                 continue;
             }
 
-            let rect = &detection.bounds;
+            let rect = detection.bounds.to_pixels(frame_size);
 
             ctx.set_source_rgba(1.0, 0.0, 0.0, (0.0..1.0).lerp(life_left));
             ctx.set_line_width(1.5);
-            ctx.rectangle(
-                rect.x() * w,
-                rect.y() * h,
-                rect.width() * w,
-                rect.height() * h,
-            );
+            ctx.rectangle(rect.x, rect.y, rect.width, rect.height);
             ctx.stroke().expect("Failed to draw rect");
 
             ctx.set_source_rgba(1.0, 0.0, 0.0, (0.0..0.3).lerp(life_left));
-            ctx.rectangle(
-                rect.x() * w,
-                rect.y() * h,
-                rect.width() * w,
-                rect.height() * h,
-            );
+            ctx.rectangle(rect.x, rect.y, rect.width, rect.height);
             ctx.fill().expect("Failed to fill");
         }
 
+        if let Some(ref target) = state_guard.target {
+            let life_left = 1.0 - life_elapsed(ts, target.pts);
+            let rect = target.bounds.to_pixels(frame_size);
+
+            ctx.set_source_rgba(0.0, 1.0, 0.0, (0.3..1.0).lerp(life_left));
+            ctx.set_line_width(3.0);
+            ctx.rectangle(rect.x, rect.y, rect.width, rect.height);
+            ctx.stroke().expect("Failed to draw rect");
+        }
+
         ctx.restore().expect("Could not restore Cairo state");
 
         None
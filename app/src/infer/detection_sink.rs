@@ -28,11 +28,14 @@ impl DetectionSink {
 }
 
 mod imp {
-    use std::sync::Mutex;
-    use std::time::Instant;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread::JoinHandle;
+    use std::time::{Duration, Instant};
 
     use anyhow::Result;
     use glib::{ParamSpecBuilderExt, ToValue};
+    use gst::prelude::*;
     use gst::subclass::prelude::*;
     use gst::{glib, FlowError, Fraction};
     use gst_base::subclass::prelude::*;
@@ -42,35 +45,184 @@ mod imp {
     use tflite_support::{BaseOptions, DetectionOptions, DetectionResult, ObjectDetector};
 
     use super::{CAT, DETECT_CAT};
-    use crate::foundation::geom::Rect;
+    use crate::foundation::geom::{Rect, Size};
+    use crate::infer::model_stats::ModelStats;
     use crate::infer::tf_buffer_adapter::TensorflowBufferAdapter;
     use crate::logging::*;
     use crate::message::{AAMessage, DetectionDetails};
 
-    #[derive(Default)]
+    /// How often the stats of models being compared are logged
+    const COMPARISON_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
     struct PropsStorage {
         model_location: Option<String>,
+        model_b_location: Option<String>,
         max_results: u32,
         score_threshold: f32,
+        num_threads: i32,
+        enable_coral: bool,
         bus: Option<gst::Bus>,
         model_invalidated: bool,
     }
 
+    impl Default for PropsStorage {
+        fn default() -> Self {
+            Self {
+                model_location: None,
+                model_b_location: None,
+                max_results: 0,
+                score_threshold: 0.0,
+                num_threads: -1,
+                enable_coral: false,
+                bus: None,
+                model_invalidated: false,
+            }
+        }
+    }
+
+    /// The settings used to load a set of models
+    struct ModelOptions {
+        model_location: String,
+        model_b_location: Option<String>,
+        max_results: u32,
+        score_threshold: f32,
+        num_threads: i32,
+        enable_coral: bool,
+    }
+
+    impl ModelOptions {
+        fn load(&self) -> Result<Models> {
+            Ok(Models {
+                a: Some(Arc::new(self.create_detector(&self.model_location)?)),
+                b: self
+                    .model_b_location
+                    .as_ref()
+                    .map(|location| self.create_detector(location).map(Arc::new))
+                    .transpose()?,
+            })
+        }
+
+        /// Creates a detector for the model at `model_location`, falling back to the CPU
+        /// if the Coral delegate is enabled but fails to initialize.
+        fn create_detector(&self, model_location: &str) -> Result<ObjectDetector> {
+            if self.enable_coral {
+                match self.create_detector_with_delegate(model_location, true) {
+                    Ok(detector) => return Ok(detector),
+                    Err(err) => warning!(
+                        CAT,
+                        "Coral delegate failed to initialize, falling back to the CPU, {}",
+                        err
+                    ),
+                }
+            }
+            self.create_detector_with_delegate(model_location, false)
+        }
+
+        fn create_detector_with_delegate(
+            &self,
+            model_location: &str,
+            enable_coral: bool,
+        ) -> Result<ObjectDetector> {
+            info!(
+                CAT,
+                "Creating new inference model, path={} threads={} coral={}",
+                model_location,
+                self.num_threads,
+                enable_coral
+            );
+            if enable_coral && !cfg!(feature = "coral-tpu") {
+                return Err(anyhow::anyhow!("Built without the coral-tpu feature"));
+            }
+            Ok(ObjectDetector::with_options(
+                BaseOptions {
+                    model_path: model_location.into(),
+                    num_threads: self.num_threads,
+                    #[cfg(feature = "coral-tpu")]
+                    enable_coral,
+                    ..Default::default()
+                },
+                DetectionOptions {
+                    max_results: Some(self.max_results as i32),
+                    score_threshold: Some(self.score_threshold),
+                },
+            )?)
+        }
+    }
+
+    /// The models used for detection. These are replaced as a whole once a new set has
+    /// loaded, so frames never see a partially loaded set.
+    #[derive(Clone, Default)]
+    struct Models {
+        a: Option<Arc<ObjectDetector>>,
+        /// The model compared against `a`, if any. The two are run on alternating
+        /// frames.
+        b: Option<Arc<ObjectDetector>>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum ModelSlot {
+        A,
+        B,
+    }
+
+    /// The stats of the models being compared, since they were last logged
+    #[derive(Default)]
+    struct Comparison {
+        a: ModelStats,
+        b: ModelStats,
+        started_at: Option<Instant>,
+    }
+
+    /// A frame awaiting inference
+    struct Frame {
+        buffer: gst::Buffer,
+        video_info: VideoInfo,
+    }
+
+    impl Frame {
+        fn size(&self) -> Size {
+            Size::new(
+                self.video_info.width() as f64,
+                self.video_info.height() as f64,
+            )
+        }
+    }
+
+    #[derive(Default)]
+    struct FrameSlot {
+        /// The newest frame, if the worker hasn't picked it up yet
+        frame: Option<Frame>,
+        /// The number of frames replaced before the worker picked them up, since it
+        /// last picked one up
+        dropped_frames: u32,
+        /// The error that stopped the worker, if any
+        flow_error: Option<gst::FlowError>,
+        stopping: bool,
+    }
+
+    /// Hands frames from the streaming thread to the inference worker. Only the newest
+    /// frame is kept, which bounds the latency between a frame and its detections.
+    #[derive(Default)]
+    struct FrameHandoff {
+        slot: Mutex<FrameSlot>,
+        ready: Condvar,
+    }
+
     // Struct containing all the element data
     #[derive(Default)]
     pub struct DetectionSink {
         props_storage: Mutex<PropsStorage>,
         video_info: Mutex<Option<VideoInfo>>,
-        object_detector: Mutex<Option<ObjectDetector>>,
+        handoff: Arc<FrameHandoff>,
+        worker: Mutex<Option<JoinHandle<()>>>,
+        models: Arc<Mutex<Models>>,
+        /// Incremented by each load, so that a slow load can't replace a newer one
+        load_generation: Arc<AtomicU64>,
+        frame_count: AtomicU64,
+        comparison: Mutex<Comparison>,
     }
 
     impl DetectionSink {
-        fn get_dimensions(&self) -> Result<(f64, f64)> {
-            let info_guard = self.video_info.lock().unwrap();
-            let info = info_guard.as_ref().unwrap();
-            Ok((info.width() as f64, info.height() as f64))
-        }
-
         fn post_to_bus(&self, msg: gst::Message) -> Result<()> {
             let instance = self.instance();
             log!(CAT, obj: &instance, "Posting message on bus, {:?}", msg);
@@ -84,58 +236,117 @@ mod imp {
             }
         }
 
-        fn is_model_invalidated(&self) -> bool {
-            let props_guard = self.props_storage.lock().unwrap();
-            props_guard.model_invalidated && props_guard.model_location.is_some()
+        /// Returns the options for a new set of models if the current set has been
+        /// invalidated, marking the models as valid.
+        fn take_invalidated_model_options(&self) -> Option<ModelOptions> {
+            let mut props_guard = self.props_storage.lock().unwrap();
+            if !props_guard.model_invalidated {
+                return None;
+            }
+            let model_location = props_guard.model_location.clone()?;
+            props_guard.model_invalidated = false;
+            Some(ModelOptions {
+                model_location,
+                model_b_location: props_guard.model_b_location.clone(),
+                max_results: props_guard.max_results,
+                score_threshold: props_guard.score_threshold,
+                num_threads: props_guard.num_threads,
+                enable_coral: props_guard.enable_coral,
+            })
         }
 
-        fn create_inference_model(&self) -> Result<()> {
-            let mut props_guard = self.props_storage.lock().unwrap();
-            let model_location = props_guard.model_location.as_ref().unwrap();
-            info!(
-                CAT,
-                "Creating new inference model, path={}", &model_location
-            );
+        /// Loads a new set of models on a background thread, and swaps them in once
+        /// they're ready. The current models continue to be used in the meantime, and
+        /// are kept if the load fails.
+        fn load_models(&self, options: ModelOptions) {
+            let generation = self.load_generation.fetch_add(1, Ordering::SeqCst) + 1;
+            let load_generation = self.load_generation.clone();
+            let models = self.models.clone();
+            *self.comparison.lock().unwrap() = Comparison::default();
+
+            let spawn_res = std::thread::Builder::new()
+                .name("model-loader".into())
+                .spawn(move || {
+                    let start_ts = Instant::now();
+                    let loaded = match options.load() {
+                        Ok(loaded) => loaded,
+                        Err(err) => {
+                            error!(CAT, "Failed to load inference model, {}", err);
+                            return;
+                        }
+                    };
+
+                    // Checked while holding the lock, so that a newer load can't
+                    // complete between the check and the swap
+                    let mut models_guard = models.lock().unwrap();
+                    if load_generation.load(Ordering::SeqCst) != generation {
+                        debug!(CAT, "Discarding models superseded by a newer load");
+                        return;
+                    }
+                    *models_guard = loaded;
+                    info!(
+                        CAT,
+                        "Swapped in new inference models, comparing={}, duration={}",
+                        models_guard.b.is_some(),
+                        start_ts.elapsed().as_secs_f32(),
+                    );
+                });
+            if let Err(err) = spawn_res {
+                error!(CAT, "Failed to start model loader, {}", err);
+            }
+        }
 
-            let object_detector = ObjectDetector::with_options(
-                BaseOptions {
-                    model_path: model_location.clone(),
-                    ..Default::default()
-                },
-                DetectionOptions {
-                    max_results: Some(props_guard.max_results as i32),
-                    score_threshold: Some(props_guard.score_threshold),
-                },
-            )?;
+        /// Chooses the model for the next frame. When comparing models, they're
+        /// alternated, and the chosen model's slot is returned along with it.
+        fn next_model(&self) -> Option<(Arc<ObjectDetector>, Option<ModelSlot>)> {
+            let models = self.models.lock().unwrap();
+            let a = models.a.clone()?;
+            match models.b {
+                Some(ref b) => {
+                    if self.frame_count.fetch_add(1, Ordering::Relaxed) % 2 == 0 {
+                        Some((a, Some(ModelSlot::A)))
+                    } else {
+                        Some((b.clone(), Some(ModelSlot::B)))
+                    }
+                }
+                None => Some((a, None)),
+            }
+        }
 
-            let mut detector_guard = self.object_detector.lock().unwrap();
-            *detector_guard = Some(object_detector);
-            props_guard.model_invalidated = false;
+        /// Records a compared model's frame, and periodically logs the comparison.
+        fn record_comparison(&self, slot: ModelSlot, scores: &[f32], duration: Duration) {
+            let mut comparison = self.comparison.lock().unwrap();
+            let started_at = *comparison.started_at.get_or_insert_with(Instant::now);
+            match slot {
+                ModelSlot::A => comparison.a.record(scores, duration),
+                ModelSlot::B => comparison.b.record(scores, duration),
+            }
 
-            Ok(())
+            if started_at.elapsed() >= COMPARISON_LOG_INTERVAL {
+                let props_guard = self.props_storage.lock().unwrap();
+                info!(
+                    CAT,
+                    "Model comparison over {}s\n  A {}\n    {:?}\n  B {}\n    {:?}",
+                    started_at.elapsed().as_secs(),
+                    comparison.a,
+                    props_guard.model_location,
+                    comparison.b,
+                    props_guard.model_b_location,
+                );
+                *comparison = Comparison::default();
+            }
         }
 
         fn perform_detection(
             &self,
-            buffer: &gst::Buffer,
+            frame: &Frame,
+            detector: &ObjectDetector,
         ) -> Result<DetectionResult, gst::FlowError> {
-            let mut info_guard = self.video_info.lock().unwrap();
-            let video_info = info_guard.as_mut().ok_or_else(|| {
-                gst::element_error!(
-                    &self.obj(),
-                    gst::CoreError::Negotiation,
-                    ["Have no info yet"]
-                );
-                gst::FlowError::NotNegotiated
-            })?;
-
-            let detector_guard = self.object_detector.lock().unwrap();
-            let detector = detector_guard
-                .as_ref()
-                .expect("We don't have an object detector, but should");
-
             Ok(detector
-                .detect(TensorflowBufferAdapter { video_info, buffer })
+                .detect(TensorflowBufferAdapter {
+                    video_info: &frame.video_info,
+                    buffer: &frame.buffer,
+                })
                 .map_err(|_| FlowError::Error)?)
         }
     }
@@ -163,6 +374,13 @@ mod imp {
                         .nick("Model location")
                         .blurb("Path to the .tflite file")
                         .build(),
+                    glib::ParamSpecString::builder("model-b-location")
+                        .nick("Comparison model location")
+                        .blurb(
+                            "Path to a .tflite file run on alternating frames, to compare \
+                             against model-location",
+                        )
+                        .build(),
                     glib::ParamSpecUInt::builder("max-results")
                         .nick("Max result count")
                         .blurb("The maximum number of detections to return per frame")
@@ -173,6 +391,23 @@ mod imp {
                         .blurb("The minimum score a detection must meet to be returned")
                         .default_value(0.3)
                         .build(),
+                    glib::ParamSpecInt::builder("num-threads")
+                        .nick("Thread count")
+                        .blurb(
+                            "The number of threads used for inference, or -1 to let TFLite \
+                             decide",
+                        )
+                        .minimum(-1)
+                        .default_value(-1)
+                        .build(),
+                    glib::ParamSpecBoolean::builder("enable-coral")
+                        .nick("Enable Coral")
+                        .blurb(
+                            "Delegate inference to a Coral Edge TPU, falling back to the CPU if \
+                             it fails to initialize",
+                        )
+                        .default_value(false)
+                        .build(),
                     glib::ParamSpecObject::builder::<gst::Bus>("bus")
                         .nick("Pipeline bus")
                         .blurb("The bus to which detection messages are written")
@@ -190,6 +425,11 @@ mod imp {
                     props_guard.model_location = value.get().expect("type checked upstream");
                     props_guard.model_invalidated = true
                 }
+                "model-b-location" => {
+                    props_guard.model_b_location =
+                        value.get().expect("type checked upstream");
+                    props_guard.model_invalidated = true
+                }
                 "max-results" => {
                     props_guard.max_results =
                         value.get::<u32>().expect("type checked upstream");
@@ -199,6 +439,14 @@ mod imp {
                     props_guard.score_threshold = value.get().expect("type checked upstream");
                     props_guard.model_invalidated = true
                 }
+                "num-threads" => {
+                    props_guard.num_threads = value.get().expect("type checked upstream");
+                    props_guard.model_invalidated = true
+                }
+                "enable-coral" => {
+                    props_guard.enable_coral = value.get().expect("type checked upstream");
+                    props_guard.model_invalidated = true
+                }
                 "bus" => props_guard.bus = value.get().expect("type checked upstream"),
                 _ => unimplemented!(),
             }
@@ -208,8 +456,11 @@ mod imp {
             let props_guard = self.props_storage.lock().unwrap();
             match pspec.name() {
                 "model-location" => props_guard.model_location.to_value(),
+                "model-b-location" => props_guard.model_b_location.to_value(),
                 "max-results" => props_guard.max_results.to_value(),
                 "score-threshold" => props_guard.score_threshold.to_value(),
+                "num-threads" => props_guard.num_threads.to_value(),
+                "enable-coral" => props_guard.enable_coral.to_value(),
                 "bus" => props_guard.bus.to_value(),
                 _ => unimplemented!(),
             }
@@ -269,6 +520,35 @@ mod imp {
 
             Ok(())
         }
+
+        fn start(&self) -> Result<(), gst::ErrorMessage> {
+            *self.handoff.slot.lock().unwrap() = FrameSlot::default();
+
+            let handoff = self.handoff.clone();
+            let sink = self.obj().downgrade();
+            let worker = std::thread::Builder::new()
+                .name("inference".into())
+                .spawn(move || run_inference_worker(handoff, sink))
+                .map_err(|err| {
+                    gst::error_msg!(
+                        gst::ResourceError::Failed,
+                        ["Failed to start inference worker, {}", err]
+                    )
+                })?;
+            *self.worker.lock().unwrap() = Some(worker);
+
+            Ok(())
+        }
+
+        fn stop(&self) -> Result<(), gst::ErrorMessage> {
+            self.handoff.slot.lock().unwrap().stopping = true;
+            self.handoff.ready.notify_one();
+            if let Some(worker) = self.worker.lock().unwrap().take() {
+                let _ = worker.join();
+            }
+
+            Ok(())
+        }
     }
 
     impl VideoSinkImpl for DetectionSink {
@@ -276,17 +556,64 @@ mod imp {
             &self,
             buffer: &gst::Buffer,
         ) -> Result<gst::FlowSuccess, gst::FlowError> {
-            // Create the model if it's been invalidated, or is not yet created
-            if self.is_model_invalidated() {
-                self.create_inference_model()
-                    .map_err(|_| FlowError::Error)?;
+            let video_info = self.video_info.lock().unwrap().clone().ok_or_else(|| {
+                gst::element_error!(
+                    &self.obj(),
+                    gst::CoreError::Negotiation,
+                    ["Have no info yet"]
+                );
+                gst::FlowError::NotNegotiated
+            })?;
+
+            // Hand the frame to the inference worker, replacing any frame it hasn't
+            // picked up yet
+            let mut slot = self.handoff.slot.lock().unwrap();
+            if let Some(err) = slot.flow_error {
+                return Err(err);
+            }
+            if slot
+                .frame
+                .replace(Frame {
+                    buffer: buffer.clone(),
+                    video_info,
+                })
+                .is_some()
+            {
+                slot.dropped_frames += 1;
+                log!(CAT, "Dropped a stale inference frame");
+            }
+            self.handoff.ready.notify_one();
+
+            Ok(gst::FlowSuccess::Ok)
+        }
+    }
+
+    impl DetectionSink {
+        /// Runs inference on `frame`, and posts the results to the bus. Called from the
+        /// inference worker.
+        fn infer_frame(
+            &self,
+            frame: Frame,
+            dropped_frames: u32,
+        ) -> Result<(), gst::FlowError> {
+            // Start loading new models if they've been invalidated, or are not yet
+            // created. Frames are skipped until the first models are loaded.
+            if let Some(options) = self.take_invalidated_model_options() {
+                self.load_models(options);
             }
+            let (detector, slot) = match self.next_model() {
+                Some(model) => model,
+                None => {
+                    log!(CAT, "Skipping frame, waiting for a model to load");
+                    return Ok(());
+                }
+            };
 
             let start_ts = Instant::now();
 
             // Notify that we're beginning the inference frame
-            let dts = buffer.pts().unwrap();
-            debug!(CAT, "Starting inference frame {:?}", dts,);
+            let dts = frame.buffer.pts().unwrap();
+            debug!(CAT, "Starting inference frame {:?}, model={:?}", dts, slot);
             self.post_to_bus(
                 AAMessage::InferFrameStart { dts: dts }
                     .to_gst_message()
@@ -296,7 +623,7 @@ mod imp {
             .map_err(|_| FlowError::Error)?;
 
             // Run object detection on the frame
-            let res = self.perform_detection(buffer)?;
+            let res = self.perform_detection(&frame, &detector)?;
             if res.size() != 0 {
                 debug!(
                     DETECT_CAT,
@@ -305,6 +632,7 @@ mod imp {
                     if res.size() == 1 { "" } else { "s" }
                 );
             }
+            let mut scores = Vec::with_capacity(res.size());
             for d in res.detections() {
                 let (label, score) = {
                     let first_category = d
@@ -313,18 +641,22 @@ mod imp {
                         .expect("A detection result should have at least one category");
                     (first_category.label_as_string(), first_category.score())
                 };
+                scores.push(score);
 
                 debug!(DETECT_CAT, "label={:<8} score={}", label, score);
 
-                let (w, h) = self.get_dimensions().unwrap();
+                let frame_size = frame.size();
                 let object_bounds = d.bounding_box();
                 let fractional_bounds = Rect::new(
-                    object_bounds.x as f64 / w,
-                    object_bounds.y as f64 / h,
-                    object_bounds.width as f64 / w,
-                    object_bounds.height as f64 / h,
-                );
+                    object_bounds.x as f64,
+                    object_bounds.y as f64,
+                    object_bounds.width as f64,
+                    object_bounds.height as f64,
+                )
+                .to_normalized(frame_size);
 
+                // Both models' detections are posted when comparing, so that tracking
+                // continues at the full inference rate
                 self.post_to_bus(
                     AAMessage::InferObjectDetection(DetectionDetails {
                         pts: dts,
@@ -344,6 +676,7 @@ mod imp {
                     dts: dts,
                     detection_count: res.size() as i32,
                     duration: start_ts.elapsed(),
+                    dropped_frames,
                 }
                 .to_gst_message()
                 // TODO(shyndman): We really need to report the error text
@@ -351,14 +684,52 @@ mod imp {
             )
             .map_err(|_| FlowError::Error)?;
 
+            if let Some(slot) = slot {
+                self.record_comparison(slot, &scores, start_ts.elapsed());
+            }
+
             debug!(
                 CAT,
-                "Finished inference frame {:?}, duration={}",
+                "Finished inference frame {:?}, duration={}, dropped={}",
                 dts,
                 start_ts.elapsed().as_secs_f32(),
+                dropped_frames,
             );
 
-            Ok(gst::FlowSuccess::Ok)
+            Ok(())
+        }
+    }
+
+    /// Runs inference on the newest frame handed off by the streaming thread, until the
+    /// sink stops.
+    fn run_inference_worker(
+        handoff: Arc<FrameHandoff>,
+        sink: glib::WeakRef<super::DetectionSink>,
+    ) {
+        loop {
+            let (frame, dropped_frames) = {
+                let mut slot = handoff.slot.lock().unwrap();
+                loop {
+                    if slot.stopping {
+                        return;
+                    }
+                    if let Some(frame) = slot.frame.take() {
+                        break (frame, std::mem::take(&mut slot.dropped_frames));
+                    }
+                    slot = handoff.ready.wait(slot).unwrap();
+                }
+            };
+
+            let sink = match sink.upgrade() {
+                Some(sink) => sink,
+                None => return,
+            };
+            if let Err(err) = sink.imp().infer_frame(frame, dropped_frames) {
+                // Reported to the streaming thread by the next frame
+                error!(CAT, obj: &sink, "Inference failed, {:?}", err);
+                handoff.slot.lock().unwrap().flow_error = Some(err);
+                return;
+            }
         }
     }
 }
@@ -1,8 +1,11 @@
 mod color_detection_sink;
 mod detection_overlay;
 mod detection_sink;
+mod model_stats;
+mod model_watcher;
 mod tf_buffer_adapter;
 
 pub use color_detection_sink::*;
 pub use detection_overlay::*;
 pub use detection_sink::*;
+pub use model_watcher::connect_model_watcher;
@@ -0,0 +1,77 @@
+//! Summarizes how a detection model performs on live footage, so that models can be
+//! compared.
+use std::fmt;
+use std::time::Duration;
+
+/// Accumulates a model's inference statistics
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ModelStats {
+    pub frames: u64,
+    /// The number of frames with at least one detection
+    pub frames_with_detections: u64,
+    pub detections: u64,
+    pub score_sum: f64,
+    pub duration: Duration,
+}
+
+impl ModelStats {
+    /// Records an inference frame that produced detections with `scores`, and took
+    /// `duration` to run.
+    pub fn record(&mut self, scores: &[f32], duration: Duration) {
+        self.frames += 1;
+        if !scores.is_empty() {
+            self.frames_with_detections += 1;
+        }
+        self.detections += scores.len() as u64;
+        self.score_sum += scores.iter().map(|s| *s as f64).sum::<f64>();
+        self.duration += duration;
+    }
+
+    pub fn mean_duration(&self) -> Duration {
+        if self.frames == 0 {
+            Duration::ZERO
+        } else {
+            self.duration.div_f64(self.frames as f64)
+        }
+    }
+
+    /// The fraction of frames with at least one detection
+    pub fn detection_rate(&self) -> f64 {
+        if self.frames == 0 {
+            0.0
+        } else {
+            self.frames_with_detections as f64 / self.frames as f64
+        }
+    }
+
+    pub fn detections_per_frame(&self) -> f64 {
+        if self.frames == 0 {
+            0.0
+        } else {
+            self.detections as f64 / self.frames as f64
+        }
+    }
+
+    pub fn mean_score(&self) -> f64 {
+        if self.detections == 0 {
+            0.0
+        } else {
+            self.score_sum / self.detections as f64
+        }
+    }
+}
+
+impl fmt::Display for ModelStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frames={} mean_duration={:.1}ms detection_rate={:.2} \
+             detections_per_frame={:.2} mean_score={:.3}",
+            self.frames,
+            self.mean_duration().as_secs_f64() * 1000.0,
+            self.detection_rate(),
+            self.detections_per_frame(),
+            self.mean_score()
+        )
+    }
+}
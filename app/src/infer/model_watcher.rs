@@ -0,0 +1,183 @@
+//! Keeps the detection sink's models in line with the live configuration, and reloads
+//! them when their files change, so that retrained models can be tried without
+//! restarting the pipeline.
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use aa_foundation::path::to_canonicalized_path_string;
+use anyhow::{anyhow, Result};
+use figment::value::magic::RelativePathBuf;
+use gst::prelude::*;
+use once_cell::sync::Lazy;
+
+use crate::config::SharedConfig;
+use crate::logging::*;
+use crate::pipeline::names;
+
+pub(self) static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "AA_MODEL_WATCH",
+        gst::DebugColorFlags::FG_YELLOW,
+        Some("Auto-Arena Model Watcher"),
+    )
+});
+
+/// How often the model files and configuration are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Assigns the configured models to the detection sink whenever the configuration's
+/// model paths change, or (if `watch_model_files` is set) whenever a model file is
+/// rewritten. Assigning a model causes the sink to reload it in the background.
+pub fn connect_model_watcher(config: SharedConfig, pipeline: &gst::Pipeline) -> Result<()> {
+    let sink = pipeline
+        .by_name(names::DETECTION_SINK)
+        .ok_or(anyhow!("Detection sink not found"))?
+        .downgrade();
+
+    // The sink has already been assigned the models present at startup
+    let mut model_a = WatchedModel::default();
+    let mut model_b = WatchedModel::default();
+    {
+        let config = config.read().unwrap();
+        model_a.poll(Some(resolve_model(&config.detection.model_path)?), false);
+        model_b.poll(
+            match config.detection.model_b_path {
+                Some(ref path) => Some(resolve_model(path)?),
+                None => None,
+            },
+            false,
+        );
+    }
+
+    info!(CAT, "Watching detection models");
+    glib::timeout_add(WATCH_INTERVAL, move || {
+        let sink = match sink.upgrade() {
+            Some(sink) => sink,
+            None => return glib::Continue(false),
+        };
+        let detection = config.read().unwrap().detection.clone();
+        let watch_files = detection.watch_model_files;
+
+        match resolve_model(&detection.model_path) {
+            Ok(model) => {
+                if model_a.poll(Some(model), watch_files) {
+                    let location = model_a.location();
+                    info!(CAT, "Assigning model, path={:?}", location);
+                    sink.set_property("model-location", location);
+                }
+            }
+            Err(err) => warning!(CAT, "Can't watch model, {}", err),
+        }
+
+        let model = match detection.model_b_path {
+            Some(ref path) => match resolve_model(path) {
+                Ok(model) => Some(model),
+                Err(err) => {
+                    warning!(CAT, "Can't watch comparison model, {}", err);
+                    return glib::Continue(true);
+                }
+            },
+            None => None,
+        };
+        if model_b.poll(model, watch_files) {
+            let location = model_b.location();
+            info!(CAT, "Assigning comparison model, path={:?}", location);
+            sink.set_property("model-b-location", location);
+        }
+
+        glib::Continue(true)
+    });
+
+    Ok(())
+}
+
+/// A model file as it was last seen
+#[derive(Clone, Debug, PartialEq)]
+struct ModelFile {
+    /// The model's canonical path
+    location: String,
+    modified: SystemTime,
+}
+
+fn resolve_model(path: &RelativePathBuf) -> Result<ModelFile> {
+    let path: PathBuf = path.relative();
+    Ok(ModelFile {
+        location: to_canonicalized_path_string(&path)?,
+        modified: fs::metadata(&path)?.modified()?,
+    })
+}
+
+/// Tracks a model assigned to the detection sink, and decides when it should be
+/// reassigned
+#[derive(Default)]
+struct WatchedModel {
+    /// The model as it was when it was last assigned
+    assigned: Option<ModelFile>,
+    /// The model as it was on the previous poll
+    previous: Option<ModelFile>,
+}
+
+impl WatchedModel {
+    fn location(&self) -> Option<String> {
+        self.assigned.as_ref().map(|model| model.location.clone())
+    }
+
+    /// Returns `true` if the sink should be assigned `model`, either because it's a
+    /// different model, or because `watch_files` is set and its file has been rewritten.
+    ///
+    /// A rewritten file is only reported once its modification time has held steady
+    /// for a poll, so that partially written models aren't loaded.
+    fn poll(&mut self, model: Option<ModelFile>, watch_files: bool) -> bool {
+        let previous = std::mem::replace(&mut self.previous, model.clone());
+        let location_changed = self.assigned.as_ref().map(|m| &m.location) !=
+            model.as_ref().map(|m| &m.location);
+        let file_changed = watch_files && model != self.assigned && model == previous;
+
+        if location_changed || file_changed {
+            self.assigned = model;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn model(location: &str, modified_secs: u64) -> Option<ModelFile> {
+        Some(ModelFile {
+            location: location.into(),
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(modified_secs),
+        })
+    }
+
+    #[test]
+    fn test_reassigns_changed_location() {
+        let mut watched = WatchedModel::default();
+        assert!(watched.poll(model("/a.tflite", 1), false));
+        assert!(!watched.poll(model("/a.tflite", 1), false));
+        assert!(watched.poll(model("/b.tflite", 1), false));
+        assert!(watched.poll(None, false));
+        assert_eq!(watched.location(), None);
+    }
+
+    #[test]
+    fn test_reassigns_rewritten_file_once_settled() {
+        let mut watched = WatchedModel::default();
+        watched.poll(model("/a.tflite", 1), true);
+
+        // Still being written
+        assert!(!watched.poll(model("/a.tflite", 2), true));
+        assert!(!watched.poll(model("/a.tflite", 3), true));
+        // Settled
+        assert!(watched.poll(model("/a.tflite", 3), true));
+        assert!(!watched.poll(model("/a.tflite", 3), true));
+
+        // Ignored when not watching files
+        watched.poll(model("/a.tflite", 4), false);
+        assert!(!watched.poll(model("/a.tflite", 4), false));
+    }
+}
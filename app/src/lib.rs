@@ -1,9 +1,17 @@
 #![feature(array_methods)]
 
 pub mod config;
+pub mod control;
 pub mod foundation;
 pub mod infer;
 pub mod logging;
 pub mod message;
+pub mod metrics;
 pub mod pipeline;
+pub mod preview;
+pub mod recovery;
+pub mod retention;
+pub mod session;
 pub mod system;
+pub mod tracking;
+pub mod upload;
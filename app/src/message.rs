@@ -6,12 +6,18 @@ use anyhow::{anyhow, Result};
 use gst::ClockTime;
 use lazy_static::lazy_static;
 use regex::Regex;
-use strum_macros::{Display as DisplayEnum, EnumString};
+use serde_derive::{Deserialize, Serialize};
+use strum_macros::Display as DisplayEnum;
 
 use crate::foundation::geom::Rect;
+use crate::foundation::structure::{from_structure, to_structure};
 
 const APP: &'static str = "aa";
 
+/// The field naming a message's kind in its serialized forms. In structures, the kind is
+/// part of the structure's name instead.
+const KIND_FIELD: &str = "kind";
+
 lazy_static! {
     static ref KIND_PATTERN: Regex = {
         let s = format!(r"^\w*(?P<app>{})/(?P<name>[\w\d_\.-]+)\w*$", APP);
@@ -19,9 +25,16 @@ lazy_static! {
     };
 }
 
-#[derive(Debug, DisplayEnum, EnumString)]
+/// The application's messages, which are posted to the pipeline's bus.
+///
+/// Messages are encoded by serde, so adding a message only requires adding a variant.
+/// Besides `gst::Structure`, they have a JSON form for consumers outside of GStreamer
+/// and a compact MessagePack form, in which the kind of message is named by a `kind`
+/// field, eg. `{"kind": "track-lost", "track_id": 3, "pts": 1000000}`. Timestamps and
+/// durations are in nanoseconds.
+#[derive(Clone, Debug, Deserialize, DisplayEnum, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
 #[strum(serialize_all = "kebab-case")]
-#[strum(ascii_case_insensitive)]
 pub enum AAMessage {
     /// Emitted when the inference engine begins operating upon a frame.
     ///
@@ -34,8 +47,45 @@ pub enum AAMessage {
     InferObjectDetection(DetectionDetails),
     InferFrameDone {
         dts: ClockTime,
+        #[serde(with = "duration_nanos")]
         duration: Duration,
         detection_count: i32,
+        /// The number of frames dropped since the previous inference frame, because they
+        /// were superseded by newer frames before inference could run on them
+        dropped_frames: u32,
+    },
+    /// Emitted when the tracker has seen an object for enough frames to consider it a
+    /// track.
+    TrackCreated(TrackDetails),
+    /// Emitted once per inference frame for every live track, including tracks that are
+    /// being held through a detection dropout.
+    TrackUpdated(TrackDetails),
+    /// Emitted when a track has gone without a matching detection for too long, and has
+    /// been discarded.
+    TrackLost { track_id: u32, pts: ClockTime },
+    /// Identifies the subject the camera should follow for an inference frame, as chosen
+    /// by the configured target selection policy.
+    TargetSelected(TargetDetails),
+    /// Emitted by the retention manager when recorded chunks can no longer be deleted to
+    /// make space. `paused` is true if recording has been paused, or false if it has
+    /// only been switched to a lower bitrate.
+    StorageLow {
+        free_bytes: u64,
+        used_bytes: u64,
+        paused: bool,
+    },
+    /// Emitted once space has been recovered after a `StorageLow`, and recording is back
+    /// to normal.
+    StorageRecovered { free_bytes: u64, used_bytes: u64 },
+    /// Emitted when a recording session starts.
+    SessionStarted { session_id: String },
+    /// Emitted once a recording session has stopped, and its final chunk is complete.
+    SessionStopped { session_id: String },
+    /// Emitted when the source watchdog has rebuilt the media sources, after receiving no
+    /// frames from them for `stalled_for`.
+    SourceRestarted {
+        #[serde(with = "duration_nanos")]
+        stalled_for: Duration,
     },
 }
 
@@ -48,6 +98,14 @@ impl AAMessage {
         format!("{}/{}", APP, self)
     }
 
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
     pub fn from_gst_message(msg: &gst::Message) -> Result<Self> {
         let structure = msg
             .structure()
@@ -56,75 +114,239 @@ impl AAMessage {
         Self::from_gst_message_structure(structure)
     }
 
+    /// Decodes a message from a structure written by `to_gst_message`, which is named
+    /// after the message's kind.
     pub fn from_gst_message_structure(structure: &gst::StructureRef) -> Result<Self> {
         if !Self::kind_matches(structure.name()) {
             return Err(anyhow!("Provided structure is not an AAMessage"));
         }
 
-        let message_name = structure.name().split("/").last().unwrap();
-
-        // We first deserialize into an empty enum variant, then match on it, and from
-        // that determine what we should pull out of the provided structure.
-        let empty_message: AAMessage = message_name.parse()?;
-        let full_message = match empty_message {
-            AAMessage::InferFrameStart { .. } => AAMessage::InferFrameStart {
-                dts: structure.get("dts")?,
-            },
-            AAMessage::InferObjectDetection(..) => {
-                AAMessage::InferObjectDetection(DetectionDetails {
-                    pts: structure.get("dts")?,
-                    label: structure.get("label")?,
-                    score: structure.get("score")?,
-                    bounds: structure.get("bounds")?,
-                })
-            }
-            AAMessage::InferFrameDone { .. } => AAMessage::InferFrameDone {
-                dts: structure.get("dts")?,
-                detection_count: structure.get("detection_count")?,
-                duration: structure.get::<ClockTime>("duration")?.into(),
-            },
-        };
-        Ok(full_message)
+        let mut structure = structure.to_owned();
+        let kind = structure.name().split("/").last().unwrap().to_owned();
+        structure.set(KIND_FIELD, kind);
+        Ok(from_structure(&structure)?)
     }
 
     pub fn to_gst_message(&self) -> Result<gst::Message> {
-        let mut structure = gst::Structure::new_empty(&self.kind());
-        match self {
-            AAMessage::InferFrameStart { dts } => {
-                structure.set("dts", dts);
-            }
-            AAMessage::InferObjectDetection(DetectionDetails {
-                pts: dts,
-                label,
-                score,
-                bounds,
-            }) => {
-                structure.set("dts", dts);
-                structure.set("label", label);
-                structure.set("score", score);
-                structure.set("bounds", bounds);
-            }
-            AAMessage::InferFrameDone {
-                dts,
-                detection_count,
-                duration,
-            } => {
-                structure.set("dts", dts);
-                structure.set("detection_count", detection_count);
-                structure.set(
-                    "duration",
-                    <ClockTime as TryFrom<Duration>>::try_from(*duration).unwrap(),
-                );
-            }
-        }
+        let mut structure = to_structure(self)?;
+        structure.remove_field(KIND_FIELD);
+        structure.set_name(&self.kind());
         Ok(gst::message::Application::builder(structure).build())
     }
+
+    /// Encodes the message compactly, as MessagePack.
+    pub fn to_msgpack(&self) -> Result<Vec<u8>> {
+        // Fields are named, as the `kind` tag requires a self-describing format
+        Ok(rmp_serde::to_vec_named(self)?)
+    }
+
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Self> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+/// Serializes a `Duration` as a number of nanoseconds, to match the clock times
+/// alongside it.
+mod duration_nanos {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        duration: &Duration,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_nanos() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_nanos)
+    }
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct DetectionDetails {
     pub pts: ClockTime,
     pub label: String,
     pub score: f32,
     pub bounds: Rect,
 }
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct TrackDetails {
+    /// Identifies the track across inference frames. IDs are never reused.
+    pub track_id: u32,
+    pub pts: ClockTime,
+    pub label: String,
+    pub score: f32,
+    pub bounds: Rect,
+    /// The number of consecutive frames the track has gone without a matching
+    /// detection. If non-zero, `bounds` is a prediction.
+    pub missed_frames: u32,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct TargetDetails {
+    pub pts: ClockTime,
+    /// The track the target was selected from. When boxes are merged, this is the track
+    /// of the primary (horse) box.
+    pub track_id: u32,
+    pub label: String,
+    pub score: f32,
+    pub bounds: Rect,
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn clock_time() -> impl Strategy<Value = ClockTime> {
+        // `u64::MAX` is reserved for `ClockTime::NONE`
+        (0..u64::MAX).prop_map(ClockTime::from_nseconds)
+    }
+
+    fn label() -> impl Strategy<Value = String> {
+        "[a-z ]{0,12}"
+    }
+
+    fn score() -> impl Strategy<Value = f32> {
+        0.0f32..=1.0
+    }
+
+    fn rect() -> impl Strategy<Value = Rect> {
+        (-2.0..2.0, -2.0..2.0, 0.0..2.0, 0.0..2.0)
+            .prop_map(|(x, y, width, height)| Rect::new(x, y, width, height))
+    }
+
+    fn track_details() -> impl Strategy<Value = TrackDetails> {
+        (
+            any::<u32>(),
+            clock_time(),
+            label(),
+            score(),
+            rect(),
+            any::<u32>(),
+        )
+            .prop_map(|(track_id, pts, label, score, bounds, missed_frames)| {
+                TrackDetails {
+                    track_id,
+                    pts,
+                    label,
+                    score,
+                    bounds,
+                    missed_frames,
+                }
+            })
+    }
+
+    fn any_message() -> impl Strategy<Value = AAMessage> {
+        prop_oneof![
+            clock_time().prop_map(|dts| AAMessage::InferFrameStart { dts }),
+            (clock_time(), label(), score(), rect()).prop_map(
+                |(pts, label, score, bounds)| {
+                    AAMessage::InferObjectDetection(DetectionDetails {
+                        pts,
+                        label,
+                        score,
+                        bounds,
+                    })
+                }
+            ),
+            (clock_time(), any::<u64>(), any::<i32>(), any::<u32>()).prop_map(
+                |(dts, duration_nanos, detection_count, dropped_frames)| {
+                    AAMessage::InferFrameDone {
+                        dts,
+                        duration: Duration::from_nanos(duration_nanos),
+                        detection_count,
+                        dropped_frames,
+                    }
+                }
+            ),
+            track_details().prop_map(AAMessage::TrackCreated),
+            track_details().prop_map(AAMessage::TrackUpdated),
+            (any::<u32>(), clock_time())
+                .prop_map(|(track_id, pts)| AAMessage::TrackLost { track_id, pts }),
+            (clock_time(), any::<u32>(), label(), score(), rect()).prop_map(
+                |(pts, track_id, label, score, bounds)| {
+                    AAMessage::TargetSelected(TargetDetails {
+                        pts,
+                        track_id,
+                        label,
+                        score,
+                        bounds,
+                    })
+                }
+            ),
+            (any::<u64>(), any::<u64>(), any::<bool>()).prop_map(
+                |(free_bytes, used_bytes, paused)| AAMessage::StorageLow {
+                    free_bytes,
+                    used_bytes,
+                    paused,
+                }
+            ),
+            (any::<u64>(), any::<u64>()).prop_map(|(free_bytes, used_bytes)| {
+                AAMessage::StorageRecovered {
+                    free_bytes,
+                    used_bytes,
+                }
+            }),
+            label().prop_map(|session_id| AAMessage::SessionStarted { session_id }),
+            label().prop_map(|session_id| AAMessage::SessionStopped { session_id }),
+            any::<u64>().prop_map(|stalled_for_nanos| AAMessage::SourceRestarted {
+                stalled_for: Duration::from_nanos(stalled_for_nanos),
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_roundtrip_gst_message() {
+        gst::init().unwrap();
+        proptest!(|(message in any_message())| {
+            let gst_message = message.to_gst_message().unwrap();
+            assert_eq!(gst_message.structure().unwrap().name(), message.kind());
+            assert_eq!(message, AAMessage::from_gst_message(&gst_message).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_roundtrip_json() {
+        proptest!(|(message in any_message())| {
+            let json = message.to_json().unwrap();
+            assert_eq!(message, AAMessage::from_json(&json).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_roundtrip_msgpack() {
+        proptest!(|(message in any_message())| {
+            let bytes = message.to_msgpack().unwrap();
+            assert_eq!(message, AAMessage::from_msgpack(&bytes).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_kind_names_agree() {
+        proptest!(|(message in any_message())| {
+            let json = serde_json::to_value(&message).unwrap();
+            assert_eq!(json[KIND_FIELD], message.to_string());
+        });
+    }
+
+    #[test]
+    fn test_detection_pts_field() {
+        gst::init().unwrap();
+        let message = AAMessage::InferObjectDetection(DetectionDetails {
+            pts: ClockTime::from_seconds(2),
+            ..Default::default()
+        })
+        .to_gst_message()
+        .unwrap();
+        let structure = message.structure().unwrap();
+        assert_eq!(structure.get::<u64>("pts").unwrap(), 2_000_000_000);
+        assert!(!structure.has_field("dts"));
+    }
+}
@@ -0,0 +1,393 @@
+//! Aggregates the application's health into metrics, which are exported in the
+//! Prometheus text format.
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fs, io};
+
+use aa_sys::timer::{timer_stats, TimerStats};
+use anyhow::{anyhow, Result};
+use gst::prelude::*;
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, Counter, Encoder, Gauge, Histogram, HistogramOpts, IntCounter,
+    IntGauge, Registry, TextEncoder,
+};
+
+use crate::config::Config;
+use crate::logging::*;
+use crate::message::AAMessage;
+use crate::pipeline::names;
+use crate::retention::free_space_bytes;
+
+pub(self) static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "AA_METRICS",
+        gst::DebugColorFlags::FG_CYAN,
+        Some("Auto-Arena Metrics"),
+    )
+});
+
+/// Holds the application's metrics. Those driven by bus messages are updated as they
+/// arrive, and the rest are sampled when the metrics are exported.
+pub struct Metrics {
+    registry: Registry,
+
+    inference_duration: Histogram,
+    inference_detections: Histogram,
+    inference_frames: IntCounter,
+    inference_dropped_frames: IntCounter,
+
+    encoder_queue_buffers: IntGauge,
+    encoder_queue_seconds: Gauge,
+
+    timer_waits: IntCounter,
+    timer_late_waits: IntCounter,
+    timer_lateness_seconds: Counter,
+
+    storage_chunk_bytes: IntGauge,
+    storage_free_bytes: IntGauge,
+
+    /// Held while sampling, so that concurrent exports don't double count
+    sample_lock: Mutex<()>,
+    pipeline: glib::WeakRef<gst::Pipeline>,
+    storage_dir: PathBuf,
+    chunk_extension: &'static str,
+}
+
+impl Metrics {
+    pub fn connect(config: &Config, pipeline: &gst::Pipeline) -> Result<Arc<Self>> {
+        info!(CAT, "Connecting metrics");
+        let bus = pipeline
+            .bus()
+            .ok_or(anyhow!("Pipeline without bus. Shouldn't happen!"))?;
+
+        let metrics = Arc::new(Self::new(
+            pipeline,
+            config.video_storage.temp_dir_path.relative(),
+            config.video_storage.container_format.extension(),
+        )?);
+
+        let weak_metrics = Arc::downgrade(&metrics);
+        bus.connect("message", true, move |args| {
+            let msg = args[1].get::<gst::Message>().unwrap();
+            if let (Some(metrics), Ok(message)) =
+                (weak_metrics.upgrade(), AAMessage::from_gst_message(&msg))
+            {
+                metrics.handle_message(&message);
+            }
+            None
+        });
+
+        Ok(metrics)
+    }
+
+    /// Creates the metrics, sampling the encoder queue of `pipeline` and the chunks with
+    /// `chunk_extension` in `storage_dir`.
+    fn new(
+        pipeline: &gst::Pipeline,
+        storage_dir: PathBuf,
+        chunk_extension: &'static str,
+    ) -> Result<Self> {
+        let registry = Registry::new_custom(Some("aa".into()), None)?;
+        Ok(Self {
+            inference_duration: register(
+                &registry,
+                Histogram::with_opts(
+                    HistogramOpts::new(
+                        "inference_duration_seconds",
+                        "The time taken to run inference on a frame",
+                    )
+                    .buckets(exponential_buckets(0.005, 2.0, 10)?),
+                )?,
+            )?,
+            inference_detections: register(
+                &registry,
+                Histogram::with_opts(
+                    HistogramOpts::new(
+                        "inference_detections",
+                        "The number of objects detected in a frame",
+                    )
+                    .buckets(vec![0.0, 1.0, 2.0, 3.0, 4.0, 6.0, 8.0, 12.0]),
+                )?,
+            )?,
+            inference_frames: register(
+                &registry,
+                IntCounter::new("inference_frames_total", "The number of inference frames")?,
+            )?,
+            inference_dropped_frames: register(
+                &registry,
+                IntCounter::new(
+                    "inference_dropped_frames_total",
+                    "The number of frames dropped because inference was running behind",
+                )?,
+            )?,
+            encoder_queue_buffers: register(
+                &registry,
+                IntGauge::new(
+                    "encoder_queue_buffers",
+                    "The number of buffers waiting to be encoded",
+                )?,
+            )?,
+            encoder_queue_seconds: register(
+                &registry,
+                Gauge::new(
+                    "encoder_queue_seconds",
+                    "The duration of the video waiting to be encoded",
+                )?,
+            )?,
+            timer_waits: register(
+                &registry,
+                IntCounter::new(
+                    "stepper_timer_waits_total",
+                    "The number of waits made by the stepper timers",
+                )?,
+            )?,
+            timer_late_waits: register(
+                &registry,
+                IntCounter::new(
+                    "stepper_timer_late_waits_total",
+                    "The number of stepper timer waits that finished late",
+                )?,
+            )?,
+            timer_lateness_seconds: register(
+                &registry,
+                Counter::new(
+                    "stepper_timer_lateness_seconds_total",
+                    "The total time by which stepper timer waits finished late",
+                )?,
+            )?,
+            storage_chunk_bytes: register(
+                &registry,
+                IntGauge::new(
+                    "storage_chunk_bytes",
+                    "The size of the recorded chunks in the video storage directory",
+                )?,
+            )?,
+            storage_free_bytes: register(
+                &registry,
+                IntGauge::new(
+                    "storage_free_bytes",
+                    "The space available on the video storage device",
+                )?,
+            )?,
+            registry,
+            sample_lock: Mutex::new(()),
+            pipeline: pipeline.downgrade(),
+            storage_dir,
+            chunk_extension,
+        })
+    }
+
+    /// Returns the metrics in the Prometheus text format.
+    pub fn export(&self) -> Result<String> {
+        self.sample();
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+
+    fn handle_message(&self, message: &AAMessage) {
+        if let AAMessage::InferFrameDone {
+            duration,
+            detection_count,
+            dropped_frames,
+            ..
+        } = message
+        {
+            self.inference_duration.observe(duration.as_secs_f64());
+            self.inference_detections.observe(*detection_count as f64);
+            self.inference_frames.inc();
+            self.inference_dropped_frames.inc_by(*dropped_frames as u64);
+        }
+    }
+
+    /// Updates the metrics that aren't driven by bus messages.
+    fn sample(&self) {
+        let _guard = self.sample_lock.lock().unwrap();
+
+        if let Some(queue) = self
+            .pipeline
+            .upgrade()
+            .and_then(|pipeline| pipeline.by_name(names::PERSISTENCE_ENCODER_QUEUE))
+        {
+            self.encoder_queue_buffers
+                .set(queue.property::<u32>("current-level-buffers") as i64);
+            let level = Duration::from_nanos(queue.property::<u64>("current-level-time"));
+            self.encoder_queue_seconds.set(level.as_secs_f64());
+        }
+
+        self.sync_timer_counters(timer_stats());
+
+        match self.chunk_bytes() {
+            Ok(bytes) => self.storage_chunk_bytes.set(bytes as i64),
+            Err(err) => warning!(CAT, "Failed to measure chunk storage, {}", err),
+        }
+        match free_space_bytes(&self.storage_dir) {
+            Ok(bytes) => self.storage_free_bytes.set(bytes as i64),
+            Err(err) => warning!(CAT, "Failed to measure free storage, {}", err),
+        }
+    }
+
+    /// Advances the timer counters to the totals in `timer`. The totals are kept by the
+    /// timers themselves, since they're shared by every timer in the process.
+    fn sync_timer_counters(&self, timer: TimerStats) {
+        self.timer_waits
+            .inc_by(timer.waits.saturating_sub(self.timer_waits.get()));
+        self.timer_late_waits
+            .inc_by(timer.late_waits.saturating_sub(self.timer_late_waits.get()));
+        let lateness_secs = timer.lateness_us as f64 / 1_000_000.0;
+        self.timer_lateness_seconds
+            .inc_by((lateness_secs - self.timer_lateness_seconds.get()).max(0.0));
+    }
+
+    fn chunk_bytes(&self) -> Result<u64> {
+        let mut total = 0;
+        for entry in fs::read_dir(&self.storage_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some(self.chunk_extension) {
+                total += match fs::metadata(&path) {
+                    Ok(metadata) => metadata.len(),
+                    // Deleted since the directory was read, like by the retention manager
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+                    Err(err) => return Err(err.into()),
+                };
+            }
+        }
+        Ok(total)
+    }
+}
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(
+    registry: &Registry,
+    metric: M,
+) -> Result<M> {
+    registry.register(Box::new(metric.clone()))?;
+    Ok(metric)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Returns the metrics of an empty pipeline, storing chunks in a directory unique
+    /// to `test`.
+    fn metrics(test: &str) -> (Metrics, gst::Pipeline, PathBuf) {
+        gst::init().unwrap();
+        let dir =
+            std::env::temp_dir().join(format!("aa-metrics-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let pipeline = gst::Pipeline::new(None);
+        let metrics = Metrics::new(&pipeline, dir.clone(), "mp4").unwrap();
+        (metrics, pipeline, dir)
+    }
+
+    /// Returns the lines of `exported` for the metric named `name`.
+    fn samples<'a>(exported: &'a str, name: &str) -> Vec<&'a str> {
+        exported
+            .lines()
+            .filter(|line| line.split([' ', '{']).next() == Some(name))
+            .collect()
+    }
+
+    #[test]
+    fn test_counts_inference_frames() {
+        let (metrics, _pipeline, _) = metrics("inference");
+        for (millis, detection_count, dropped_frames) in [(250, 2, 0), (125, 5, 3)] {
+            metrics.handle_message(&AAMessage::InferFrameDone {
+                dts: gst::ClockTime::ZERO,
+                duration: Duration::from_millis(millis),
+                detection_count,
+                dropped_frames,
+            });
+        }
+        // Other messages are ignored
+        metrics.handle_message(&AAMessage::InferFrameStart {
+            dts: gst::ClockTime::ZERO,
+        });
+
+        let exported = metrics.export().unwrap();
+        assert_eq!(
+            samples(&exported, "aa_inference_frames_total"),
+            ["aa_inference_frames_total 2"]
+        );
+        assert_eq!(
+            samples(&exported, "aa_inference_dropped_frames_total"),
+            ["aa_inference_dropped_frames_total 3"]
+        );
+        assert_eq!(
+            samples(&exported, "aa_inference_duration_seconds_sum"),
+            ["aa_inference_duration_seconds_sum 0.375"]
+        );
+        assert_eq!(
+            samples(&exported, "aa_inference_detections_count"),
+            ["aa_inference_detections_count 2"]
+        );
+        assert!(exported.contains(r#"aa_inference_detections_bucket{le="2"} 1"#));
+        assert!(exported.contains(r#"aa_inference_detections_bucket{le="6"} 2"#));
+    }
+
+    #[test]
+    fn test_timer_counters_follow_totals() {
+        let (metrics, _pipeline, _) = metrics("timer");
+        metrics.sync_timer_counters(TimerStats {
+            waits: 10,
+            late_waits: 2,
+            lateness_us: 500_000,
+        });
+        metrics.sync_timer_counters(TimerStats {
+            waits: 16,
+            late_waits: 3,
+            lateness_us: 1_250_000,
+        });
+
+        let exported = metrics.export().unwrap();
+        assert_eq!(
+            samples(&exported, "aa_stepper_timer_waits_total"),
+            ["aa_stepper_timer_waits_total 16"]
+        );
+        assert_eq!(
+            samples(&exported, "aa_stepper_timer_late_waits_total"),
+            ["aa_stepper_timer_late_waits_total 3"]
+        );
+        assert_eq!(
+            samples(&exported, "aa_stepper_timer_lateness_seconds_total"),
+            ["aa_stepper_timer_lateness_seconds_total 1.25"]
+        );
+    }
+
+    #[test]
+    fn test_samples_storage_and_encoder_queue() {
+        let (metrics, pipeline, dir) = metrics("storage");
+        let queue = gst::ElementFactory::make("queue")
+            .name(names::PERSISTENCE_ENCODER_QUEUE)
+            .build()
+            .unwrap();
+        pipeline.add(&queue).unwrap();
+
+        fs::write(dir.join("chunk-0001.mp4"), vec![0; 1000]).unwrap();
+        fs::write(dir.join("chunk-0002.mp4"), vec![0; 24]).unwrap();
+        fs::write(dir.join("upload-queue.toml"), vec![0; 500]).unwrap();
+        // A chunk deleted between listing the directory and measuring it
+        std::os::unix::fs::symlink(dir.join("deleted.mp4"), dir.join("chunk-0003.mp4"))
+            .unwrap();
+
+        let exported = metrics.export().unwrap();
+        assert_eq!(
+            samples(&exported, "aa_storage_chunk_bytes"),
+            ["aa_storage_chunk_bytes 1024"]
+        );
+        assert_eq!(samples(&exported, "aa_storage_free_bytes").len(), 1);
+        assert_eq!(
+            samples(&exported, "aa_encoder_queue_buffers"),
+            ["aa_encoder_queue_buffers 0"]
+        );
+        assert_eq!(
+            samples(&exported, "aa_encoder_queue_seconds"),
+            ["aa_encoder_queue_seconds 0"]
+        );
+    }
+}
@@ -1,38 +1,105 @@
 use std::fmt::Display;
-use std::time::Instant;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use aa_foundation::path::to_canonicalized_path_string;
 use anyhow::{anyhow, Result};
-use chrono::Local;
 use gst::prelude::*;
 
 use super::{names, CONFIGURE_CAT};
-use crate::config::Config;
+use crate::config::{Config, InferenceDelegate, PreviewMode, RecordMode, SharedConfig};
+use crate::infer::connect_model_watcher;
 use crate::logging::*;
+use crate::metrics::Metrics;
+use crate::preview::{connect_preview_frames, PreviewFrames};
+use crate::retention::connect_retention_manager;
+use crate::session::{connect_activity_recorder, SessionManager};
 use crate::system::HardwareSystems;
+use crate::tracking::{connect_multi_object_tracker, connect_tracking_controller};
+use crate::upload::connect_uploader;
+
+/// How often the debug viewport is moved to follow the simulated motors
+const VIEWPORT_UPDATE_INTERVAL: Duration = Duration::from_millis(33);
+
+/// A pipeline that's been configured, along with the systems that drive it.
+pub struct ConfiguredPipeline {
+    pub main_loop: glib::MainLoop,
+    pub pipeline: gst::Pipeline,
+    pub hardware: HardwareSystems,
+    pub sessions: Arc<SessionManager>,
+    pub config: SharedConfig,
+    /// The `mjpeg` preview's frames, if it's enabled
+    pub preview: Option<PreviewFrames>,
+    pub metrics: Arc<Metrics>,
+}
 
 pub fn configure_pipeline(
     config: &Config,
     hardware: HardwareSystems,
     (main_loop, pipeline): (glib::MainLoop, gst::Pipeline),
-) -> Result<(glib::MainLoop, gst::Pipeline, HardwareSystems)> {
+) -> Result<ConfiguredPipeline> {
     info!(CONFIGURE_CAT, "Configuring pipeline");
     let now = Instant::now();
+    let shared_config: SharedConfig = Arc::new(RwLock::new(config.clone()));
 
-    if let Err(e) = configure_video_storage(config, &pipeline) {
+    let sessions = SessionManager::connect(config, &pipeline)?;
+    let metrics = Metrics::connect(config, &pipeline)?;
+    if let Err(err) = configure_recording(config, &pipeline, &sessions) {
+        warning!(
+            CONFIGURE_CAT,
+            "Problem encountered while configuring recording, {}",
+            err
+        );
+    }
+    if let Err(err) = connect_retention_manager(config, &pipeline) {
         warning!(
             CONFIGURE_CAT,
-            "Problem encountered while configuring video storage, {}",
-            e
+            "Problem encountered while configuring storage retention, {}",
+            err
         );
     }
-    if let Err(err) = configure_detection(config, &pipeline) {
+    if let Err(err) = configure_upload(config, &pipeline) {
+        warning!(
+            CONFIGURE_CAT,
+            "Problem encountered while configuring uploads, {}",
+            err
+        );
+    }
+    if let Err(err) = configure_detection(config, &shared_config, &pipeline) {
         warning!(
             CONFIGURE_CAT,
             "Problem encountered while configuring inference, {}",
             err
         );
     }
+    if let Err(err) = configure_debug_viewport(config, &pipeline, &hardware) {
+        warning!(
+            CONFIGURE_CAT,
+            "Problem encountered while configuring the debug viewport, {}",
+            err
+        );
+    }
+    let preview = match config.preview.preview_mode {
+        PreviewMode::Mjpeg => match connect_preview_frames(&pipeline) {
+            Ok(frames) => Some(frames),
+            Err(err) => {
+                warning!(
+                    CONFIGURE_CAT,
+                    "Problem encountered while configuring the preview, {}",
+                    err
+                );
+                None
+            }
+        },
+        _ => None,
+    };
+    if let Err(err) = configure_tracking(config, &shared_config, &pipeline, &hardware) {
+        warning!(
+            CONFIGURE_CAT,
+            "Problem encountered while configuring tracking, {}",
+            err
+        );
+    }
 
     info!(
         CONFIGURE_CAT,
@@ -40,34 +107,47 @@ pub fn configure_pipeline(
         now.elapsed().as_nanos()
     );
 
-    Ok((main_loop, pipeline, hardware))
+    Ok(ConfiguredPipeline {
+        main_loop,
+        pipeline,
+        hardware,
+        sessions,
+        config: shared_config,
+        preview,
+        metrics,
+    })
 }
 
-fn configure_video_storage(
+fn configure_recording(
     config: &Config,
     pipeline: &gst::Pipeline,
+    sessions: &Arc<SessionManager>,
 ) -> Result<(), anyhow::Error> {
-    let storage_config = &config.video_storage;
-    let persistence_sink = pipeline
-        .by_name(names::PERSISTENCE_SINK)
-        .ok_or(anyhow!("Persistence sink not found"))?;
-
-    set_object_property(
-        &persistence_sink,
-        "max-size-time",
-        storage_config.video_chunk_duration_nanos(),
-    );
-    set_object_property(
-        &persistence_sink,
-        "location",
-        storage_config.video_path_pattern_for_datetime(Local::now())?,
-    );
-
+    match config.recording.record_mode {
+        RecordMode::Continuous => {
+            sessions.start()?;
+        }
+        RecordMode::Manual => {}
+        RecordMode::Activity => {
+            let bus = pipeline
+                .bus()
+                .ok_or(anyhow!("Pipeline without bus. Shouldn't happen!"))?;
+            connect_activity_recorder(&bus, config, sessions.clone())?;
+        }
+    }
     Ok(())
 }
 
+fn configure_upload(config: &Config, pipeline: &gst::Pipeline) -> Result<(), anyhow::Error> {
+    let bus = pipeline
+        .bus()
+        .ok_or(anyhow!("Pipeline without bus. Shouldn't happen!"))?;
+    connect_uploader(&bus, config)
+}
+
 fn configure_detection(
     config: &Config,
+    shared_config: &SharedConfig,
     pipeline: &gst::Pipeline,
 ) -> Result<(), anyhow::Error> {
     let detection_config = &config.detection;
@@ -81,11 +161,28 @@ fn configure_detection(
             "model-location",
             to_canonicalized_path_string(&detection_config.model_path.relative())?.as_str(),
         );
+        if let Some(ref model_b_path) = detection_config.model_b_path {
+            set_object_property(
+                &detection_sink,
+                "model-b-location",
+                to_canonicalized_path_string(&model_b_path.relative())?.as_str(),
+            );
+        }
         set_object_property(
             &detection_sink,
             "score-threshold",
             detection_config.score_threshold,
         );
+        set_object_property(
+            &detection_sink,
+            "num-threads",
+            detection_config.inference_threads() as i32,
+        );
+        set_object_property(
+            &detection_sink,
+            "enable-coral",
+            detection_config.inference_delegate == InferenceDelegate::Coral,
+        );
     } else {
         // Color detection
         set_object_property(
@@ -96,9 +193,121 @@ fn configure_detection(
     }
 
     set_object_property(&detection_sink, "max-results", detection_config.max_results);
+
+    if config.detection.is_ml() {
+        connect_model_watcher(shared_config.clone(), pipeline)?;
+    }
+    Ok(())
+}
+
+fn configure_tracking(
+    config: &Config,
+    shared_config: &SharedConfig,
+    pipeline: &gst::Pipeline,
+    hardware: &HardwareSystems,
+) -> Result<(), anyhow::Error> {
+    let bus = pipeline
+        .bus()
+        .ok_or(anyhow!("Pipeline without bus. Shouldn't happen!"))?;
+    connect_multi_object_tracker(&bus, config)?;
+    connect_tracking_controller(&bus, shared_config.clone(), hardware.pantilt.clone())
+}
+
+/// Moves the debug video's viewport to follow the simulated pan/tilt motors, so that the
+/// tracking loop sees the results of its own movements.
+fn configure_debug_viewport(
+    config: &Config,
+    pipeline: &gst::Pipeline,
+    hardware: &HardwareSystems,
+) -> Result<(), anyhow::Error> {
+    let viewport_fraction = match config.source.debug_viewport_fraction {
+        Some(fraction) => fraction,
+        None => return Ok(()),
+    };
+    if pipeline.by_name(names::DEBUG_VIEWPORT).is_none() {
+        return Err(anyhow!("Debug viewport not found"));
+    }
+    if hardware.pantilt.simulated_position().is_none() {
+        return Err(anyhow!(
+            "The debug viewport requires simulated pantilt motors"
+        ));
+    }
+
+    let pantilt = hardware.pantilt.clone();
+    let tracking_config = config.tracking.clone();
+    let pipeline = pipeline.downgrade();
+    glib::timeout_add(VIEWPORT_UPDATE_INTERVAL, move || {
+        let pipeline = match pipeline.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return glib::Continue(false),
+        };
+        // Looked up on each update, as the viewport is replaced when the source watchdog
+        // rebuilds the sources
+        let viewport = match pipeline.by_name(names::DEBUG_VIEWPORT) {
+            Some(viewport) => viewport,
+            None => return glib::Continue(true),
+        };
+        let position = pantilt.simulated_position();
+        let video_info = viewport
+            .static_pad("sink")
+            .and_then(|pad| pad.current_caps())
+            .and_then(|caps| gst_video::VideoInfo::from_caps(&caps).ok());
+
+        if let (Some((pan, tilt)), Some(info)) = (position, video_info) {
+            let (left, right, top, bottom) = viewport_crop(
+                (info.width() as i32, info.height() as i32),
+                viewport_fraction,
+                (
+                    pan / tracking_config.pan_steps_per_frame_width,
+                    tilt / tracking_config.tilt_steps_per_frame_height,
+                ),
+            );
+            log!(
+                CONFIGURE_CAT,
+                obj: &viewport,
+                "Moving viewport, pan={} tilt={} crop=({}, {}, {}, {})",
+                pan,
+                tilt,
+                left,
+                right,
+                top,
+                bottom
+            );
+            viewport.set_property("left", left);
+            viewport.set_property("right", right);
+            viewport.set_property("top", top);
+            viewport.set_property("bottom", bottom);
+        }
+
+        glib::Continue(true)
+    });
+
     Ok(())
 }
 
+/// Returns the (left, right, top, bottom) crop that places a viewport of
+/// `viewport_fraction` of the video's `dimensions`, offset from the video's center by
+/// `offset_in_viewports` (measured in viewport widths and heights).
+fn viewport_crop(
+    (width, height): (i32, i32),
+    viewport_fraction: f64,
+    (offset_x, offset_y): (f64, f64),
+) -> (i32, i32, i32, i32) {
+    // Crops are kept even, so that chroma subsampled formats stay aligned
+    let even = |v: f64| (v as i32) & !1;
+    let crop_axis = |length: i32, offset: f64| {
+        let viewport_length = even(length as f64 * viewport_fraction);
+        let center = length as f64 / 2.0 + offset * viewport_length as f64;
+        let start =
+            even(center - viewport_length as f64 / 2.0).clamp(0, length - viewport_length);
+        (start, length - viewport_length - start)
+    };
+
+    let (left, right) = crop_axis(width, offset_x);
+    let (top, bottom) = crop_axis(height, offset_y);
+    (left, right, top, bottom)
+}
+
 fn set_object_property<V: ToValue + Display>(
     element: &gst::Element,
     prop_name: &str,
@@ -113,3 +322,48 @@ fn set_object_property<V: ToValue + Display>(
     );
     element.set_property(prop_name, value);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DIMENSIONS: (i32, i32) = (1280, 720);
+
+    #[test]
+    fn test_viewport_crop_centered() {
+        assert_eq!(
+            viewport_crop(DIMENSIONS, 0.5, (0.0, 0.0)),
+            (320, 320, 180, 180)
+        );
+    }
+
+    #[test]
+    fn test_viewport_crop_clamps_to_edges() {
+        // Half a viewport is enough to reach the left edge, anything further stays there
+        assert_eq!(viewport_crop(DIMENSIONS, 0.5, (-0.5, 0.0)).0, 0);
+        assert_eq!(
+            viewport_crop(DIMENSIONS, 0.5, (-3.0, 0.0)),
+            (0, 640, 180, 180)
+        );
+        assert_eq!(
+            viewport_crop(DIMENSIONS, 0.5, (3.0, -3.0)),
+            (640, 0, 0, 360)
+        );
+    }
+
+    #[test]
+    fn test_viewport_crop_full_frame() {
+        for offset in [(0.0, 0.0), (-1.0, 1.0), (2.5, -0.3)] {
+            assert_eq!(viewport_crop(DIMENSIONS, 1.0, offset), (0, 0, 0, 0));
+        }
+    }
+
+    #[test]
+    fn test_viewport_crop_is_even() {
+        // A 332x198 viewport, whose unrounded crops start at 367.2 and 181.2
+        assert_eq!(
+            viewport_crop((1000, 600), 0.333, (0.1, -0.1)),
+            (366, 302, 180, 222)
+        );
+    }
+}
@@ -1,15 +1,30 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
 use anyhow::Result;
 use gst::prelude::*;
 use gst_app::prelude::BaseSinkExt;
 use gst_video::VideoFormat;
 
-use super::source::{create_media_sources, SourcePads};
+use super::encoder::{create_encoder, encoder_output_caps};
+use super::source::create_media_sources;
+use super::watchdog::connect_source_watchdog;
 use super::{names, CREATE_CAT as CAT};
-use crate::config::Config;
+use crate::config::{
+    Config, ContainerFormat, Mp4Layout, PreviewConfig, PreviewMode, RecordMode,
+    VideoStorageConfig,
+};
 use crate::foundation::gst::find_sink_pad;
 use crate::infer::{build_detection_overlay, ColorDetectionSink, DetectionSink};
 use crate::logging::*;
 
+/// The duration of each fragment written to fragmented MP4 chunks, in milliseconds
+const MP4_FRAGMENT_DURATION_MS: u32 = 1000;
+
+/// The space left in the preroll queue beyond the preroll itself, so that it never
+/// fills and leaks before reaching its threshold
+const PREROLL_HEADROOM: Duration = Duration::from_secs(2);
+
 pub fn create_pipeline(config: &Config) -> Result<(glib::MainLoop, gst::Pipeline)> {
     gst::init()?;
     gst::update_registry()?;
@@ -25,10 +40,7 @@ pub fn create_pipeline(config: &Config) -> Result<(glib::MainLoop, gst::Pipeline
         .bus()
         .expect("Pipeline without bus. Shouldn't happen!");
 
-    let SourcePads {
-        display_stream_src_pad,
-        infer_stream_src_pad,
-    } = create_media_sources(config, &pipeline)?;
+    let sources = create_media_sources(config, &pipeline)?;
 
     // The multiqueue allows us to replace all other queues, and is responsible for
     // ensuring that the encoder's heavy up-front frame requests (so it can determine how
@@ -43,15 +55,16 @@ pub fn create_pipeline(config: &Config) -> Result<(glib::MainLoop, gst::Pipeline
     pipeline.add(&queue)?;
 
     let display_sink_pad = queue.request_pad_simple("sink_%u").unwrap();
-    display_stream_src_pad.link(&display_sink_pad)?;
+    sources.display_stream_src_pad.link(&display_sink_pad)?;
     let display_src_pad = display_sink_pad.iterate_internal_links().next()?.unwrap();
 
     let infer_sink_pad = queue.request_pad_simple("sink_%u").unwrap();
-    infer_stream_src_pad.link(&infer_sink_pad)?;
+    sources.infer_stream_src_pad.link(&infer_sink_pad)?;
     let infer_src_pad = infer_sink_pad.iterate_internal_links().next()?.unwrap();
 
     create_display_stream_pipeline(&pipeline, &bus, &display_src_pad, config)?;
     create_infer_stream_pipeline(&pipeline, &bus, &infer_src_pad, config)?;
+    connect_source_watchdog(config, &pipeline, sources)?;
 
     Ok((main_loop, pipeline))
 }
@@ -62,10 +75,10 @@ fn create_display_stream_pipeline(
     display_stream_src: &gst::Pad,
     config: &Config,
 ) -> Result<()> {
-    // Splits the display input to the (optional) debug pipeline and the filesystem
+    // Splits the display input to the (optional) preview pipeline and the filesystem
     // pipeline.
     let display_splitter = gst::ElementFactory::make("tee")
-        .name("display.splitter")
+        .name(names::DISPLAY_SPLITTER)
         .build()?;
     pipeline.add(&display_splitter)?;
     display_stream_src.link(&find_sink_pad(&display_splitter)?)?;
@@ -74,113 +87,288 @@ fn create_display_stream_pipeline(
         .pad_template("src_%u")
         .expect("No src template found on tee");
 
-    create_display_stream_debug_branch(
+    if config.preview.preview_mode != PreviewMode::None {
+        create_display_stream_preview_branch(
+            pipeline,
+            bus,
+            display_splitter
+                .request_pad(&splitter_src_tmpl, None, None)
+                .unwrap(),
+            config,
+        )?;
+    }
+    create_display_stream_encode_branch(
         pipeline,
-        bus,
         display_splitter
             .request_pad(&splitter_src_tmpl, None, None)
             .unwrap(),
         config,
     )?;
-    create_display_stream_persistence_branch(
-        pipeline,
-        bus,
-        display_splitter
-            .request_pad(&splitter_src_tmpl, None, None)
-            .unwrap(),
-    )?;
 
     Ok(())
 }
 
-/// Creates the pipeline branch that consumes the display stream, encodes it, and writes
-/// it in chunks to the filesystem.
-fn create_display_stream_persistence_branch(
+/// Creates the pipeline branch that encodes the display stream. In the `activity` record
+/// mode, the most recent `preroll_secs` of it are held in a queue so that recording
+/// sessions can include the moments before they were started.
+///
+/// The branch ends in the recording splitter, which only has session bins (see
+/// `create_persistence_bin`) linked to it while recording.
+fn create_display_stream_encode_branch(
     pipeline: &gst::Pipeline,
-    _bus: &gst::Bus,
     src_pad: gst::Pad,
+    config: &Config,
 ) -> Result<()> {
+    // Allows recording to be paused when storage runs low
+    let valve = gst::ElementFactory::make("valve")
+        .name(names::PERSISTENCE_VALVE)
+        .build()?;
     let encode_queue = gst::ElementFactory::make("queue")
-        .name("display.persist.encoder.queue")
+        .name(names::PERSISTENCE_ENCODER_QUEUE)
         .build()?;
 
-    let encoder = if cfg!(feature = "use-v4l2-h264-encoding") {
-        gst::ElementFactory::make("v4l2h264enc")
-            .name("display.persist.encoder")
-            .build()?
-    } else {
-        gst::ElementFactory::make("nvh264enc")
-            .name("display.persist.encoder")
-            .property_from_str("preset", "low-latency-hq")
-            .build()?
-    };
+    let encoder = create_encoder(&config.encoder)?;
 
     let caps = gst::ElementFactory::make("capsfilter")
         .name("display.persist.encoder.out.caps")
-        .property_from_str("caps", "video/x-h264,level=(string)4")
+        .property("caps", encoder_output_caps(&config.encoder))
         .build()?;
     let h264parse = gst::ElementFactory::make("h264parse")
         .name("display.persist.parse_encoded")
         // Ensure that we're sending PTS/DTS with each IDR frame
         .property("config-interval", -1)
         .build()?;
+
+    // Holding back `preroll` worth of encoded video delays everything written to disk
+    // by the same amount, which is what lets a session start in the past. Encoded video
+    // is a small fraction of the size of raw frames. Sessions in the other modes start
+    // when asked to, so they skip the delay.
+    let preroll_queue = if config.recording.record_mode == RecordMode::Activity {
+        let preroll_nanos = config.recording.preroll_duration().as_nanos() as u64;
+        Some(
+            gst::ElementFactory::make("queue")
+                .name(names::PREROLL_QUEUE)
+                .property("max-size-buffers", 0u32)
+                .property("max-size-bytes", 0u32)
+                .property(
+                    "max-size-time",
+                    preroll_nanos + PREROLL_HEADROOM.as_nanos() as u64,
+                )
+                .property("min-threshold-time", preroll_nanos)
+                .property_from_str("leaky", "downstream")
+                .build()?,
+        )
+    } else {
+        None
+    };
+    let recording_splitter = gst::ElementFactory::make("tee")
+        .name(names::RECORDING_SPLITTER)
+        .property("allow-not-linked", true)
+        .build()?;
+
+    let mut elements = vec![&valve, &encode_queue, &encoder, &caps, &h264parse];
+    elements.extend(preroll_queue.as_ref());
+    elements.push(&recording_splitter);
+    pipeline.add_many(&elements)?;
+    src_pad.link(&find_sink_pad(&valve)?)?;
+    gst::Element::link_many(&elements)?;
+
+    Ok(())
+}
+
+/// Creates a bin named `name` that consumes the encoded display stream, and writes it in
+/// chunks to files matching `location`.
+///
+/// The bin has a single sink pad, and forwards its children's messages so that the end
+/// of its stream can be observed on the bus.
+pub fn create_persistence_bin(
+    name: &str,
+    storage_config: &VideoStorageConfig,
+    location: &str,
+) -> Result<gst::Bin> {
+    let bin = gst::Bin::new(Some(name));
+    bin.set_property("message-forward", true);
+
+    let queue = gst::ElementFactory::make("queue")
+        .name(names::PERSISTENCE_QUEUE)
+        .build()?;
+    let muxer = create_chunk_muxer(storage_config)?;
     let chunk_file_writer = gst::ElementFactory::make("splitmuxsink")
         .name(names::PERSISTENCE_SINK)
-        .property("max-size-time", 10.minutes().nseconds())
-        .property("muxer-factory", "matroskamux")
-        .property("location", "video%05d.mp4")
+        .property("max-size-time", storage_config.video_chunk_duration_nanos())
+        .property("muxer", &muxer)
+        .property("location", location)
         .property("async-handling", true)
         .build()?;
 
-    pipeline.add_many(&[
-        &encode_queue,
-        &encoder,
-        &caps,
-        &h264parse,
-        &chunk_file_writer,
-    ])?;
-    src_pad.link(&find_sink_pad(&encode_queue)?)?;
-    gst::Element::link_many(&[
-        &encode_queue,
-        &encoder,
-        &caps,
-        &h264parse,
-        &chunk_file_writer,
-    ])?;
+    bin.add_many(&[&queue, &chunk_file_writer])?;
+    queue.link(&chunk_file_writer)?;
 
-    Ok(())
+    // Sessions can begin mid-GOP, and the first chunk must open with a keyframe to be
+    // playable
+    let sink_pad = find_sink_pad(&queue)?;
+    let seen_keyframe = AtomicBool::new(false);
+    sink_pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        if seen_keyframe.load(Ordering::Relaxed) {
+            return gst::PadProbeReturn::Remove;
+        }
+        match info.buffer() {
+            Some(buffer) if !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT) => {
+                seen_keyframe.store(true, Ordering::Relaxed);
+                gst::PadProbeReturn::Remove
+            }
+            _ => gst::PadProbeReturn::Drop,
+        }
+    });
+    bin.add_pad(&gst::GhostPad::with_target(Some("sink"), &sink_pad)?)?;
+
+    Ok(bin)
+}
+
+/// Creates the muxer used to write each video chunk, as configured by `storage_config`.
+fn create_chunk_muxer(storage_config: &VideoStorageConfig) -> Result<gst::Element> {
+    let format = storage_config.container_format;
+    let builder =
+        gst::ElementFactory::make(format.muxer_factory()).name("display.persist.muxer");
+
+    let builder = match (format, storage_config.mp4_layout.unwrap_or_default()) {
+        (ContainerFormat::Mp4, Mp4Layout::Fragmented) => {
+            builder.property("fragment-duration", MP4_FRAGMENT_DURATION_MS)
+        }
+        (ContainerFormat::Mp4, Mp4Layout::Faststart) => builder.property("faststart", true),
+        _ => builder,
+    };
+
+    Ok(builder.build()?)
 }
 
-/// Creates the pipeline branch that
-fn create_display_stream_debug_branch(
+/// Creates the pipeline branch that previews the display stream, as configured by
+/// `config.preview`.
+fn create_display_stream_preview_branch(
     pipeline: &gst::Pipeline,
     bus: &gst::Bus,
     src_pad: gst::Pad,
     config: &Config,
 ) -> Result<()> {
-    let overlay_convert1 = gst::ElementFactory::make("videoconvert")
-        .name("display.debug.overlay_convert_in")
-        .build()?;
-    let overlay_display = build_detection_overlay("display.debug.overlay", bus, config)?;
-    let overlay_convert2 = gst::ElementFactory::make("videoconvert")
-        .name("display.debug.overlay_convert_out")
-        .build()?;
-    let sink_display = gst::ElementFactory::make("autovideosink")
-        .name("display.debug.autovideosink")
+    let preview_config = &config.preview;
+    let mut elements = vec![];
+
+    if preview_config.preview_overlay {
+        elements.push(
+            gst::ElementFactory::make("videoconvert")
+                .name("display.preview.overlay_convert_in")
+                .build()?,
+        );
+        elements.push(build_detection_overlay(
+            "display.preview.overlay",
+            bus,
+            config,
+        )?);
+    }
+    elements.push(
+        gst::ElementFactory::make("videoconvert")
+            .name("display.preview.convert")
+            .build()?,
+    );
+
+    match preview_config.preview_mode {
+        PreviewMode::None => unreachable!("No preview branch is created"),
+        PreviewMode::Window => {
+            let sink = gst::ElementFactory::make("autovideosink")
+                .name(names::PREVIEW_SINK)
+                .build()?;
+            offset_window_sink(&sink, config);
+            elements.push(sink);
+        }
+        PreviewMode::Webrtc => {
+            elements.extend(create_preview_scale_elements(preview_config, None)?);
+            let sink = gst::ElementFactory::make("webrtcsink")
+                .name(names::PREVIEW_SINK)
+                .build()?;
+            if let Some(uri) = &preview_config.preview_webrtc_signaller_uri {
+                sink.dynamic_cast_ref::<gst::ChildProxy>()
+                    .expect("webrtcsink is a child proxy")
+                    .set_child_property("signaller::uri", uri);
+            }
+            elements.push(sink);
+        }
+        PreviewMode::Mjpeg => {
+            elements.extend(create_preview_scale_elements(
+                preview_config,
+                Some(preview_config.preview_max_fps),
+            )?);
+            elements.push(
+                gst::ElementFactory::make("jpegenc")
+                    .name("display.preview.jpegenc")
+                    .property("quality", preview_config.preview_jpeg_quality)
+                    .build()?,
+            );
+            // Only the most recent frame matters to viewers, and a slow consumer
+            // mustn't hold up the display splitter
+            elements.push(
+                gst_app::AppSink::builder()
+                    .name(names::PREVIEW_SINK)
+                    .sync(false)
+                    .drop(true)
+                    .max_buffers(1)
+                    .build()
+                    .upcast(),
+            );
+        }
+    }
+
+    // Keeps a stalled preview from stalling the recording
+    let queue = gst::ElementFactory::make("queue")
+        .name(names::PREVIEW_QUEUE)
+        .property_from_str("leaky", "downstream")
+        .property("max-size-buffers", 2u32)
         .build()?;
+    elements.insert(0, queue);
+
+    let elements: Vec<&gst::Element> = elements.iter().collect();
+    pipeline.add_many(&elements)?;
+    src_pad.link(&find_sink_pad(elements[0])?)?;
+    gst::Element::link_many(&elements)?;
+
+    Ok(())
+}
 
-    let elements = &[
-        &overlay_convert1,
-        &overlay_display,
-        &overlay_convert2,
-        &sink_display,
-    ];
-    pipeline.add_many(elements)?;
-    src_pad.link(&find_sink_pad(&overlay_convert1)?)?;
-    gst::Element::link_many(elements)?;
+/// Creates the elements that scale the preview down to its configured width, and limit
+/// its frame rate to `max_fps` if provided.
+fn create_preview_scale_elements(
+    preview_config: &PreviewConfig,
+    max_fps: Option<i32>,
+) -> Result<Vec<gst::Element>> {
+    let mut elements = vec![gst::ElementFactory::make("videoscale")
+        .name("display.preview.scale")
+        .build()?];
+    if let Some(max_fps) = max_fps {
+        elements.push(
+            gst::ElementFactory::make("videorate")
+                .name("display.preview.rate")
+                .property("max-rate", max_fps)
+                .build()?,
+        );
+    }
+    // The height is left to videoscale, which preserves the display aspect ratio
+    let caps = gst_video::VideoCapsBuilder::new()
+        .width(preview_config.preview_width)
+        .pixel_aspect_ratio(gst::Fraction::new(1, 1))
+        .build();
+    elements.push(
+        gst::ElementFactory::make("capsfilter")
+            .name("display.preview.caps")
+            .property("caps", caps)
+            .build()?,
+    );
+    Ok(elements)
+}
 
+/// Delays the window sink's presentation by an inference frame, so that detections
+/// drawn by the overlay line up with the video.
+fn offset_window_sink(sink: &gst::Element, config: &Config) {
     let duration = config.detection.inference_frame_duration();
-    sink_display.connect("element-added", true, move |args| {
+    sink.connect("element-added", true, move |args| {
         let (sink, child) = if let [sink, child, ..] = args {
             (
                 sink.get::<gst::Element>().unwrap(),
@@ -197,8 +385,6 @@ fn create_display_stream_debug_branch(
 
         None
     });
-
-    Ok(())
 }
 
 fn create_infer_stream_pipeline(
@@ -208,7 +394,9 @@ fn create_infer_stream_pipeline(
     config: &Config,
 ) -> Result<()> {
     let mut elements = vec![];
-    let infer_rate = gst::ElementFactory::make("videorate").build()?;
+    let infer_rate = gst::ElementFactory::make("videorate")
+        .name(names::INFER_RATE)
+        .build()?;
     elements.push(&infer_rate);
 
     let video_caps = {
@@ -223,10 +411,13 @@ fn create_infer_stream_pipeline(
         caps.build()
     };
 
-    let infer_convert = gst::ElementFactory::make("videoconvert").build()?;
+    let infer_convert = gst::ElementFactory::make("videoconvert")
+        .name("infer.convert")
+        .build()?;
     elements.push(&infer_convert);
 
     let infer_caps = gst::ElementFactory::make("capsfilter")
+        .name("infer.caps")
         .property("caps", video_caps)
         .build()?;
     elements.push(&infer_caps);
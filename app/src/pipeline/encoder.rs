@@ -0,0 +1,188 @@
+//! Creates the encoder that compresses the display stream for recording, and maps the
+//! encoder configuration onto the properties of whichever element is chosen.
+use anyhow::{anyhow, Result};
+use gst::prelude::*;
+
+use super::{names, CREATE_CAT as CAT};
+use crate::config::{EncoderConfig, VideoEncoder};
+use crate::logging::*;
+
+/// The encoders tried by `VideoEncoder::Auto`, in order of preference. GStreamer only
+/// registers hardware encoders when their hardware is present.
+const AUTO_ENCODERS: [VideoEncoder; 5] = [
+    VideoEncoder::V4l2,
+    VideoEncoder::Vaapi,
+    VideoEncoder::Nvenc,
+    VideoEncoder::X264,
+    VideoEncoder::Openh264,
+];
+
+/// Creates the persistence encoder selected by `config`, with its bitrate and GOP size
+/// applied. The profile and level are negotiated through the caps returned by
+/// `encoder_output_caps`.
+pub(super) fn create_encoder(config: &EncoderConfig) -> Result<gst::Element> {
+    let kind = match config.encoder {
+        VideoEncoder::Auto => probe_encoder()?,
+        kind => kind,
+    };
+    let factory_name = kind.factory_name().unwrap();
+    info!(CAT, "Creating {} encoder", factory_name);
+
+    let encoder = gst::ElementFactory::make(factory_name)
+        .name(names::PERSISTENCE_ENCODER)
+        .build()?;
+    let gop_size = config.encoder_gop_size;
+    match kind {
+        VideoEncoder::V4l2 => {
+            encoder.set_property(
+                "extra-controls",
+                gst::Structure::builder("controls")
+                    .field("h264_i_frame_period", gop_size as i32)
+                    .build(),
+            );
+        }
+        VideoEncoder::X264 => {
+            encoder.set_property("key-int-max", gop_size);
+            // Keeps up with live video on modest CPUs
+            encoder.set_property_from_str("speed-preset", "veryfast");
+            encoder.set_property_from_str("tune", "zerolatency");
+        }
+        VideoEncoder::Openh264 => {
+            encoder.set_property("gop-size", gop_size);
+        }
+        VideoEncoder::Vaapi => {
+            encoder.set_property("keyframe-period", gop_size);
+        }
+        VideoEncoder::Nvenc => {
+            encoder.set_property_from_str("preset", "low-latency-hq");
+            encoder.set_property("gop-size", gop_size as i32);
+        }
+        VideoEncoder::Auto => unreachable!(),
+    }
+    set_bitrate_kbps(&encoder, config.encoder_bitrate_kbps);
+
+    Ok(encoder)
+}
+
+/// Returns the caps that constrain the encoder's output to the configured profile and
+/// level.
+pub(super) fn encoder_output_caps(config: &EncoderConfig) -> gst::Caps {
+    gst::Caps::builder("video/x-h264")
+        .field("profile", config.encoder_profile.caps_name())
+        .field("level", config.encoder_level.as_str())
+        .build()
+}
+
+fn probe_encoder() -> Result<VideoEncoder> {
+    AUTO_ENCODERS
+        .into_iter()
+        .find(|kind| gst::ElementFactory::find(kind.factory_name().unwrap()).is_some())
+        .ok_or(anyhow!("No H.264 encoder available"))
+}
+
+fn encoder_kind(encoder: &gst::Element) -> Option<VideoEncoder> {
+    let factory_name = encoder.factory()?.name();
+    AUTO_ENCODERS
+        .into_iter()
+        .find(|kind| kind.factory_name() == Some(factory_name.as_str()))
+}
+
+/// Returns the bitrate of a persistence encoder in kbit/s, or `None` if the encoder
+/// isn't one that `create_encoder` makes, or its bitrate can't be changed while it's
+/// running. The V4L2 encoder only reads its controls when it starts.
+pub(crate) fn bitrate_kbps(encoder: &gst::Element) -> Option<u32> {
+    match encoder_kind(encoder)? {
+        VideoEncoder::V4l2 => None,
+        VideoEncoder::Openh264 => Some(encoder.property::<u32>("bitrate") / 1000),
+        _ => Some(encoder.property::<u32>("bitrate")),
+    }
+}
+
+/// Sets the bitrate of a persistence encoder, in kbit/s. The V4L2 encoder only applies
+/// changes when it next starts.
+pub(crate) fn set_bitrate_kbps(encoder: &gst::Element, kbps: u32) {
+    // The encoders that take bit/s are limited to `MAX_ENCODER_BITRATE_KBPS` by the
+    // config, but saturate rather than wrap if they're handed more
+    let bps = kbps.saturating_mul(1000);
+    match encoder_kind(encoder) {
+        Some(VideoEncoder::V4l2) => {
+            let mut controls = encoder
+                .property::<Option<gst::Structure>>("extra-controls")
+                .unwrap_or_else(|| gst::Structure::new_empty("controls"));
+            controls.set("video_bitrate", i32::try_from(bps).unwrap_or(i32::MAX));
+            encoder.set_property("extra-controls", controls);
+        }
+        Some(VideoEncoder::Openh264) => encoder.set_property("bitrate", bps),
+        Some(_) => encoder.set_property("bitrate", kbps),
+        None => warning!(CAT, obj: encoder, "Can't set the bitrate of an unknown encoder"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::H264Profile;
+
+    fn config(encoder: VideoEncoder) -> EncoderConfig {
+        EncoderConfig {
+            encoder,
+            encoder_bitrate_kbps: 4000,
+            encoder_gop_size: 60,
+            encoder_profile: H264Profile::Main,
+            encoder_level: "4.1".into(),
+        }
+    }
+
+    #[test]
+    fn test_factory_names() {
+        assert_eq!(VideoEncoder::Auto.factory_name(), None);
+        assert_eq!(
+            AUTO_ENCODERS.map(|kind| kind.factory_name().unwrap()),
+            [
+                "v4l2h264enc",
+                "vaapih264enc",
+                "nvh264enc",
+                "x264enc",
+                "openh264enc"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_profile_caps_names() {
+        assert_eq!(
+            H264Profile::ConstrainedBaseline.caps_name(),
+            "constrained-baseline"
+        );
+        assert_eq!(H264Profile::Baseline.caps_name(), "baseline");
+        assert_eq!(H264Profile::Main.caps_name(), "main");
+        assert_eq!(H264Profile::High.caps_name(), "high");
+    }
+
+    #[test]
+    fn test_encoder_output_caps() {
+        gst::init().unwrap();
+        let caps = encoder_output_caps(&config(VideoEncoder::Auto));
+        let structure = caps.structure(0).unwrap();
+
+        assert_eq!(structure.name(), "video/x-h264");
+        assert_eq!(structure.get::<&str>("profile").unwrap(), "main");
+        assert_eq!(structure.get::<&str>("level").unwrap(), "4.1");
+    }
+
+    #[test]
+    fn test_bitrate_round_trip() {
+        gst::init().unwrap();
+        // x264 takes kbit/s, and OpenH264 bit/s
+        for kind in [VideoEncoder::X264, VideoEncoder::Openh264] {
+            if gst::ElementFactory::find(kind.factory_name().unwrap()).is_none() {
+                continue;
+            }
+            let encoder = create_encoder(&config(kind)).unwrap();
+            assert_eq!(bitrate_kbps(&encoder), Some(4000), "{:?}", kind);
+
+            set_bitrate_kbps(&encoder, 1500);
+            assert_eq!(bitrate_kbps(&encoder), Some(1500), "{:?}", kind);
+        }
+    }
+}
@@ -1,8 +1,10 @@
 mod configure;
 mod create;
-pub(self) mod names;
+pub(crate) mod encoder;
+pub(crate) mod names;
 mod run;
 pub(self) mod source;
+mod watchdog;
 
 pub use configure::*;
 pub use create::*;
@@ -32,3 +34,11 @@ pub(self) static RUN_CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
         Some("Auto-Arena Run"),
     )
 });
+
+pub(self) static WATCHDOG_CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "AA_WATCHDOG",
+        gst::DebugColorFlags::FG_YELLOW,
+        Some("Auto-Arena Source Watchdog"),
+    )
+});
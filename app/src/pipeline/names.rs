@@ -1,2 +1,16 @@
+pub const INFER_NAMESPACE: &str = "infer";
+pub const INFER_RATE: &str = "infer.rate";
 pub const DETECTION_SINK: &str = "infer.detection_sink";
+pub const DISPLAY_SPLITTER: &str = "display.splitter";
+pub const PREROLL_QUEUE: &str = "display.persist.preroll";
+pub const RECORDING_SPLITTER: &str = "display.persist.splitter";
+pub const PERSISTENCE_BIN: &str = "display.persist";
+pub const PERSISTENCE_QUEUE: &str = "display.persist.queue";
+pub const PERSISTENCE_VALVE: &str = "display.persist.valve";
+pub const PERSISTENCE_ENCODER_QUEUE: &str = "display.persist.encoder.queue";
+pub const PERSISTENCE_ENCODER: &str = "display.persist.encoder";
 pub const PERSISTENCE_SINK: &str = "display.persist.multifile_sink";
+pub const DEBUG_VIEWPORT: &str = "debug-video.viewport";
+pub const PREVIEW_NAMESPACE: &str = "display.preview";
+pub const PREVIEW_QUEUE: &str = "display.preview.queue";
+pub const PREVIEW_SINK: &str = "display.preview.sink";
@@ -1,13 +1,26 @@
-use anyhow::Result;
+use std::time::Duration;
+
+use aa_sys::signal::handle_termination_signals;
+use anyhow::{anyhow, Result};
 use gst::prelude::*;
 
-use super::RUN_CAT as CAT;
+use super::{ConfiguredPipeline, RUN_CAT as CAT};
 use crate::foundation::debug::trace_graph_state_change;
 use crate::logging::*;
-use crate::system::HardwareSystems;
+use crate::recovery::ErrorRecovery;
+
+/// How long the pipeline is given to drain after a termination signal, before the main
+/// loop is stopped regardless.
+const EOS_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub fn run_main_loop(
-    (main_loop, pipeline, _hardware): (glib::MainLoop, gst::Pipeline, HardwareSystems),
+    ConfiguredPipeline {
+        main_loop,
+        pipeline,
+        hardware,
+        sessions,
+        ..
+    }: ConfiguredPipeline,
 ) -> Result<()> {
     info!(CAT, obj: &pipeline, "Starting main loop");
 
@@ -15,6 +28,9 @@ pub fn run_main_loop(
         .bus()
         .expect("Pipeline without bus. Shouldn't happen!");
 
+    let recovery = ErrorRecovery::connect(&pipeline, &main_loop, sessions.clone())?;
+    let recovery_clone = recovery.clone();
+
     let pipeline_element = pipeline.dynamic_cast_ref::<gst::Element>().unwrap();
     pipeline_element.set_state(gst::State::Playing)?;
 
@@ -48,17 +64,7 @@ pub fn run_main_loop(
                 main_loop.quit();
             }
             MessageView::Error(err) => {
-                // TODO(shyndman): Figure out how to judge the severity of the error
-                error!(
-                    CAT,
-                    "Error from {:?}: {:?} {} ({:?})",
-                    err.src().map(|s| s.path_string()),
-                    err.error().domain(),
-                    err.error(),
-                    err.debug()
-                );
-
-                let _ = pipeline.set_state(gst::State::Ready);
+                recovery_clone.handle_error(err);
             }
             MessageView::StateChanged(event_details) => {
                 trace_graph_state_change(&pipeline, &event_details);
@@ -73,9 +79,55 @@ pub fn run_main_loop(
         }
     });
 
+    register_shutdown_handler(&main_loop, &pipeline)?;
+
     bus.add_signal_watch();
     main_loop.run();
     pipeline.set_state(gst::State::Null)?;
+    sessions.finish();
 
+    info!(CAT, "Parking pantilt system");
+    hardware.pantilt.park()?;
+
+    if recovery.gave_up() {
+        return Err(anyhow!("The pipeline failed, and couldn't be recovered"));
+    }
     Ok(())
 }
+
+/// Stops the pipeline when the process is asked to terminate.
+///
+/// An EOS is sent through the pipeline so that the muxer can finalize the chunk it's
+/// writing. The main loop quits once the EOS reaches the bus, or after `EOS_TIMEOUT`
+/// if the pipeline fails to drain.
+fn register_shutdown_handler(
+    main_loop: &glib::MainLoop,
+    pipeline: &gst::Pipeline,
+) -> Result<()> {
+    let main_loop = main_loop.clone();
+    let pipeline_weak = pipeline.downgrade();
+    handle_termination_signals(move || {
+        let pipeline = match pipeline_weak.upgrade() {
+            Some(pipeline) => pipeline,
+            None => {
+                main_loop.quit();
+                return;
+            }
+        };
+
+        info!(CAT, obj: &pipeline, "Sending EOS to finalize recordings");
+        if !pipeline.send_event(gst::event::Eos::new()) {
+            warning!(CAT, obj: &pipeline, "Pipeline did not accept EOS, stopping now");
+            main_loop.quit();
+            return;
+        }
+
+        glib::timeout_add_once(EOS_TIMEOUT, move || {
+            warning!(
+                CAT,
+                "Timed out waiting for the pipeline to drain, stopping now"
+            );
+            main_loop.quit();
+        });
+    })
+}
@@ -1,7 +1,9 @@
 use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use aa_foundation::path::to_canonicalized_path_string;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use glib::value::FromValue;
 use glib::{EnumClass, Type};
 use gst::prelude::*;
@@ -12,19 +14,28 @@ use gst_video::VideoCapsBuilder;
 use crate::config::Config;
 use crate::foundation::gst::find_src_pad;
 use crate::logging::*;
-use crate::pipeline::CREATE_CAT as CAT;
+use crate::pipeline::{names, CREATE_CAT as CAT};
 
 pub struct SourcePads {
     pub display_stream_src_pad: gst::Pad,
     pub infer_stream_src_pad: gst::Pad,
+    /// The elements added to the pipeline to produce the streams
+    pub elements: Vec<gst::Element>,
 }
 
 pub fn create_media_sources(config: &Config, pipeline: &gst::Pipeline) -> Result<SourcePads> {
-    if let Some(ref video_path) = config.source.debug_source_video_path {
+    let existing = pipeline.children();
+    let mut pads = if let Some(ref video_path) = config.source.debug_source_video_path {
         setup_debug_video_sources(PathBuf::from(video_path), config, pipeline)
     } else {
         setup_camera_sources(config, pipeline)
-    }
+    }?;
+    pads.elements = pipeline
+        .children()
+        .into_iter()
+        .filter(|element| !existing.contains(element))
+        .collect();
+    Ok(pads)
 }
 
 fn setup_camera_sources(config: &Config, pipeline: &gst::Pipeline) -> Result<SourcePads> {
@@ -38,7 +49,9 @@ fn setup_camera_sources(config: &Config, pipeline: &gst::Pipeline) -> Result<Sou
 fn build_libcamera_streams(pipeline: &gst::Pipeline) -> Result<SourcePads> {
     info!(CAT, "Creating libcamera source");
 
-    let camera = gst::ElementFactory::make("libcamerasrc").build()?;
+    let camera = gst::ElementFactory::make("libcamerasrc")
+        .name("camera.src")
+        .build()?;
     pipeline.add(&camera)?;
     // This MUST follow the ElementFactory line, to ensure that the plugin is
     // loaded
@@ -63,6 +76,7 @@ fn build_libcamera_streams(pipeline: &gst::Pipeline) -> Result<SourcePads> {
     Ok(SourcePads {
         display_stream_src_pad: display_pad,
         infer_stream_src_pad: inference_pad,
+        elements: vec![],
     })
 }
 
@@ -139,6 +153,7 @@ fn synthesize_libcamera_streams(
     Ok(SourcePads {
         display_stream_src_pad: find_src_pad(&queue_display)?,
         infer_stream_src_pad: find_src_pad(&caps_filter_infer)?,
+        elements: vec![],
     })
 }
 
@@ -179,7 +194,23 @@ fn setup_debug_video_sources(
         .build()?;
 
     pipeline.add_many(&[&decodebin, &convert1, &scale, &rate, &caps, &splitter])?;
-    gst::Element::link_many(&[&convert1, &scale, &rate, &caps, &splitter])?;
+
+    // If requested, crop the video down to a viewport, which will be moved around by
+    // the simulated pan/tilt motors during configuration
+    if config.source.debug_viewport_fraction.is_some() {
+        let viewport = gst::ElementFactory::make("videocrop")
+            .name(names::DEBUG_VIEWPORT)
+            .build()?;
+        pipeline.add(&viewport)?;
+        gst::Element::link_many(&[&convert1, &viewport, &scale])?;
+    } else {
+        convert1.link(&scale)?;
+    }
+    gst::Element::link_many(&[&scale, &rate, &caps, &splitter])?;
+
+    if let Some(secs) = config.source.debug_simulate_stall_secs {
+        simulate_stall(&splitter, Duration::from_secs_f64(secs))?;
+    }
 
     // Build pads
     let pad_template = splitter
@@ -284,5 +315,33 @@ fn setup_debug_video_sources(
     Ok(SourcePads {
         display_stream_src_pad: find_src_pad(&queue_display)?,
         infer_stream_src_pad: find_src_pad(&caps_filter_infer)?,
+        elements: vec![],
     })
 }
+
+/// Stops frames from reaching `element` once `after` has passed since its first frame,
+/// as a stalled camera would.
+fn simulate_stall(element: &gst::Element, after: Duration) -> Result<()> {
+    let sink_pad = element
+        .static_pad("sink")
+        .ok_or(anyhow!("Element has no sink pad"))?;
+    let first_frame: Mutex<Option<Instant>> = Mutex::new(None);
+    sink_pad.add_probe(
+        gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST,
+        move |_, _| {
+            let now = Instant::now();
+            let started = *first_frame.lock().unwrap().get_or_insert(now);
+            if now.duration_since(started) < after {
+                gst::PadProbeReturn::Ok
+            } else {
+                gst::PadProbeReturn::Drop
+            }
+        },
+    );
+    info!(
+        CAT,
+        "Simulating a source stall after {}s",
+        after.as_secs_f64()
+    );
+    Ok(())
+}
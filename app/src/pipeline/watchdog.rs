@@ -0,0 +1,385 @@
+//! Watches the media sources for stalls, and rebuilds them when they stop producing
+//! frames. libcamera occasionally stops producing buffers without posting an error, so
+//! the pipeline's error recovery never hears about it.
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use gst::prelude::*;
+
+use super::source::{create_media_sources, SourcePads};
+use super::WATCHDOG_CAT as CAT;
+use crate::config::Config;
+use crate::logging::*;
+use crate::message::AAMessage;
+
+/// How often the sources' frame flow is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Rebuilds the media sources whenever one of their streams goes the configured stall
+/// timeout without producing a frame, and posts `SourceRestarted` once it has.
+pub(super) fn connect_source_watchdog(
+    config: &Config,
+    pipeline: &gst::Pipeline,
+    sources: SourcePads,
+) -> Result<()> {
+    let timeout = match config.source.source_stall_timeout() {
+        Some(timeout) => timeout,
+        None => {
+            info!(CAT, "Source watchdog disabled");
+            return Ok(());
+        }
+    };
+    let bus = pipeline
+        .bus()
+        .ok_or(anyhow!("Pipeline without bus. Shouldn't happen!"))?;
+
+    let detector = Arc::new(Mutex::new(StallDetector::new(timeout, Instant::now())));
+    let mut watched = WatchedSources::watch(sources, &detector)?;
+    let config = config.clone();
+    let pipeline_weak = pipeline.downgrade();
+
+    info!(CAT, "Watching sources, timeout={}s", timeout.as_secs());
+    glib::timeout_add(CHECK_INTERVAL, move || {
+        let pipeline = match pipeline_weak.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return glib::Continue(false),
+        };
+        let playing = pipeline.current_state() == gst::State::Playing;
+        let stalled_for = match detector.lock().unwrap().check(Instant::now(), playing) {
+            Some(stalled_for) => stalled_for,
+            None => return glib::Continue(true),
+        };
+
+        warning!(
+            CAT,
+            "No frames from the source for {:.1}s, rebuilding it",
+            stalled_for.as_secs_f64()
+        );
+        match watched.rebuild(&config, &pipeline, &detector) {
+            Ok(()) => {
+                info!(CAT, "Rebuilt the source");
+                match (AAMessage::SourceRestarted { stalled_for }).to_gst_message() {
+                    Ok(msg) => {
+                        if let Err(err) = bus.post(msg) {
+                            error!(CAT, "Failed to post source message, {}", err);
+                        }
+                    }
+                    Err(err) => error!(CAT, "Failed to encode source message, {}", err),
+                }
+            }
+            Err(err) => {
+                // Posted by the pipeline itself, so the error recovery restarts the whole
+                // pipeline. The rebuild is retried if that doesn't bring the frames back.
+                error!(CAT, "Failed to rebuild the source, {}", err);
+                gst::element_error!(
+                    pipeline,
+                    gst::ResourceError::Failed,
+                    ("Failed to rebuild the stalled source"),
+                    ["{}", err]
+                );
+            }
+        }
+        detector.lock().unwrap().reset(Instant::now());
+
+        glib::Continue(true)
+    });
+
+    Ok(())
+}
+
+/// The media sources, along with the probes reporting their frames to the detector
+struct WatchedSources {
+    /// The pads downstream of the sources, which outlive them
+    sink_pads: [gst::Pad; 2],
+    /// The current sources, or `None` if the last rebuild failed before creating them
+    sources: Option<SourcePads>,
+    probes: Vec<(gst::Pad, gst::PadProbeId)>,
+}
+
+impl WatchedSources {
+    fn watch(sources: SourcePads, detector: &Arc<Mutex<StallDetector>>) -> Result<Self> {
+        let peer = |src_pad: &gst::Pad| {
+            src_pad
+                .peer()
+                .ok_or(anyhow!("Source pad {} isn't linked", src_pad.name()))
+        };
+        let mut watched = Self {
+            sink_pads: [
+                peer(&sources.display_stream_src_pad)?,
+                peer(&sources.infer_stream_src_pad)?,
+            ],
+            sources: None,
+            probes: vec![],
+        };
+        watched.add_probes(sources, detector);
+        Ok(watched)
+    }
+
+    /// Reports the frames of `sources` to the detector, in place of the old sources'.
+    fn add_probes(&mut self, sources: SourcePads, detector: &Arc<Mutex<StallDetector>>) {
+        self.probes = [
+            &sources.display_stream_src_pad,
+            &sources.infer_stream_src_pad,
+        ]
+        .into_iter()
+        .enumerate()
+        .filter_map(|(stream, pad)| {
+            let detector = detector.clone();
+            pad.add_probe(
+                gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST,
+                move |_, _| {
+                    detector.lock().unwrap().frame(stream, Instant::now());
+                    gst::PadProbeReturn::Ok
+                },
+            )
+            .map(|probe| (pad.clone(), probe))
+        })
+        .collect();
+        self.sources = Some(sources);
+    }
+
+    /// Tears down the sources, and replaces them with new ones linked to the same pads.
+    /// On failure, whatever is left of the old sources is kept, so that calling this
+    /// again picks up where it failed.
+    fn rebuild(
+        &mut self,
+        config: &Config,
+        pipeline: &gst::Pipeline,
+        detector: &Arc<Mutex<StallDetector>>,
+    ) -> Result<()> {
+        if let Some(ref sources) = self.sources {
+            for (src_pad, sink_pad) in [
+                &sources.display_stream_src_pad,
+                &sources.infer_stream_src_pad,
+            ]
+            .into_iter()
+            .zip(&self.sink_pads)
+            {
+                if src_pad.peer().as_ref() == Some(sink_pad) {
+                    src_pad.unlink(sink_pad)?;
+                }
+            }
+            for element in &sources.elements {
+                element.set_state(gst::State::Null)?;
+                if element.parent().is_some() {
+                    pipeline.remove(element)?;
+                }
+            }
+            for (pad, probe) in self.probes.drain(..) {
+                pad.remove_probe(probe);
+            }
+            self.sources = None;
+        }
+
+        let existing = pipeline.children();
+        let sources = match create_media_sources(config, pipeline) {
+            Ok(sources) => sources,
+            Err(err) => {
+                // Whatever was added would clash with the next attempt's elements
+                for element in pipeline.children() {
+                    if !existing.contains(&element) {
+                        let _ = element.set_state(gst::State::Null);
+                        let _ = pipeline.remove(&element);
+                    }
+                }
+                return Err(err);
+            }
+        };
+        let links = [
+            sources.display_stream_src_pad.link(&self.sink_pads[0]),
+            sources.infer_stream_src_pad.link(&self.sink_pads[1]),
+        ];
+        // Watched before checking the links, so that a failed rebuild's sources are torn
+        // down by the next one
+        self.add_probes(sources, detector);
+        for link in links {
+            link?;
+        }
+        for element in &self.sources.as_ref().unwrap().elements {
+            element.sync_state_with_parent()?;
+        }
+        let _ = pipeline.recalculate_latency();
+
+        Ok(())
+    }
+}
+
+/// Tracks when each of a source's streams last produced a frame, and decides when the
+/// source has stalled
+#[derive(Debug)]
+struct StallDetector {
+    timeout: Duration,
+    /// When each stream last produced a frame, or when detection last (re)started
+    last_frames: [Instant; 2],
+}
+
+impl StallDetector {
+    fn new(timeout: Duration, now: Instant) -> Self {
+        Self {
+            timeout,
+            last_frames: [now; 2],
+        }
+    }
+
+    fn frame(&mut self, stream: usize, now: Instant) {
+        self.last_frames[stream] = now;
+    }
+
+    fn reset(&mut self, now: Instant) {
+        self.last_frames = [now; 2];
+    }
+
+    /// Returns how long the source has stalled for, if any of its streams has gone the
+    /// timeout without a frame. Time spent outside of the playing state isn't counted.
+    fn check(&mut self, now: Instant, playing: bool) -> Option<Duration> {
+        if !playing {
+            self.reset(now);
+            return None;
+        }
+
+        let stalled_for = self
+            .last_frames
+            .iter()
+            .map(|last| now.saturating_duration_since(*last))
+            .max()
+            .unwrap();
+        (stalled_for >= self.timeout).then_some(stalled_for)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::foundation::testing::{config_from_args, dispatch_until};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Encodes a ten second test video to `path`.
+    fn write_test_video(path: &Path) {
+        let pipeline = gst::parse_launch(&format!(
+            "videotestsrc num-buffers=300 ! video/x-raw,width=320,height=240,framerate=30/1 \
+             ! jpegenc ! avimux ! filesink location={}",
+            path.display()
+        ))
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+        let msg = pipeline
+            .bus()
+            .unwrap()
+            .timed_pop_filtered(
+                gst::ClockTime::from_seconds(30),
+                &[gst::MessageType::Eos, gst::MessageType::Error],
+            )
+            .unwrap();
+        assert_eq!(msg.type_(), gst::MessageType::Eos);
+        pipeline.set_state(gst::State::Null).unwrap();
+    }
+
+    #[test]
+    fn test_detects_stalled_stream() {
+        let start = Instant::now();
+        let mut detector = StallDetector::new(TIMEOUT, start);
+
+        detector.frame(0, start + Duration::from_secs(8));
+        detector.frame(1, start + Duration::from_secs(8));
+        assert_eq!(detector.check(start + Duration::from_secs(12), true), None);
+
+        // Only the display stream keeps flowing
+        detector.frame(0, start + Duration::from_secs(17));
+        assert_eq!(
+            detector.check(start + Duration::from_secs(18), true),
+            Some(TIMEOUT)
+        );
+    }
+
+    #[test]
+    fn test_ignores_time_outside_playing() {
+        let start = Instant::now();
+        let mut detector = StallDetector::new(TIMEOUT, start);
+
+        assert_eq!(detector.check(start + Duration::from_secs(30), false), None);
+        assert_eq!(detector.check(start + Duration::from_secs(35), true), None);
+        assert!(detector
+            .check(start + Duration::from_secs(40), true)
+            .is_some());
+    }
+
+    #[test]
+    fn test_rebuilds_stalled_debug_source() {
+        gst::init().unwrap();
+        let dir = std::env::temp_dir().join(format!(
+            "aa-watchdog-{}-{}",
+            std::process::id(),
+            "rebuilds_stalled_debug_source"
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let video_path = dir.join("stall.avi");
+        write_test_video(&video_path);
+
+        let config = config_from_args(&[
+            "--debug-use-color-detection",
+            &format!("--debug-source-video-path={}", video_path.display()),
+            "--debug-simulate-stall-secs=0.5",
+            "--source-stall-timeout-secs=1",
+        ]);
+
+        // Feed both streams to sinks, counting the frames that reach the display's
+        let pipeline = gst::Pipeline::new(None);
+        let sources = create_media_sources(&config, &pipeline).unwrap();
+        let display_frames = Arc::new(AtomicUsize::new(0));
+        for (src_pad, name) in [
+            (&sources.display_stream_src_pad, "test.display.sink"),
+            (&sources.infer_stream_src_pad, "test.infer.sink"),
+        ] {
+            let sink = gst::ElementFactory::make("fakesink")
+                .name(name)
+                .property("sync", true)
+                .build()
+                .unwrap();
+            pipeline.add(&sink).unwrap();
+            src_pad.link(&sink.static_pad("sink").unwrap()).unwrap();
+        }
+        let frames = display_frames.clone();
+        pipeline
+            .by_name("test.display.sink")
+            .and_then(|sink| sink.static_pad("sink"))
+            .unwrap()
+            .add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+                frames.fetch_add(1, Ordering::SeqCst);
+                gst::PadProbeReturn::Ok
+            });
+        connect_source_watchdog(&config, &pipeline, sources).unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+
+        // Each rebuilt source stalls too, so the watchdog keeps rebuilding it. Frames
+        // reaching the display in between show that the rebuilt source was linked.
+        let bus = pipeline.bus().unwrap();
+        let mut restarts = vec![];
+        let rebuilt_twice = dispatch_until(Duration::from_secs(20), || {
+            while let Some(msg) = bus.pop() {
+                if let gst::MessageView::Error(err) = msg.view() {
+                    panic!("Pipeline error: {}", err.error());
+                }
+                if let Ok(AAMessage::SourceRestarted { stalled_for }) =
+                    AAMessage::from_gst_message(&msg)
+                {
+                    assert!(stalled_for >= Duration::from_secs(1));
+                    restarts.push(display_frames.load(Ordering::SeqCst));
+                }
+            }
+            restarts.len() >= 2
+        });
+        pipeline.set_state(gst::State::Null).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(rebuilt_twice, "Restarts: {:?}", restarts);
+        assert!(restarts[0] > 0);
+        assert!(restarts[1] > restarts[0], "Restarts: {:?}", restarts);
+    }
+}
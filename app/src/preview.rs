@@ -0,0 +1,77 @@
+//! Makes the `mjpeg` preview's frames available outside of the pipeline, so that they
+//! can be served over the network.
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use gst::prelude::*;
+use once_cell::sync::Lazy;
+
+use crate::logging::*;
+use crate::pipeline::names;
+
+pub(self) static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "AA_PREVIEW",
+        gst::DebugColorFlags::FG_CYAN,
+        Some("Auto-Arena Preview"),
+    )
+});
+
+/// A JPEG encoded preview frame
+#[derive(Clone, Debug)]
+pub struct PreviewFrame {
+    /// Increases by one with each frame, so that viewers can tell when a new frame has
+    /// arrived
+    pub sequence: u64,
+    pub jpeg: Arc<Vec<u8>>,
+}
+
+/// Holds the most recent preview frame. Clones share the same frame.
+#[derive(Clone, Default)]
+pub struct PreviewFrames {
+    latest: Arc<Mutex<Option<PreviewFrame>>>,
+}
+
+impl PreviewFrames {
+    pub fn latest(&self) -> Option<PreviewFrame> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    /// Replaces the most recent frame with `jpeg`.
+    pub fn publish(&self, jpeg: Vec<u8>) {
+        let mut latest = self.latest.lock().unwrap();
+        let sequence = latest.as_ref().map_or(1, |frame| frame.sequence + 1);
+        *latest = Some(PreviewFrame {
+            sequence,
+            jpeg: Arc::new(jpeg),
+        });
+    }
+}
+
+/// Publishes the frames reaching the pipeline's preview sink, which must be an appsink
+/// producing JPEGs.
+pub fn connect_preview_frames(pipeline: &gst::Pipeline) -> Result<PreviewFrames> {
+    info!(CAT, "Connecting preview frames");
+
+    let sink = pipeline
+        .by_name(names::PREVIEW_SINK)
+        .ok_or(anyhow!("Preview sink not found"))?
+        .dynamic_cast::<gst_app::AppSink>()
+        .map_err(|_| anyhow!("Preview sink is not an appsink"))?;
+
+    let frames = PreviewFrames::default();
+    let frames_clone = frames.clone();
+    sink.set_callbacks(
+        gst_app::AppSinkCallbacks::builder()
+            .new_sample(move |sink| {
+                let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+                let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                frames_clone.publish(map.as_slice().to_vec());
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+
+    Ok(frames)
+}
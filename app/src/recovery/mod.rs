@@ -0,0 +1,589 @@
+//! Recovers the pipeline from errors, by restarting the branch that failed while the
+//! others keep running, or restarting the whole pipeline when the failure is upstream
+//! of every branch.
+mod policy;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use gst::prelude::*;
+use once_cell::sync::Lazy;
+pub use policy::*;
+
+use crate::logging::*;
+use crate::pipeline::names;
+use crate::session::SessionManager;
+
+pub(self) static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "AA_RECOVERY",
+        gst::DebugColorFlags::FG_RED,
+        Some("Auto-Arena Recovery"),
+    )
+});
+
+/// The recording sessions, as managed during recovery
+pub trait RecoverableSessions: Send + Sync {
+    /// Removes every session without finalizing it. Returns `true` if a session was
+    /// recording.
+    fn abandon(&self) -> bool;
+
+    /// Starts a new recording session, and returns its ID.
+    fn start(&self) -> Result<String>;
+}
+
+impl RecoverableSessions for SessionManager {
+    fn abandon(&self) -> bool {
+        SessionManager::abandon(self)
+    }
+
+    fn start(&self) -> Result<String> {
+        SessionManager::start(self)
+    }
+}
+
+/// Responds to the pipeline's errors, as decided by a `RecoveryPolicy`.
+///
+/// A failed branch is isolated as soon as its error is posted, by dropping the buffers
+/// bound for it. Otherwise its error would travel upstream through the splitters, and
+/// stop every other branch with it.
+pub struct ErrorRecovery {
+    policy: Mutex<RecoveryPolicy>,
+    pipeline: glib::WeakRef<gst::Pipeline>,
+    main_loop: glib::MainLoop,
+    sessions: Arc<dyn RecoverableSessions>,
+    /// The pads feeding isolated branches, along with the probes dropping their buffers
+    isolated: Mutex<HashMap<Branch, (gst::Pad, gst::PadProbeId)>>,
+    gave_up: AtomicBool,
+}
+
+impl ErrorRecovery {
+    /// Creates the error recovery, and connects it to the pipeline's bus so that failed
+    /// branches are isolated immediately. The errors themselves are handled by
+    /// `handle_error`, on the main loop.
+    pub fn connect(
+        pipeline: &gst::Pipeline,
+        main_loop: &glib::MainLoop,
+        sessions: Arc<dyn RecoverableSessions>,
+    ) -> Result<Arc<Self>> {
+        info!(CAT, "Connecting error recovery");
+        let bus = pipeline
+            .bus()
+            .ok_or(anyhow!("Pipeline without bus. Shouldn't happen!"))?;
+
+        let recovery = Arc::new(Self {
+            policy: Mutex::new(RecoveryPolicy::default()),
+            pipeline: pipeline.downgrade(),
+            main_loop: main_loop.clone(),
+            sessions,
+            isolated: Mutex::new(HashMap::new()),
+            gave_up: AtomicBool::new(false),
+        });
+
+        // Called from the thread posting the error, before the failure can spread
+        let weak_recovery = Arc::downgrade(&recovery);
+        bus.enable_sync_message_emission();
+        bus.connect_sync_message(Some("error"), move |_, msg| {
+            let recovery = match weak_recovery.upgrade() {
+                Some(recovery) => recovery,
+                None => return,
+            };
+            if let gst::MessageView::Error(err) = msg.view() {
+                if let Some((element, branch)) = recovery.locate(err.src()) {
+                    if branch != Branch::Source {
+                        recovery.isolate(branch, &element);
+                    }
+                }
+            }
+        });
+
+        Ok(recovery)
+    }
+
+    /// `true` if an error couldn't be recovered from, and the main loop was stopped
+    pub fn gave_up(&self) -> bool {
+        self.gave_up.load(Ordering::SeqCst)
+    }
+
+    pub fn handle_error(self: &Arc<Self>, err: &gst::message::Error) {
+        let located = self.locate(err.src());
+        let branch = located
+            .as_ref()
+            .map_or(Branch::Source, |(_, branch)| *branch);
+        let kind = error_kind(&err.error());
+        error!(
+            CAT,
+            "Error in {:?} branch from {:?}: {:?} {:?} {} ({:?})",
+            branch,
+            err.src().map(|s| s.path_string()),
+            kind,
+            err.error().domain(),
+            err.error(),
+            err.debug()
+        );
+
+        let action = match self
+            .policy
+            .lock()
+            .unwrap()
+            .decide(branch, kind, Instant::now())
+        {
+            Some(action) => action,
+            None => {
+                debug!(
+                    CAT,
+                    "Already recovering from the {:?} branch's failure", branch
+                );
+                return;
+            }
+        };
+
+        let weak_self = Arc::downgrade(self);
+        match action {
+            RecoveryAction::RestartBranch(branch, delay) => {
+                warning!(
+                    CAT,
+                    "Restarting {:?} branch in {}s",
+                    branch,
+                    delay.as_secs_f32()
+                );
+                glib::timeout_add_once(delay, move || {
+                    if let Some(recovery) = weak_self.upgrade() {
+                        recovery.restart_branch(branch);
+                    }
+                });
+            }
+            RecoveryAction::AbandonBranch(branch) => {
+                error!(
+                    CAT,
+                    "Abandoning {:?} branch, which can't recover. The rest of the pipeline \
+                     will keep running.",
+                    branch
+                );
+                if branch == Branch::Session {
+                    self.sessions.abandon();
+                    self.isolated.lock().unwrap().remove(&branch);
+                }
+            }
+            RecoveryAction::RestartPipeline(delay) => {
+                warning!(CAT, "Restarting pipeline in {}s", delay.as_secs_f32());
+                glib::timeout_add_once(delay, move || {
+                    if let Some(recovery) = weak_self.upgrade() {
+                        recovery.restart_pipeline();
+                    }
+                });
+            }
+            RecoveryAction::Quit => {
+                error!(CAT, "Unable to recover from pipeline errors, stopping");
+                self.gave_up.store(true, Ordering::SeqCst);
+                self.main_loop.quit();
+            }
+        }
+    }
+
+    /// Returns the pipeline's child containing `src`, along with its branch.
+    fn locate(&self, src: Option<&gst::Object>) -> Option<(gst::Element, Branch)> {
+        let pipeline = self.pipeline.upgrade()?;
+        let pipeline = pipeline.upcast_ref::<gst::Object>();
+
+        let mut current = src?.clone();
+        loop {
+            let parent = current.parent()?;
+            if &parent == pipeline {
+                break;
+            }
+            current = parent;
+        }
+
+        let element = current.downcast::<gst::Element>().ok()?;
+        let branch = Branch::of_top_level_element(&element.name(), element.is::<gst::Bin>());
+        Some((element, branch))
+    }
+
+    /// Drops the buffers bound for `branch`, which contains the pipeline's child
+    /// `element`.
+    fn isolate(&self, branch: Branch, element: &gst::Element) {
+        let mut isolated = self.isolated.lock().unwrap();
+        if isolated.contains_key(&branch) {
+            return;
+        }
+
+        let entry_pad = match self.branch_entry_pad(branch, element) {
+            Some(pad) => pad,
+            None => {
+                warning!(CAT, "Can't find the entry of the {:?} branch", branch);
+                return;
+            }
+        };
+        debug!(
+            CAT,
+            "Isolating {:?} branch at {:?}",
+            branch,
+            entry_pad.name()
+        );
+        if let Some(probe) = entry_pad.add_probe(
+            gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST,
+            |_, _| gst::PadProbeReturn::Drop,
+        ) {
+            isolated.insert(branch, (entry_pad, probe));
+        }
+    }
+
+    /// Returns the upstream pad that feeds `branch`, which contains `element`.
+    fn branch_entry_pad(&self, branch: Branch, element: &gst::Element) -> Option<gst::Pad> {
+        let head = match branch {
+            Branch::Source => return None,
+            Branch::Session => element.clone(),
+            Branch::Recording => self.by_name(names::PERSISTENCE_VALVE)?,
+            Branch::Preview => self.by_name(names::PREVIEW_QUEUE)?,
+            Branch::Inference => self.by_name(names::INFER_RATE)?,
+        };
+        head.static_pad("sink")?.peer()
+    }
+
+    fn by_name(&self, name: &str) -> Option<gst::Element> {
+        self.pipeline.upgrade()?.by_name(name)
+    }
+
+    /// Restarts the elements of `branch`, and reconnects it to the rest of the pipeline.
+    fn restart_branch(&self, branch: Branch) {
+        let pipeline = match self.pipeline.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        info!(CAT, "Restarting {:?} branch", branch);
+
+        if branch == Branch::Session {
+            // Sessions can't resume their files, so a new session replaces the failed one
+            self.isolated.lock().unwrap().remove(&branch);
+            if self.sessions.abandon() {
+                if let Err(err) = self.sessions.start() {
+                    error!(CAT, "Failed to start a replacement session, {}", err);
+                }
+            }
+        } else {
+            let elements: Vec<gst::Element> = pipeline
+                .children()
+                .into_iter()
+                .filter(|e| {
+                    Branch::of_top_level_element(&e.name(), e.is::<gst::Bin>()) == branch
+                })
+                .collect();
+            for element in &elements {
+                let _ = element.set_state(gst::State::Null);
+            }
+            for element in &elements {
+                if let Err(err) = element.sync_state_with_parent() {
+                    warning!(CAT, obj: element, "Failed to restart element, {}", err);
+                }
+            }
+
+            if let Some((pad, probe)) = self.isolated.lock().unwrap().remove(&branch) {
+                // Relinking resends the sticky events (stream start, caps, segment) that
+                // the restarted elements have lost
+                if let Some(peer) = pad.peer() {
+                    let _ = pad.unlink(&peer);
+                    if let Err(err) = pad.link(&peer) {
+                        error!(CAT, "Failed to relink {:?} branch, {}", branch, err);
+                    }
+                }
+                pad.remove_probe(probe);
+            }
+            let _ = pipeline.recalculate_latency();
+        }
+
+        self.policy
+            .lock()
+            .unwrap()
+            .restarted(RecoveryTarget::Branch(branch));
+    }
+
+    fn restart_pipeline(&self) {
+        let pipeline = match self.pipeline.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        info!(CAT, "Restarting pipeline");
+
+        let was_recording = self.sessions.abandon();
+        let _ = pipeline.set_state(gst::State::Null);
+        for (_, (pad, probe)) in self.isolated.lock().unwrap().drain() {
+            pad.remove_probe(probe);
+        }
+        self.policy
+            .lock()
+            .unwrap()
+            .restarted(RecoveryTarget::Pipeline);
+
+        // A failure here is posted to the bus, and handled like any other
+        if let Err(err) = pipeline.set_state(gst::State::Playing) {
+            error!(CAT, "Failed to restart pipeline, {}", err);
+            return;
+        }
+        if was_recording {
+            if let Err(err) = self.sessions.start() {
+                error!(CAT, "Failed to resume recording, {}", err);
+            }
+        }
+    }
+}
+
+/// Classifies `err` by its domain
+fn error_kind(err: &glib::Error) -> ErrorKind {
+    use gst::{CoreError, LibraryError, ResourceError, StreamError};
+
+    if let Some(err) = err.kind::<CoreError>() {
+        match err {
+            CoreError::MissingPlugin | CoreError::NotImplemented => ErrorKind::Unrecoverable,
+            _ => ErrorKind::Other,
+        }
+    } else if let Some(err) = err.kind::<LibraryError>() {
+        match err {
+            LibraryError::Init => ErrorKind::Unrecoverable,
+            _ => ErrorKind::Other,
+        }
+    } else if err.kind::<ResourceError>().is_some() {
+        ErrorKind::Resource
+    } else if let Some(err) = err.kind::<StreamError>() {
+        match err {
+            StreamError::CodecNotFound => ErrorKind::Unrecoverable,
+            _ => ErrorKind::Stream,
+        }
+    } else {
+        ErrorKind::Other
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::foundation::testing::dispatch_until;
+
+    /// Counts the calls made by the error recovery
+    #[derive(Default)]
+    struct FakeSessions {
+        abandoned: AtomicUsize,
+        started: AtomicUsize,
+    }
+
+    impl RecoverableSessions for FakeSessions {
+        fn abandon(&self) -> bool {
+            self.abandoned.fetch_add(1, Ordering::SeqCst);
+            true
+        }
+
+        fn start(&self) -> Result<String> {
+            self.started.fetch_add(1, Ordering::SeqCst);
+            Ok("session".into())
+        }
+    }
+
+    /// A live source split between a preview, inference and a session bin, along with the
+    /// number of buffers that have reached each of their sinks.
+    struct TestPipeline {
+        pipeline: gst::Pipeline,
+        preview_sink: gst::Element,
+        session_sink: gst::Element,
+        preview_buffers: Arc<AtomicUsize>,
+        infer_buffers: Arc<AtomicUsize>,
+    }
+
+    impl TestPipeline {
+        fn new() -> Self {
+            gst::init().unwrap();
+            let pipeline = gst::Pipeline::new(None);
+            let make = |factory: &str, name: &str| {
+                gst::ElementFactory::make(factory)
+                    .name(name)
+                    .build()
+                    .unwrap()
+            };
+
+            let source = gst::ElementFactory::make("videotestsrc")
+                .property("is-live", true)
+                .build()
+                .unwrap();
+            let splitter = make("tee", names::DISPLAY_SPLITTER);
+            let preview_queue = make("queue", names::PREVIEW_QUEUE);
+            let preview_sink = make("fakesink", names::PREVIEW_SINK);
+            let infer_queue = make("queue", names::INFER_RATE);
+            let infer_sink = make("fakesink", names::DETECTION_SINK);
+            pipeline
+                .add_many(&[
+                    &source,
+                    &splitter,
+                    &preview_queue,
+                    &preview_sink,
+                    &infer_queue,
+                    &infer_sink,
+                ])
+                .unwrap();
+            source.link(&splitter).unwrap();
+            gst::Element::link_many(&[&splitter, &preview_queue, &preview_sink]).unwrap();
+            gst::Element::link_many(&[&splitter, &infer_queue, &infer_sink]).unwrap();
+
+            // Sessions' bins are identified by their prefix
+            let session = gst::Bin::new(Some(&format!("{}.test", names::PERSISTENCE_BIN)));
+            let session_sink = gst::ElementFactory::make("fakesink").build().unwrap();
+            session.add(&session_sink).unwrap();
+            let ghost_pad = gst::GhostPad::with_target(
+                Some("sink"),
+                &session_sink.static_pad("sink").unwrap(),
+            )
+            .unwrap();
+            session.add_pad(&ghost_pad).unwrap();
+            pipeline.add(&session).unwrap();
+            splitter.link(&session).unwrap();
+
+            let test_pipeline = Self {
+                preview_buffers: count_buffers(&preview_sink),
+                infer_buffers: count_buffers(&infer_sink),
+                pipeline,
+                preview_sink,
+                session_sink,
+            };
+            test_pipeline
+                .pipeline
+                .set_state(gst::State::Playing)
+                .unwrap();
+            test_pipeline
+        }
+
+        /// Posts a failure of `kind` from `element`, as the element itself would, and
+        /// hands it to `recovery` as the main loop would.
+        fn fail(
+            &self,
+            recovery: &Arc<ErrorRecovery>,
+            element: &gst::Element,
+            kind: ErrorKind,
+        ) {
+            let msg = match kind {
+                ErrorKind::Unrecoverable => gst::message::Error::builder(
+                    gst::CoreError::MissingPlugin,
+                    "Test failure",
+                ),
+                _ => gst::message::Error::builder(gst::StreamError::Failed, "Test failure"),
+            }
+            .src(element)
+            .build();
+            self.pipeline.bus().unwrap().post(msg.clone()).unwrap();
+            if let gst::MessageView::Error(err) = msg.view() {
+                recovery.handle_error(err);
+            }
+        }
+
+        /// Returns `true` if buffers keep reaching `counter`'s sink.
+        fn flowing(counter: &AtomicUsize) -> bool {
+            // Let the buffers already past the splitter drain
+            std::thread::sleep(Duration::from_millis(50));
+            let before = counter.load(Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(200));
+            counter.load(Ordering::SeqCst) > before
+        }
+    }
+
+    impl Drop for TestPipeline {
+        fn drop(&mut self) {
+            let _ = self.pipeline.set_state(gst::State::Null);
+        }
+    }
+
+    fn count_buffers(sink: &gst::Element) -> Arc<AtomicUsize> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let probe_count = count.clone();
+        sink.static_pad("sink")
+            .unwrap()
+            .add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+                probe_count.fetch_add(1, Ordering::SeqCst);
+                gst::PadProbeReturn::Ok
+            });
+        count
+    }
+
+    fn connect(test: &TestPipeline, sessions: &Arc<FakeSessions>) -> Arc<ErrorRecovery> {
+        let main_loop = glib::MainLoop::new(None, false);
+        ErrorRecovery::connect(&test.pipeline, &main_loop, sessions.clone()).unwrap()
+    }
+
+    #[test]
+    fn test_isolates_and_restarts_failed_branch() {
+        let test = TestPipeline::new();
+        let sessions = Arc::new(FakeSessions::default());
+        let recovery = connect(&test, &sessions);
+        assert!(TestPipeline::flowing(&test.preview_buffers));
+
+        test.fail(&recovery, &test.preview_sink, ErrorKind::Stream);
+        assert!(recovery
+            .isolated
+            .lock()
+            .unwrap()
+            .contains_key(&Branch::Preview));
+        assert!(!TestPipeline::flowing(&test.preview_buffers));
+        // The other branches keep running
+        assert!(TestPipeline::flowing(&test.infer_buffers));
+
+        // Restarted after the first backoff delay
+        assert!(dispatch_until(Duration::from_secs(5), || recovery
+            .isolated
+            .lock()
+            .unwrap()
+            .is_empty()));
+        assert!(TestPipeline::flowing(&test.preview_buffers));
+        assert_eq!(sessions.abandoned.load(Ordering::SeqCst), 0);
+        assert!(!recovery.gave_up());
+    }
+
+    #[test]
+    fn test_abandons_unrecoverable_session() {
+        let test = TestPipeline::new();
+        let sessions = Arc::new(FakeSessions::default());
+        let recovery = connect(&test, &sessions);
+
+        test.fail(&recovery, &test.session_sink, ErrorKind::Unrecoverable);
+        assert_eq!(sessions.abandoned.load(Ordering::SeqCst), 1);
+        assert_eq!(sessions.started.load(Ordering::SeqCst), 0);
+        assert!(recovery.isolated.lock().unwrap().is_empty());
+        assert!(TestPipeline::flowing(&test.preview_buffers));
+    }
+
+    #[test]
+    fn test_restarts_pipeline_once_branch_budget_is_spent() {
+        let test = TestPipeline::new();
+        let sessions = Arc::new(FakeSessions::default());
+        let recovery = connect(&test, &sessions);
+
+        // Spend the preview's restarts, without waiting out their backoff
+        {
+            let mut policy = recovery.policy.lock().unwrap();
+            for _ in 0..5 {
+                assert!(matches!(
+                    policy.decide(Branch::Preview, ErrorKind::Stream, Instant::now()),
+                    Some(RecoveryAction::RestartBranch(Branch::Preview, _))
+                ));
+                policy.restarted(RecoveryTarget::Branch(Branch::Preview));
+            }
+        }
+
+        // The failed branch stays isolated until the whole pipeline restarts
+        test.fail(&recovery, &test.preview_sink, ErrorKind::Stream);
+        assert!(!TestPipeline::flowing(&test.preview_buffers));
+        assert!(TestPipeline::flowing(&test.infer_buffers));
+
+        assert!(dispatch_until(Duration::from_secs(5), || sessions
+            .abandoned
+            .load(Ordering::SeqCst) ==
+            1));
+        assert!(recovery.isolated.lock().unwrap().is_empty());
+        // A session was recording, so it's resumed
+        assert_eq!(sessions.started.load(Ordering::SeqCst), 1);
+        assert!(dispatch_until(Duration::from_secs(5), || {
+            TestPipeline::flowing(&test.preview_buffers)
+        }));
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::pipeline::names;
+
+/// The delay before the first restart of a failing part of the pipeline. Each
+/// consecutive failure doubles it, up to `MAX_RESTART_DELAY`.
+const BASE_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+
+/// A part of the pipeline that runs this long without failing has its backoff reset
+const STABLE_PERIOD: Duration = Duration::from_secs(120);
+
+/// A branch that fails this many times in a row has the whole pipeline restarted instead
+const MAX_BRANCH_RESTARTS: u32 = 5;
+
+/// A pipeline that fails this many times in a row is given up on
+const MAX_PIPELINE_RESTARTS: u32 = 5;
+
+/// The parts of the pipeline that can fail and be restarted independently of each other
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Branch {
+    /// The camera (or debug video), and the elements that split its streams. Every other
+    /// branch depends on these.
+    Source,
+    /// The encoder and the elements around it, up to the recording splitter
+    Recording,
+    /// A recording session's persistence bin
+    Session,
+    Preview,
+    Inference,
+}
+
+impl Branch {
+    /// Returns the branch of the pipeline's child named `name`.
+    pub fn of_top_level_element(name: &str, is_bin: bool) -> Self {
+        let in_namespace = |namespace: &str| {
+            name.strip_prefix(namespace)
+                .map_or(false, |rest| rest.starts_with('.'))
+        };
+
+        // Sessions' bins share the recording namespace, but are the only bins in it
+        if in_namespace(names::PERSISTENCE_BIN) {
+            if is_bin {
+                Branch::Session
+            } else {
+                Branch::Recording
+            }
+        } else if in_namespace(names::PREVIEW_NAMESPACE) {
+            Branch::Preview
+        } else if in_namespace(names::INFER_NAMESPACE) {
+            Branch::Inference
+        } else {
+            Branch::Source
+        }
+    }
+}
+
+/// The kinds of errors, according to GStreamer's error domains
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    /// A missing plugin or codec, or a library that failed to initialize. Restarting
+    /// won't help.
+    Unrecoverable,
+    /// A device disconnected, or a file couldn't be read or written
+    Resource,
+    /// A failure to decode, encode or otherwise process the stream
+    Stream,
+    Other,
+}
+
+/// The part of the pipeline restarted to recover from an error
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RecoveryTarget {
+    Branch(Branch),
+    Pipeline,
+}
+
+/// How to respond to an error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecoveryAction {
+    /// Restart the branch after the delay, while the rest of the pipeline keeps running
+    RestartBranch(Branch, Duration),
+    /// Leave the branch stopped, while the rest of the pipeline keeps running
+    AbandonBranch(Branch),
+    /// Restart the whole pipeline after the delay
+    RestartPipeline(Duration),
+    /// Stop the application, so that its supervisor can decide what to do
+    Quit,
+}
+
+/// Counts a target's consecutive failures
+#[derive(Clone, Copy, Debug, Default)]
+struct Backoff {
+    failures: u32,
+    last_failure: Option<Instant>,
+}
+
+impl Backoff {
+    /// Records a failure at `now`, and returns the number of consecutive failures.
+    fn fail(&mut self, now: Instant) -> u32 {
+        if self
+            .last_failure
+            .map_or(false, |last| now.duration_since(last) >= STABLE_PERIOD)
+        {
+            self.failures = 0;
+        }
+        self.failures += 1;
+        self.last_failure = Some(now);
+        self.failures
+    }
+}
+
+/// Returns the delay before restarting a target that has failed `failures` times in a
+/// row.
+fn restart_delay(failures: u32) -> Duration {
+    let exponent = failures.saturating_sub(1).min(16);
+    (BASE_RESTART_DELAY * 2u32.pow(exponent)).min(MAX_RESTART_DELAY)
+}
+
+/// Decides how the pipeline recovers from errors
+#[derive(Default)]
+pub struct RecoveryPolicy {
+    backoffs: HashMap<RecoveryTarget, Backoff>,
+    /// The targets that have failed, and haven't been restarted yet. Their further
+    /// errors are ignored, as a failure tends to produce several.
+    recovering: HashSet<RecoveryTarget>,
+}
+
+impl RecoveryPolicy {
+    /// Returns how to respond to an error of `kind` in `branch`, or `None` if the error
+    /// is already being recovered from.
+    pub fn decide(
+        &mut self,
+        branch: Branch,
+        kind: ErrorKind,
+        now: Instant,
+    ) -> Option<RecoveryAction> {
+        if self.recovering.contains(&RecoveryTarget::Pipeline) ||
+            self.recovering.contains(&RecoveryTarget::Branch(branch))
+        {
+            return None;
+        }
+
+        if branch != Branch::Source {
+            let target = RecoveryTarget::Branch(branch);
+            if kind == ErrorKind::Unrecoverable {
+                self.recovering.insert(target);
+                return Some(RecoveryAction::AbandonBranch(branch));
+            }
+
+            let failures = self.backoffs.entry(target).or_default().fail(now);
+            if failures <= MAX_BRANCH_RESTARTS {
+                self.recovering.insert(target);
+                return Some(RecoveryAction::RestartBranch(
+                    branch,
+                    restart_delay(failures),
+                ));
+            }
+            // Escalate, as the branch may be failing because of something upstream
+            self.backoffs.remove(&target);
+        } else if kind == ErrorKind::Unrecoverable {
+            return Some(RecoveryAction::Quit);
+        }
+
+        let failures = self
+            .backoffs
+            .entry(RecoveryTarget::Pipeline)
+            .or_default()
+            .fail(now);
+        if failures > MAX_PIPELINE_RESTARTS {
+            return Some(RecoveryAction::Quit);
+        }
+        self.recovering.insert(RecoveryTarget::Pipeline);
+        Some(RecoveryAction::RestartPipeline(restart_delay(failures)))
+    }
+
+    /// Records that `target` has been restarted. Restarting the pipeline restarts every
+    /// branch, including any that were abandoned.
+    pub fn restarted(&mut self, target: RecoveryTarget) {
+        match target {
+            RecoveryTarget::Pipeline => self.recovering.clear(),
+            target => {
+                self.recovering.remove(&target);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_branch_of_top_level_element() {
+        assert_eq!(
+            Branch::of_top_level_element(names::PERSISTENCE_ENCODER, false),
+            Branch::Recording
+        );
+        assert_eq!(
+            Branch::of_top_level_element("display.persist.20221107T101500", true),
+            Branch::Session
+        );
+        assert_eq!(
+            Branch::of_top_level_element(names::PREVIEW_SINK, true),
+            Branch::Preview
+        );
+        assert_eq!(
+            Branch::of_top_level_element(names::DETECTION_SINK, false),
+            Branch::Inference
+        );
+        assert_eq!(
+            Branch::of_top_level_element(names::DISPLAY_SPLITTER, false),
+            Branch::Source
+        );
+        assert_eq!(
+            Branch::of_top_level_element("videoconvert3", false),
+            Branch::Source
+        );
+    }
+
+    #[test]
+    fn test_restarts_branch_with_backoff() {
+        let mut policy = RecoveryPolicy::default();
+        let now = Instant::now();
+
+        assert_eq!(
+            policy.decide(Branch::Recording, ErrorKind::Stream, now),
+            Some(RecoveryAction::RestartBranch(
+                Branch::Recording,
+                Duration::from_secs(1)
+            ))
+        );
+        // Follow-up errors are ignored until the branch restarts
+        assert_eq!(
+            policy.decide(Branch::Recording, ErrorKind::Stream, now),
+            None
+        );
+        policy.restarted(RecoveryTarget::Branch(Branch::Recording));
+
+        assert_eq!(
+            policy.decide(Branch::Recording, ErrorKind::Stream, now),
+            Some(RecoveryAction::RestartBranch(
+                Branch::Recording,
+                Duration::from_secs(2)
+            ))
+        );
+        policy.restarted(RecoveryTarget::Branch(Branch::Recording));
+
+        // Other branches are unaffected
+        assert_eq!(
+            policy.decide(Branch::Preview, ErrorKind::Resource, now),
+            Some(RecoveryAction::RestartBranch(
+                Branch::Preview,
+                Duration::from_secs(1)
+            ))
+        );
+
+        // The backoff resets once the branch has been stable
+        assert_eq!(
+            policy.decide(Branch::Recording, ErrorKind::Stream, now + STABLE_PERIOD),
+            Some(RecoveryAction::RestartBranch(
+                Branch::Recording,
+                Duration::from_secs(1)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_escalates_repeated_failures() {
+        let mut policy = RecoveryPolicy::default();
+        let now = Instant::now();
+
+        for _ in 0..MAX_BRANCH_RESTARTS {
+            assert!(matches!(
+                policy.decide(Branch::Inference, ErrorKind::Other, now),
+                Some(RecoveryAction::RestartBranch(Branch::Inference, _))
+            ));
+            policy.restarted(RecoveryTarget::Branch(Branch::Inference));
+        }
+        assert_eq!(
+            policy.decide(Branch::Inference, ErrorKind::Other, now),
+            Some(RecoveryAction::RestartPipeline(Duration::from_secs(1)))
+        );
+        // Nothing else is restarted while the pipeline restarts
+        assert_eq!(policy.decide(Branch::Preview, ErrorKind::Other, now), None);
+        policy.restarted(RecoveryTarget::Pipeline);
+
+        for _ in 1..MAX_PIPELINE_RESTARTS {
+            assert!(matches!(
+                policy.decide(Branch::Source, ErrorKind::Resource, now),
+                Some(RecoveryAction::RestartPipeline(_))
+            ));
+            policy.restarted(RecoveryTarget::Pipeline);
+        }
+        assert_eq!(
+            policy.decide(Branch::Source, ErrorKind::Resource, now),
+            Some(RecoveryAction::Quit)
+        );
+    }
+
+    #[test]
+    fn test_unrecoverable_errors() {
+        let mut policy = RecoveryPolicy::default();
+        let now = Instant::now();
+
+        assert_eq!(
+            policy.decide(Branch::Preview, ErrorKind::Unrecoverable, now),
+            Some(RecoveryAction::AbandonBranch(Branch::Preview))
+        );
+        assert_eq!(
+            policy.decide(Branch::Source, ErrorKind::Unrecoverable, now),
+            Some(RecoveryAction::Quit)
+        );
+    }
+
+    #[test]
+    fn test_restart_delay() {
+        assert_eq!(restart_delay(1), Duration::from_secs(1));
+        assert_eq!(restart_delay(3), Duration::from_secs(4));
+        assert_eq!(restart_delay(100), MAX_RESTART_DELAY);
+    }
+}
@@ -0,0 +1,288 @@
+//! Keeps recorded chunks from filling the storage device.
+mod policy;
+
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use gst::prelude::*;
+use once_cell::sync::Lazy;
+pub use policy::*;
+
+use crate::config::Config;
+use crate::foundation::gst::{splitmux_fragment_location, FRAGMENT_CLOSED, FRAGMENT_OPENED};
+use crate::logging::*;
+use crate::message::AAMessage;
+use crate::pipeline::encoder::{bitrate_kbps, set_bitrate_kbps};
+use crate::pipeline::names;
+use crate::upload::{upload_queue_path, UploadQueue};
+
+pub(self) static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "AA_RETENTION",
+        gst::DebugColorFlags::FG_YELLOW,
+        Some("Auto-Arena Retention"),
+    )
+});
+
+struct State {
+    /// The chunks currently being written, which are never deleted. A stopping session
+    /// may still be writing its last chunk after the next session has opened one.
+    open_chunks: HashSet<PathBuf>,
+    level: StorageLevel,
+    /// The encoder's bitrate before it was lowered
+    normal_bitrate: Option<u32>,
+}
+
+/// Periodically deletes old chunks from the video storage directory, and throttles or
+/// pauses recording when deleting isn't enough to stay within the configured limits.
+///
+/// When uploads are configured, only chunks that have left the upload queue are
+/// deleted. Otherwise, any finished chunk may be deleted.
+pub fn connect_retention_manager(config: &Config, pipeline: &gst::Pipeline) -> Result<()> {
+    info!(CAT, "Connecting retention manager");
+    let bus = pipeline
+        .bus()
+        .ok_or(anyhow!("Pipeline without bus. Shouldn't happen!"))?;
+
+    let state = Arc::new(Mutex::new(State {
+        open_chunks: HashSet::new(),
+        level: StorageLevel::Ok,
+        normal_bitrate: None,
+    }));
+
+    let bus_state = state.clone();
+    let bus_pipeline = pipeline.downgrade();
+    bus.connect("message", true, move |args| {
+        let msg = args[1].get::<gst::Message>().unwrap();
+        let mut state = bus_state.lock().unwrap();
+        if let Some(location) = splitmux_fragment_location(&msg, FRAGMENT_OPENED) {
+            state.open_chunks.insert(PathBuf::from(location));
+        } else if let Some(location) = splitmux_fragment_location(&msg, FRAGMENT_CLOSED) {
+            state.open_chunks.remove(Path::new(&location));
+        } else if let Ok(AAMessage::SessionStopped { session_id }) =
+            AAMessage::from_gst_message(&msg)
+        {
+            // Abandoned sessions never close their last chunk. Chunks are named after
+            // their session's datetime, which is also its ID.
+            state.open_chunks.retain(|chunk| {
+                !chunk
+                    .file_name()
+                    .map_or(false, |name| name.to_string_lossy().contains(&session_id))
+            });
+        } else if let gst::MessageView::StateChanged(change) = msg.view() {
+            // Nothing is left writing once the pipeline has stopped, eg. to be restarted
+            let from_pipeline = match (msg.src(), bus_pipeline.upgrade()) {
+                (Some(src), Some(pipeline)) => src == pipeline.upcast_ref::<gst::Object>(),
+                _ => false,
+            };
+            if from_pipeline && change.current() <= gst::State::Ready {
+                state.open_chunks.clear();
+            }
+        }
+        None
+    });
+
+    let manager = RetentionManager {
+        policy: RetentionPolicy::from_config(&config.video_storage),
+        // Canonicalized to match the chunk locations reported by the persistence sink
+        dir: config
+            .video_storage
+            .temp_dir_path
+            .relative()
+            .canonicalize()?,
+        extension: config.video_storage.container_format.extension(),
+        upload_queue_path: config
+            .upload
+            .upload_backend
+            .map(|_| upload_queue_path(config)),
+        low_space_bitrate_kbps: config.video_storage.low_space_bitrate_kbps,
+        bus,
+        pipeline: pipeline.downgrade(),
+    };
+    glib::timeout_add(config.video_storage.retention_check_interval(), move || {
+        if let Err(err) = manager.check(&mut state.lock().unwrap()) {
+            error!(CAT, "Failed to apply retention policy, {}", err);
+        }
+        glib::Continue(true)
+    });
+
+    Ok(())
+}
+
+struct RetentionManager {
+    policy: RetentionPolicy,
+    dir: PathBuf,
+    extension: &'static str,
+    /// The upload queue, if uploads are configured
+    upload_queue_path: Option<PathBuf>,
+    low_space_bitrate_kbps: Option<u32>,
+    bus: gst::Bus,
+    pipeline: glib::WeakRef<gst::Pipeline>,
+}
+
+impl RetentionManager {
+    fn check(&self, state: &mut State) -> Result<()> {
+        let chunks = self.scan_chunks(&state.open_chunks)?;
+        let mut used: u64 = chunks.iter().map(|c| c.size).sum();
+        let free = free_space_bytes(&self.dir)?;
+        log!(CAT, "Storage check, used={} free={}", used, free);
+
+        for path in self
+            .policy
+            .plan_deletions(&chunks, used, free, SystemTime::now())
+        {
+            info!(CAT, "Deleting chunk {:?}", path);
+            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            match fs::remove_file(&path) {
+                Ok(_) => used = used.saturating_sub(size),
+                Err(err) => warning!(CAT, "Failed to delete chunk {:?}, {}", path, err),
+            }
+        }
+
+        let free = free_space_bytes(&self.dir)?;
+        let level = self.policy.level(used, free);
+        if level != state.level {
+            self.apply_level(state, level);
+            state.level = level;
+            self.post_level_change(level, used, free)?;
+        }
+        Ok(())
+    }
+
+    /// Lists the finished chunks in the storage directory.
+    fn scan_chunks(&self, open_chunks: &HashSet<PathBuf>) -> Result<Vec<ChunkInfo>> {
+        let queued: Option<HashSet<PathBuf>> = match &self.upload_queue_path {
+            Some(path) => Some(UploadQueue::load(path)?.paths().collect()),
+            None => None,
+        };
+
+        let mut chunks = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(self.extension) ||
+                open_chunks.contains(&path)
+            {
+                continue;
+            }
+
+            let metadata = fs::metadata(&path)?;
+            if !metadata.is_file() {
+                continue;
+            }
+            chunks.push(ChunkInfo {
+                deletable: queued.as_ref().map_or(true, |q| !q.contains(&path)),
+                size: metadata.len(),
+                modified: metadata.modified()?,
+                path,
+            });
+        }
+        Ok(chunks)
+    }
+
+    /// Throttles or pauses recording to suit `level`.
+    fn apply_level(&self, state: &mut State, level: StorageLevel) {
+        let pipeline = match self.pipeline.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+
+        if let Some(valve) = pipeline.by_name(names::PERSISTENCE_VALVE) {
+            valve.set_property("drop", level == StorageLevel::Exhausted);
+        }
+
+        let encoder = match pipeline.by_name(names::PERSISTENCE_ENCODER) {
+            Some(encoder) if bitrate_kbps(&encoder).is_some() => encoder,
+            Some(encoder) => {
+                if self.low_space_bitrate_kbps.is_some() {
+                    warning!(
+                        CAT,
+                        obj: &encoder,
+                        "Can't change this encoder's bitrate while recording, keeping it"
+                    );
+                }
+                return;
+            }
+            None => return,
+        };
+        match (level, self.low_space_bitrate_kbps) {
+            (StorageLevel::Ok, _) => {
+                if let Some(bitrate) = state.normal_bitrate.take() {
+                    set_bitrate_kbps(&encoder, bitrate);
+                }
+            }
+            (_, Some(kbps)) => {
+                if state.normal_bitrate.is_none() {
+                    state.normal_bitrate = bitrate_kbps(&encoder);
+                }
+                set_bitrate_kbps(&encoder, kbps);
+            }
+            (_, None) => {}
+        }
+    }
+
+    fn post_level_change(
+        &self,
+        level: StorageLevel,
+        used_bytes: u64,
+        free_bytes: u64,
+    ) -> Result<()> {
+        let message = match level {
+            StorageLevel::Ok => {
+                info!(CAT, "Storage recovered, resuming normal recording");
+                AAMessage::StorageRecovered {
+                    free_bytes,
+                    used_bytes,
+                }
+            }
+            StorageLevel::Low => {
+                warning!(
+                    CAT,
+                    "Storage is low, free={} used={}",
+                    free_bytes,
+                    used_bytes
+                );
+                AAMessage::StorageLow {
+                    free_bytes,
+                    used_bytes,
+                    paused: false,
+                }
+            }
+            StorageLevel::Exhausted => {
+                warning!(
+                    CAT,
+                    "Storage is exhausted, pausing recording, free={} used={}",
+                    free_bytes,
+                    used_bytes
+                );
+                AAMessage::StorageLow {
+                    free_bytes,
+                    used_bytes,
+                    paused: true,
+                }
+            }
+        };
+
+        self.bus.post(message.to_gst_message()?)?;
+        Ok(())
+    }
+}
+
+/// Returns the number of bytes available to unprivileged users on the device holding
+/// `path`.
+pub(crate) fn free_space_bytes(path: &Path) -> Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(anyhow!(
+            "statvfs failed, {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
@@ -0,0 +1,172 @@
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use crate::config::VideoStorageConfig;
+
+/// How close the storage device is to its limits
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum StorageLevel {
+    /// Recording can continue normally
+    Ok,
+    /// Approaching a limit. Recording continues at a lower bitrate.
+    Low,
+    /// A limit has been reached. Recording is paused.
+    Exhausted,
+}
+
+/// A recorded chunk on disk
+#[derive(Clone, Debug)]
+pub struct ChunkInfo {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+    /// `true` if the chunk can be deleted without losing footage, either because it has
+    /// been uploaded or because no uploads are configured
+    pub deletable: bool,
+}
+
+/// The limits placed on the storage used by recorded chunks
+#[derive(Clone, Debug)]
+pub struct RetentionPolicy {
+    pub max_usage_bytes: Option<u64>,
+    pub min_free_bytes: u64,
+    pub max_age: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    pub fn from_config(config: &VideoStorageConfig) -> Self {
+        Self {
+            max_usage_bytes: config.max_disk_usage_bytes(),
+            min_free_bytes: config.min_free_space_bytes(),
+            max_age: config.max_chunk_age(),
+        }
+    }
+
+    /// Returns the storage level given the bytes `used` by chunks, and the bytes `free`
+    /// on the device.
+    ///
+    /// Space is considered low within twice the minimum free space, or within 90% of the
+    /// maximum usage.
+    pub fn level(&self, used: u64, free: u64) -> StorageLevel {
+        let usage_exceeds = |fraction: f64| {
+            self.max_usage_bytes
+                .map_or(false, |max| used as f64 >= max as f64 * fraction)
+        };
+
+        if free < self.min_free_bytes || usage_exceeds(1.0) {
+            StorageLevel::Exhausted
+        } else if free < self.min_free_bytes.saturating_mul(2) || usage_exceeds(0.9) {
+            StorageLevel::Low
+        } else {
+            StorageLevel::Ok
+        }
+    }
+
+    /// Returns the chunks that should be deleted, oldest first.
+    ///
+    /// Deletable chunks older than the maximum age are always deleted. After that, the
+    /// oldest deletable chunks are deleted until the storage level returns to `Ok`.
+    pub fn plan_deletions(
+        &self,
+        chunks: &[ChunkInfo],
+        mut used: u64,
+        mut free: u64,
+        now: SystemTime,
+    ) -> Vec<PathBuf> {
+        let mut deletable: Vec<&ChunkInfo> = chunks.iter().filter(|c| c.deletable).collect();
+        deletable.sort_by_key(|c| c.modified);
+
+        let mut deletions = vec![];
+        for chunk in deletable {
+            let expired = match (self.max_age, now.duration_since(chunk.modified)) {
+                (Some(max_age), Ok(age)) => age > max_age,
+                _ => false,
+            };
+            if !expired && self.level(used, free) == StorageLevel::Ok {
+                break;
+            }
+
+            used = used.saturating_sub(chunk.size);
+            free = free.saturating_add(chunk.size);
+            deletions.push(chunk.path.clone());
+        }
+        deletions
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn chunk(
+        name: &str,
+        size: u64,
+        age_secs: u64,
+        deletable: bool,
+        now: SystemTime,
+    ) -> ChunkInfo {
+        ChunkInfo {
+            path: PathBuf::from(name),
+            size,
+            modified: now - Duration::from_secs(age_secs),
+            deletable,
+        }
+    }
+
+    #[test]
+    fn test_deletes_oldest_deletable_chunks_until_ok() {
+        let now = SystemTime::now();
+        let policy = RetentionPolicy {
+            max_usage_bytes: Some(100 * MIB),
+            min_free_bytes: 10 * MIB,
+            max_age: None,
+        };
+        let chunks = vec![
+            chunk("newest", 30 * MIB, 10, true, now),
+            chunk("oldest-not-uploaded", 30 * MIB, 40, false, now),
+            chunk("oldest", 30 * MIB, 30, true, now),
+            chunk("middle", 30 * MIB, 20, true, now),
+        ];
+
+        // 120MiB used is over the limit. Deleting "oldest" leaves 90MiB, which is still
+        // low, so "middle" goes too.
+        let deletions = policy.plan_deletions(&chunks, 120 * MIB, 1000 * MIB, now);
+        assert_eq!(
+            deletions,
+            vec![PathBuf::from("oldest"), PathBuf::from("middle")]
+        );
+    }
+
+    #[test]
+    fn test_deletes_expired_chunks_with_space_to_spare() {
+        let now = SystemTime::now();
+        let policy = RetentionPolicy {
+            max_usage_bytes: None,
+            min_free_bytes: 10 * MIB,
+            max_age: Some(Duration::from_secs(60)),
+        };
+        let chunks = vec![
+            chunk("expired", MIB, 120, true, now),
+            chunk("fresh", MIB, 30, true, now),
+        ];
+
+        let deletions = policy.plan_deletions(&chunks, 2 * MIB, 1000 * MIB, now);
+        assert_eq!(deletions, vec![PathBuf::from("expired")]);
+    }
+
+    #[test]
+    fn test_level() {
+        let policy = RetentionPolicy {
+            max_usage_bytes: Some(100 * MIB),
+            min_free_bytes: 10 * MIB,
+            max_age: None,
+        };
+        assert_eq!(policy.level(50 * MIB, 100 * MIB), StorageLevel::Ok);
+        assert_eq!(policy.level(95 * MIB, 100 * MIB), StorageLevel::Low);
+        assert_eq!(policy.level(50 * MIB, 15 * MIB), StorageLevel::Low);
+        assert_eq!(policy.level(100 * MIB, 100 * MIB), StorageLevel::Exhausted);
+        assert_eq!(policy.level(50 * MIB, 5 * MIB), StorageLevel::Exhausted);
+    }
+}
@@ -0,0 +1,202 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use glib::ObjectExt;
+
+use super::{SessionManager, CAT};
+use crate::config::Config;
+use crate::logging::*;
+use crate::message::AAMessage;
+
+/// Gaps in detection shorter than this don't interrupt a run of detections
+const DROPOUT_TOLERANCE: Duration = Duration::from_secs(1);
+
+/// A change in whether there is activity in the arena
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ActivityChange {
+    Started,
+    Stopped,
+}
+
+/// Decides when activity starts and stops, given whether a target was detected in each
+/// inference frame.
+pub struct ActivityTrigger {
+    start_after: Duration,
+    stop_after: Duration,
+    /// The time of the first frame in the current run of detections
+    first_seen: Option<Duration>,
+    /// The time of the most recent frame with a detection
+    last_seen: Option<Duration>,
+    active: bool,
+}
+
+impl ActivityTrigger {
+    pub fn new(start_after: Duration, stop_after: Duration) -> Self {
+        Self {
+            start_after,
+            stop_after,
+            first_seen: None,
+            last_seen: None,
+            active: false,
+        }
+    }
+
+    /// Overrides whether activity is considered to be happening, such as when a session
+    /// is started or stopped by other means.
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+    }
+
+    /// Updates the trigger with the frame at `frame_time`, and returns the change in
+    /// activity, if any.
+    pub fn update(&mut self, frame_time: Duration, detected: bool) -> Option<ActivityChange> {
+        if detected {
+            let continues_run = self.last_seen.map_or(false, |last| {
+                frame_time.saturating_sub(last) <= DROPOUT_TOLERANCE
+            });
+            if !continues_run || self.first_seen.is_none() {
+                self.first_seen = Some(frame_time);
+            }
+            self.last_seen = Some(frame_time);
+
+            let seen_for = frame_time.saturating_sub(self.first_seen.unwrap());
+            if !self.active && seen_for >= self.start_after {
+                self.active = true;
+                return Some(ActivityChange::Started);
+            }
+        } else if self.active {
+            let unseen_for = self
+                .last_seen
+                .map_or(Duration::MAX, |last| frame_time.saturating_sub(last));
+            if unseen_for >= self.stop_after {
+                self.active = false;
+                self.first_seen = None;
+                return Some(ActivityChange::Stopped);
+            }
+        }
+        None
+    }
+}
+
+struct State {
+    trigger: ActivityTrigger,
+    /// `true` if the current inference frame has detected a target
+    frame_has_target: bool,
+}
+
+/// Connects a recorder to `bus` that starts a session when the configured target labels
+/// have been detected for `activity_start_secs`, and stops it once they've gone
+/// undetected for `activity_stop_secs`.
+pub fn connect_activity_recorder(
+    bus: &gst::Bus,
+    config: &Config,
+    sessions: Arc<SessionManager>,
+) -> Result<()> {
+    info!(CAT, "Connecting activity recorder");
+
+    let target_labels = config.tracking.target_labels.clone();
+    let state = Mutex::new(State {
+        trigger: ActivityTrigger::new(
+            config.recording.activity_start_duration(),
+            config.recording.activity_stop_duration(),
+        ),
+        frame_has_target: false,
+    });
+
+    bus.connect("message", true, move |args| {
+        let msg = args[1].get::<gst::Message>().unwrap();
+        let app_msg = if let Ok(msg) = AAMessage::from_gst_message(&msg) {
+            msg
+        } else {
+            return None;
+        };
+
+        let state_guard = &mut *state.lock().unwrap();
+        match app_msg {
+            AAMessage::InferFrameStart { .. } => {
+                state_guard.frame_has_target = false;
+            }
+            AAMessage::InferObjectDetection(details) => {
+                if target_labels.contains(&details.label) {
+                    state_guard.frame_has_target = true;
+                }
+            }
+            AAMessage::InferFrameDone { dts, .. } => {
+                let trigger = &mut state_guard.trigger;
+                trigger.set_active(sessions.active_session_id().is_some());
+
+                let frame_time = Duration::from_nanos(dts.nseconds());
+                let result = match trigger.update(frame_time, state_guard.frame_has_target) {
+                    Some(ActivityChange::Started) => {
+                        info!(CAT, "Activity detected, starting session");
+                        sessions.start().map(|_| ())
+                    }
+                    Some(ActivityChange::Stopped) => {
+                        info!(CAT, "Activity has stopped, stopping session");
+                        sessions.stop()
+                    }
+                    None => Ok(()),
+                };
+                if let Err(err) = result {
+                    error!(CAT, "Failed to change recording session, {}", err);
+                }
+            }
+            _ => {}
+        }
+
+        None
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn secs(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    #[test]
+    fn test_trigger_starts_after_persistent_detections() {
+        let mut trigger = ActivityTrigger::new(secs(2.0), secs(5.0));
+        assert_eq!(trigger.update(secs(0.0), true), None);
+        assert_eq!(trigger.update(secs(1.0), true), None);
+        // A brief dropout doesn't reset the run
+        assert_eq!(trigger.update(secs(1.5), false), None);
+        assert_eq!(
+            trigger.update(secs(2.0), true),
+            Some(ActivityChange::Started)
+        );
+    }
+
+    #[test]
+    fn test_trigger_restarts_run_after_long_gap() {
+        let mut trigger = ActivityTrigger::new(secs(2.0), secs(5.0));
+        assert_eq!(trigger.update(secs(0.0), true), None);
+        assert_eq!(trigger.update(secs(1.0), true), None);
+        // The gap ends the first run, so a new one begins at 3s
+        assert_eq!(trigger.update(secs(3.0), true), None);
+        assert_eq!(trigger.update(secs(4.0), true), None);
+        assert_eq!(
+            trigger.update(secs(5.0), true),
+            Some(ActivityChange::Started)
+        );
+    }
+
+    #[test]
+    fn test_trigger_stops_after_absence() {
+        let mut trigger = ActivityTrigger::new(secs(0.0), secs(5.0));
+        assert_eq!(
+            trigger.update(secs(0.0), true),
+            Some(ActivityChange::Started)
+        );
+        assert_eq!(trigger.update(secs(4.0), false), None);
+        assert_eq!(
+            trigger.update(secs(5.0), false),
+            Some(ActivityChange::Stopped)
+        );
+    }
+}
@@ -0,0 +1,45 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde_derive::{Deserialize, Serialize};
+
+/// The contents of a session's JSON sidecar, which is rewritten whenever the session
+/// changes.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SessionMetadata {
+    pub session_id: String,
+    /// RFC 3339
+    pub started_at: String,
+    /// RFC 3339, or `None` while the session is recording
+    pub stopped_at: Option<String>,
+    /// The session's finished chunks, in the order they were written
+    pub chunks: Vec<PathBuf>,
+    /// The application's configuration when the session started, with secrets redacted
+    pub config: serde_json::Value,
+}
+
+impl SessionMetadata {
+    pub fn new(
+        session_id: &str,
+        started_at: DateTime<Local>,
+        config: serde_json::Value,
+    ) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            started_at: started_at.to_rfc3339(),
+            stopped_at: None,
+            chunks: vec![],
+            config,
+        }
+    }
+
+    /// Writes the metadata to `path`, replacing it atomically.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
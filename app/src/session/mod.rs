@@ -0,0 +1,419 @@
+//! Starts and stops recording sessions while the rest of the pipeline keeps running.
+//!
+//! Each session links its own persistence bin to the recording splitter, and so gets its
+//! own datetime, chunk numbering, and metadata sidecar.
+mod activity;
+mod metadata;
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+pub use activity::*;
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use gst::prelude::*;
+pub use metadata::*;
+use once_cell::sync::Lazy;
+
+use crate::config::{Config, VideoStorageConfig};
+use crate::foundation::gst::{splitmux_fragment_location, FRAGMENT_CLOSED};
+use crate::logging::*;
+use crate::message::AAMessage;
+use crate::pipeline::{create_persistence_bin, names};
+
+pub(self) static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "AA_SESSION",
+        gst::DebugColorFlags::FG_BLUE,
+        Some("Auto-Arena Sessions"),
+    )
+});
+
+/// How long a stopping session's bin is given to finalize its last chunk before it's
+/// removed from the pipeline regardless.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Session {
+    metadata: SessionMetadata,
+    metadata_path: PathBuf,
+    bin: gst::Bin,
+    /// The recording splitter's pad that feeds `bin`
+    splitter_pad: gst::Pad,
+    /// `true` once the session has been asked to stop, and is draining
+    stopping: bool,
+}
+
+impl Session {
+    fn write_metadata(&self) {
+        if let Err(err) = self.metadata.write(&self.metadata_path) {
+            error!(
+                CAT,
+                "Failed to write session metadata to {:?}, {}", self.metadata_path, err
+            );
+        }
+    }
+}
+
+/// Owns the pipeline's recording sessions. At most one session records at a time,
+/// though a stopped session may still be finalizing while the next one starts.
+pub struct SessionManager {
+    storage_config: VideoStorageConfig,
+    config_snapshot: serde_json::Value,
+    pipeline: glib::WeakRef<gst::Pipeline>,
+    splitter: gst::Element,
+    sessions: Mutex<Vec<Session>>,
+}
+
+impl SessionManager {
+    /// Creates the session manager, and connects it to the pipeline's bus.
+    pub fn connect(config: &Config, pipeline: &gst::Pipeline) -> Result<Arc<Self>> {
+        info!(CAT, "Connecting session manager");
+
+        let splitter = pipeline
+            .by_name(names::RECORDING_SPLITTER)
+            .ok_or(anyhow!("Recording splitter not found"))?;
+        let bus = pipeline
+            .bus()
+            .ok_or(anyhow!("Pipeline without bus. Shouldn't happen!"))?;
+
+        let manager = Arc::new(Self {
+            storage_config: config.video_storage.clone(),
+            config_snapshot: serde_json::to_value(config.to_redacted_value()?)?,
+            pipeline: pipeline.downgrade(),
+            splitter,
+            sessions: Mutex::new(vec![]),
+        });
+
+        let weak_manager = Arc::downgrade(&manager);
+        bus.connect("message", true, move |args| {
+            let msg = args[1].get::<gst::Message>().unwrap();
+            if let Some(manager) = weak_manager.upgrade() {
+                manager.handle_message(&msg);
+            }
+            None
+        });
+
+        Ok(manager)
+    }
+
+    /// Starts a new recording session, and returns its ID.
+    pub fn start(&self) -> Result<String> {
+        let pipeline = self
+            .pipeline
+            .upgrade()
+            .ok_or(anyhow!("Pipeline has been dropped"))?;
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(active) = sessions.iter().find(|s| !s.stopping) {
+            return Err(anyhow!(
+                "Session {} is already recording",
+                active.metadata.session_id
+            ));
+        }
+
+        let now = Local::now();
+        let session_id = now.format("%Y%m%dT%H%M%S").to_string();
+        if sessions.iter().any(|s| s.metadata.session_id == session_id) {
+            return Err(anyhow!("Session {} is still finalizing", session_id));
+        }
+        info!(CAT, "Starting session {}", session_id);
+
+        let location = self.storage_config.video_path_pattern_for_datetime(now)?;
+        // Bin names must be unique within the pipeline, and a previous session may still
+        // be finalizing
+        let bin = create_persistence_bin(
+            &format!("{}.{}", names::PERSISTENCE_BIN, session_id),
+            &self.storage_config,
+            &location,
+        )?;
+
+        pipeline.add(&bin)?;
+        bin.sync_state_with_parent()?;
+        let splitter_pad = self.splitter.request_pad_simple("src_%u").ok_or(anyhow!(
+            "Could not request a pad from the recording splitter"
+        ))?;
+        let bin_sink_pad = bin
+            .static_pad("sink")
+            .ok_or(anyhow!("Persistence bin has no sink pad"))?;
+        if let Err(err) = splitter_pad.link(&bin_sink_pad) {
+            self.splitter.release_request_pad(&splitter_pad);
+            let _ = bin.set_state(gst::State::Null);
+            let _ = pipeline.remove(&bin);
+            return Err(err.into());
+        }
+
+        let session = Session {
+            metadata: SessionMetadata::new(&session_id, now, self.config_snapshot.clone()),
+            metadata_path: self
+                .storage_config
+                .session_metadata_path_for_datetime(now)?,
+            bin,
+            splitter_pad,
+            stopping: false,
+        };
+        session.write_metadata();
+        sessions.push(session);
+        drop(sessions);
+
+        self.post(AAMessage::SessionStarted {
+            session_id: session_id.clone(),
+        });
+        Ok(session_id)
+    }
+
+    /// Stops the recording session, if one is active.
+    ///
+    /// Once no buffer is being pushed to it, the session's bin is unlinked from the
+    /// recording splitter and sent an EOS, so that its final chunk is written completely.
+    /// The bin is removed from the pipeline once the EOS has reached its sink.
+    pub fn stop(self: &Arc<Self>) -> Result<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .iter_mut()
+            .find(|s| !s.stopping)
+            .ok_or(anyhow!("No session is recording"))?;
+        info!(CAT, "Stopping session {}", session.metadata.session_id);
+        session.stopping = true;
+
+        let bin_sink_pad = session
+            .bin
+            .static_pad("sink")
+            .ok_or(anyhow!("Persistence bin has no sink pad"))?;
+        // An idle probe fires straight away when nothing is flowing, eg. while recording
+        // is paused by the retention manager
+        session
+            .splitter_pad
+            .add_probe(gst::PadProbeType::IDLE, move |splitter_pad, _| {
+                let _ = splitter_pad.unlink(&bin_sink_pad);
+                bin_sink_pad.send_event(gst::event::Eos::new());
+                gst::PadProbeReturn::Remove
+            });
+
+        let weak_self = Arc::downgrade(self);
+        let bin_name = session.bin.name().to_string();
+        glib::timeout_add_once(STOP_TIMEOUT, move || {
+            if let Some(manager) = Weak::upgrade(&weak_self) {
+                manager.finalize(&bin_name, true);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Returns the ID of the recording session, if one is active.
+    pub fn active_session_id(&self) -> Option<String> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|s| !s.stopping)
+            .map(|s| s.metadata.session_id.clone())
+    }
+
+    /// Returns the persistence bin of the recording session, if one is active.
+    pub fn active_bin(&self) -> Option<gst::Bin> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|s| !s.stopping)
+            .map(|s| s.bin.clone())
+    }
+
+    /// Records the end of every session, without touching the pipeline. Used once the
+    /// whole pipeline has reached EOS, when the application is shutting down.
+    pub fn finish(&self) {
+        for mut session in self.sessions.lock().unwrap().drain(..) {
+            info!(CAT, "Finishing session {}", session.metadata.session_id);
+            session.metadata.stopped_at = Some(Local::now().to_rfc3339());
+            session.write_metadata();
+        }
+    }
+
+    fn handle_message(&self, msg: &gst::Message) {
+        let src = match msg.src() {
+            Some(src) => src,
+            None => return,
+        };
+
+        if let Some(location) = splitmux_fragment_location(msg, FRAGMENT_CLOSED) {
+            let mut sessions = self.sessions.lock().unwrap();
+            if let Some(session) = sessions.iter_mut().find(|s| src.has_as_ancestor(&s.bin)) {
+                debug!(
+                    CAT,
+                    "Session {} closed chunk {}", session.metadata.session_id, location
+                );
+                session.metadata.chunks.push(PathBuf::from(location));
+                session.write_metadata();
+            }
+            return;
+        }
+
+        // The persistence bins forward their children's messages, which is how we learn
+        // that a stopping session's EOS has reached its sink
+        let forwarded_eos = match msg.view() {
+            gst::MessageView::Element(element) => element
+                .structure()
+                .filter(|s| s.name() == "GstBinForwarded")
+                .and_then(|s| s.get::<gst::Message>("message").ok())
+                .map_or(false, |inner| inner.type_() == gst::MessageType::Eos),
+            _ => false,
+        };
+        if forwarded_eos {
+            self.finalize(&src.name(), false);
+        }
+    }
+
+    /// Removes the stopping session whose bin is named `bin_name` from the pipeline, and
+    /// records its end.
+    fn finalize(&self, bin_name: &str, timed_out: bool) {
+        let session = {
+            let mut sessions = self.sessions.lock().unwrap();
+            match sessions
+                .iter()
+                .position(|s| s.stopping && s.bin.name() == bin_name)
+            {
+                Some(i) => sessions.remove(i),
+                None => return,
+            }
+        };
+        if timed_out {
+            warning!(
+                CAT,
+                "Session {} did not finalize in time, its last chunk may be incomplete",
+                session.metadata.session_id
+            );
+        }
+        info!(CAT, "Session {} stopped", session.metadata.session_id);
+        self.remove(session);
+    }
+
+    /// Removes every session from the pipeline without waiting for them to finalize, so
+    /// their last chunks may be incomplete. Returns `true` if a session was recording.
+    ///
+    /// Used when a session's bin has failed, or before the pipeline is restarted.
+    pub fn abandon(&self) -> bool {
+        let sessions: Vec<Session> = self.sessions.lock().unwrap().drain(..).collect();
+        let was_recording = sessions.iter().any(|s| !s.stopping);
+        for session in sessions {
+            warning!(CAT, "Abandoning session {}", session.metadata.session_id);
+            self.remove(session);
+        }
+        was_recording
+    }
+
+    /// Removes `session`'s bin from the pipeline, and records its end.
+    fn remove(&self, mut session: Session) {
+        let _ = session.bin.set_state(gst::State::Null);
+        if let Some(pipeline) = self.pipeline.upgrade() {
+            let _ = pipeline.remove(&session.bin);
+        }
+        self.splitter.release_request_pad(&session.splitter_pad);
+
+        session.metadata.stopped_at = Some(Local::now().to_rfc3339());
+        session.write_metadata();
+
+        self.post(AAMessage::SessionStopped {
+            session_id: session.metadata.session_id,
+        });
+    }
+
+    fn post(&self, message: AAMessage) {
+        let bus = match self.pipeline.upgrade().and_then(|p| p.bus()) {
+            Some(bus) => bus,
+            None => return,
+        };
+        match message.to_gst_message() {
+            Ok(msg) => {
+                if let Err(err) = bus.post(msg) {
+                    error!(CAT, "Failed to post session message, {}", err);
+                }
+            }
+            Err(err) => error!(CAT, "Failed to build session message, {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::foundation::testing::{config_from_args, dispatch_until};
+
+    /// Returns a pipeline that feeds encoded live video to the recording splitter,
+    /// through the persistence valve.
+    fn create_test_pipeline() -> gst::Pipeline {
+        let pipeline = gst::parse_launch(&format!(
+            "videotestsrc is-live=true ! video/x-raw,width=320,height=240,framerate=30/1 \
+             ! valve name={} ! x264enc tune=zerolatency key-int-max=15 ! h264parse \
+             ! tee name={} allow-not-linked=true",
+            names::PERSISTENCE_VALVE,
+            names::RECORDING_SPLITTER
+        ))
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        pipeline.bus().unwrap().add_signal_watch();
+        pipeline
+    }
+
+    #[test]
+    fn test_stop_finalizes_chunk_while_valve_drops() {
+        let dir = std::env::temp_dir().join(format!(
+            "aa-session-{}-{}",
+            std::process::id(),
+            "stop_while_valve_drops"
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        // Faststart chunks only get their index once the muxer sees the EOS
+        let config = config_from_args(&[
+            "--debug-use-color-detection",
+            &format!("--temp-dir-path={}", dir.display()),
+            "--mp4-layout=faststart",
+        ]);
+        let pipeline = create_test_pipeline();
+        let manager = SessionManager::connect(&config, &pipeline).unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+        manager.start().unwrap();
+
+        let session_buffers = Arc::new(AtomicUsize::new(0));
+        let buffers = session_buffers.clone();
+        manager
+            .active_bin()
+            .and_then(|bin| bin.static_pad("sink"))
+            .unwrap()
+            .add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+                buffers.fetch_add(1, Ordering::SeqCst);
+                gst::PadProbeReturn::Ok
+            });
+        assert!(dispatch_until(Duration::from_secs(5), || {
+            session_buffers.load(Ordering::SeqCst) > 30
+        }));
+
+        // Pause recording as the retention manager would, and let the last buffers through
+        pipeline
+            .by_name(names::PERSISTENCE_VALVE)
+            .unwrap()
+            .set_property("drop", true);
+        std::thread::sleep(Duration::from_millis(200));
+        let stopped_at = session_buffers.load(Ordering::SeqCst);
+
+        manager.stop().unwrap();
+        let finalized = dispatch_until(STOP_TIMEOUT / 2, || {
+            manager.sessions.lock().unwrap().is_empty()
+        });
+        let chunk = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension() == Some("mp4".as_ref()))
+            .map(|path| std::fs::read(path).unwrap());
+        pipeline.set_state(gst::State::Null).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(finalized, "The session only stopped on its timeout");
+        assert_eq!(session_buffers.load(Ordering::SeqCst), stopped_at);
+        let chunk = chunk.expect("No chunk was written");
+        assert!(chunk.windows(4).any(|atom| atom == b"moov"));
+    }
+}
@@ -1,21 +1,29 @@
 //! Responsible for setting up the systems necessary for communication with hardware
 
+use std::sync::Arc;
+
 use aa_foundation::trace_category;
-use aa_sys::pantilt::PanTiltSystem;
+use aa_sys::pantilt::{PanTiltConfig, PanTiltSystem};
 use anyhow::Result;
 
 use self::tracing::*;
+use crate::config::Config;
 
 trace_category!("app::system");
 
-pub fn init_hardware_systems() -> Result<HardwareSystems> {
+pub fn init_hardware_systems(config: &Config) -> Result<HardwareSystems> {
     info!("Initializing hardware systems");
 
-    let pantilt = PanTiltSystem::init_system()?;
+    let pantilt = Arc::new(PanTiltSystem::init_system(PanTiltConfig {
+        pan_soft_limits: config.hardware.pan_soft_limits(),
+        tilt_soft_limits: config.hardware.tilt_soft_limits(),
+        home_on_start: config.hardware.home_on_start,
+        sleep_after_idle: config.hardware.stepper_sleep_after_idle(),
+    })?);
 
     Ok(HardwareSystems { pantilt })
 }
 
 pub struct HardwareSystems {
-    pub pantilt: PanTiltSystem,
+    pub pantilt: Arc<PanTiltSystem>,
 }
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use aa_sys::pantilt::PanTiltSystem;
+use anyhow::Result;
+use glib::ObjectExt;
+
+use super::CAT;
+use crate::config::{SharedConfig, TrackingConfig};
+use crate::logging::*;
+use crate::message::{AAMessage, TargetDetails};
+
+/// Connects a controller to `bus` that steers the pan/tilt steppers toward the selected
+/// target.
+///
+/// On every `TargetSelected` message, the distance between the target's center and the
+/// center of the frame is converted into new pan and tilt step targets. Choosing which
+/// object to follow is left to the target selection policy, so that the controller and
+/// the overlay always agree on the subject.
+///
+/// Corrections are applied to the pantilt system's current targets, so manual moves made
+/// elsewhere are respected, and the tracking settings are re-read from `config` each
+/// time so that they can be tuned while running.
+pub fn connect_tracking_controller(
+    bus: &gst::Bus,
+    config: SharedConfig,
+    pantilt: Arc<PanTiltSystem>,
+) -> Result<()> {
+    info!(CAT, "Connecting tracking controller");
+
+    bus.connect("message", true, move |args| {
+        let msg = args[1].get::<gst::Message>().unwrap();
+        let target = if let Ok(AAMessage::TargetSelected(target)) =
+            AAMessage::from_gst_message(&msg)
+        {
+            target
+        } else {
+            return None;
+        };
+
+        let (pan_correction, tilt_correction) =
+            corrections_for_target(&config.read().unwrap().tracking, &target);
+        if pan_correction == 0.0 && tilt_correction == 0.0 {
+            log!(CAT, "Target within dead zone, frame={:?}", target.pts);
+            return None;
+        }
+
+        let (pan_target, tilt_target) =
+            match pantilt.nudge_target(pan_correction, tilt_correction) {
+                Ok(targets) => targets,
+                Err(err) => {
+                    error!(CAT, "Failed to update pantilt target, {}", err);
+                    return None;
+                }
+            };
+        debug!(
+            CAT,
+            "Updated targets, frame={:?} track={} pan={:.1} ({:+.1}) tilt={:.1} ({:+.1})",
+            target.pts,
+            target.track_id,
+            pan_target,
+            pan_correction,
+            tilt_target,
+            tilt_correction
+        );
+
+        None
+    });
+
+    Ok(())
+}
+
+/// Returns the number of (pan, tilt) steps the camera should move so that `target`
+/// drifts toward the center of the frame. Positive values pan right and tilt down.
+fn corrections_for_target(config: &TrackingConfig, target: &TargetDetails) -> (f64, f64) {
+    let center = target.bounds.center();
+
+    let x_offset = center.x - 0.5;
+    let pan = if x_offset.abs() < config.pan_dead_zone {
+        0.0
+    } else {
+        x_offset * config.pan_steps_per_frame_width * config.pan_gain
+    };
+
+    let y_offset = center.y - 0.5;
+    let tilt = if y_offset.abs() < config.tilt_dead_zone {
+        0.0
+    } else {
+        y_offset * config.tilt_steps_per_frame_height * config.tilt_gain
+    };
+
+    (pan, tilt)
+}
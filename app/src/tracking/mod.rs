@@ -0,0 +1,17 @@
+//! Turns the detections posted to the pipeline bus into camera movement.
+mod controller;
+mod selection;
+mod tracker;
+
+pub use controller::*;
+use once_cell::sync::Lazy;
+pub use selection::*;
+pub use tracker::*;
+
+pub(self) static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "AA_TRACKING",
+        gst::DebugColorFlags::FG_CYAN,
+        Some("Auto-Arena Tracking"),
+    )
+});
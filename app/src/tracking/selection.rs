@@ -0,0 +1,206 @@
+use crate::config::{Config, TargetSelectionPolicy};
+use crate::foundation::geom::Rect;
+use crate::message::{TargetDetails, TrackDetails};
+
+/// Chooses the single subject the camera should follow from a frame's tracks.
+pub struct TargetSelector {
+    policy: TargetSelectionPolicy,
+    target_labels: Vec<String>,
+    rider_label: String,
+    rider_merge_min_overlap: f64,
+    /// The bounds of the most recently selected target
+    previous: Option<Rect>,
+}
+
+impl TargetSelector {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            policy: config.detection.target_selection_policy,
+            target_labels: config.tracking.target_labels.clone(),
+            rider_label: config.detection.rider_label.clone(),
+            rider_merge_min_overlap: config.detection.rider_merge_min_overlap,
+            previous: None,
+        }
+    }
+
+    /// Returns the target for the frame described by `tracks`, or `None` if none of the
+    /// tracks are eligible.
+    pub fn select(&mut self, tracks: &[TrackDetails]) -> Option<TargetDetails> {
+        let candidates: Vec<(&TrackDetails, Rect)> = tracks
+            .iter()
+            .filter(|t| self.target_labels.contains(&t.label))
+            .map(|t| (t, t.bounds))
+            .collect();
+
+        let highest_score = || {
+            candidates
+                .iter()
+                .max_by(|(a, _), (b, _)| a.score.total_cmp(&b.score))
+        };
+
+        let (track, bounds) = match self.policy {
+            TargetSelectionPolicy::LargestBox => candidates
+                .iter()
+                .max_by(|(_, a), (_, b)| a.area().total_cmp(&b.area())),
+            TargetSelectionPolicy::HighestScore => highest_score(),
+            TargetSelectionPolicy::ClosestToPrevious => match self.previous {
+                Some(previous) => {
+                    let distance_to_previous =
+                        |bounds: &Rect| bounds.center().distance_to(&previous.center());
+                    candidates.iter().min_by(|(_, a), (_, b)| {
+                        distance_to_previous(a).total_cmp(&distance_to_previous(b))
+                    })
+                }
+                None => highest_score(),
+            },
+            TargetSelectionPolicy::MergedHorseRider => highest_score(),
+        }
+        .map(|(track, bounds)| (*track, *bounds))?;
+
+        let bounds = if self.policy == TargetSelectionPolicy::MergedHorseRider {
+            self.merge_riders(bounds, tracks)
+        } else {
+            bounds
+        };
+
+        self.previous = Some(bounds);
+        Some(TargetDetails {
+            pts: track.pts,
+            track_id: track.track_id,
+            label: track.label.clone(),
+            score: track.score,
+            bounds,
+        })
+    }
+
+    /// Grows `horse` to include the boxes of any riders sufficiently overlapping it.
+    fn merge_riders(&self, horse: Rect, tracks: &[TrackDetails]) -> Rect {
+        tracks
+            .iter()
+            .filter(|t| t.label == self.rider_label)
+            .map(|t| t.bounds)
+            .filter(|rider| {
+                rider.area() > 0.0 &&
+                    horse.intersection_area(rider) / rider.area() >=
+                        self.rider_merge_min_overlap
+            })
+            .fold(horse, |merged, rider| merged.union(&rider))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use gst::ClockTime;
+
+    use super::*;
+
+    fn selector(policy: TargetSelectionPolicy) -> TargetSelector {
+        TargetSelector {
+            policy,
+            target_labels: vec!["horse".into()],
+            rider_label: "person".into(),
+            rider_merge_min_overlap: 0.5,
+            previous: None,
+        }
+    }
+
+    fn track(track_id: u32, label: &str, score: f32, bounds: Rect) -> TrackDetails {
+        TrackDetails {
+            track_id,
+            pts: ClockTime::ZERO,
+            label: label.into(),
+            score,
+            bounds,
+            missed_frames: 0,
+        }
+    }
+
+    fn selected_id(selector: &mut TargetSelector, tracks: &[TrackDetails]) -> Option<u32> {
+        selector.select(tracks).map(|target| target.track_id)
+    }
+
+    #[test]
+    fn test_largest_box() {
+        let mut selector = selector(TargetSelectionPolicy::LargestBox);
+        let tracks = [
+            track(1, "horse", 0.9, Rect::new(0.0, 0.0, 0.25, 0.25)),
+            track(2, "horse", 0.5, Rect::new(0.5, 0.5, 0.5, 0.5)),
+            // Larger still, but not a target label
+            track(3, "person", 0.9, Rect::new(0.0, 0.0, 1.0, 1.0)),
+        ];
+        assert_eq!(selected_id(&mut selector, &tracks), Some(2));
+    }
+
+    #[test]
+    fn test_highest_score() {
+        let mut selector = selector(TargetSelectionPolicy::HighestScore);
+        let tracks = [
+            track(1, "horse", 0.9, Rect::new(0.0, 0.0, 0.25, 0.25)),
+            track(2, "horse", 0.5, Rect::new(0.5, 0.5, 0.5, 0.5)),
+        ];
+        assert_eq!(selected_id(&mut selector, &tracks), Some(1));
+    }
+
+    #[test]
+    fn test_closest_to_previous() {
+        let mut selector = selector(TargetSelectionPolicy::ClosestToPrevious);
+        let left = Rect::new(0.0, 0.0, 0.25, 0.25);
+        let right = Rect::new(0.75, 0.0, 0.25, 0.25);
+
+        // Without a previous target, the highest score wins
+        let tracks = [track(1, "horse", 0.5, left), track(2, "horse", 0.9, right)];
+        assert_eq!(selected_id(&mut selector, &tracks), Some(2));
+
+        // Then the target nearest the previous one, regardless of score
+        let tracks = [
+            track(1, "horse", 0.9, left),
+            track(2, "horse", 0.5, Rect::new(0.625, 0.0, 0.25, 0.25)),
+        ];
+        assert_eq!(selected_id(&mut selector, &tracks), Some(2));
+    }
+
+    #[test]
+    fn test_selected_track_disappears() {
+        let mut selector = selector(TargetSelectionPolicy::ClosestToPrevious);
+        let tracks = [
+            track(1, "horse", 0.5, Rect::new(0.0, 0.0, 0.25, 0.25)),
+            track(2, "horse", 0.9, Rect::new(0.75, 0.0, 0.25, 0.25)),
+        ];
+        assert_eq!(selected_id(&mut selector, &tracks), Some(2));
+
+        // The remaining track takes over
+        assert_eq!(selected_id(&mut selector, &tracks[..1]), Some(1));
+
+        // With no tracks, there's no target
+        assert_eq!(selected_id(&mut selector, &[]), None);
+
+        // The previous target is remembered through the gap, so a track reappearing
+        // near it is preferred over a higher scoring one
+        let tracks = [
+            track(3, "horse", 0.5, Rect::new(0.125, 0.0, 0.25, 0.25)),
+            track(4, "horse", 0.9, Rect::new(0.75, 0.0, 0.25, 0.25)),
+        ];
+        assert_eq!(selected_id(&mut selector, &tracks), Some(3));
+    }
+
+    #[test]
+    fn test_merged_horse_rider() {
+        let mut selector = selector(TargetSelectionPolicy::MergedHorseRider);
+        let horse = Rect::new(0.25, 0.5, 0.5, 0.25);
+        let tracks = [
+            track(1, "horse", 0.9, horse),
+            // Overlaps the horse by half its area, so it's merged
+            track(2, "person", 0.9, Rect::new(0.25, 0.25, 0.25, 0.5)),
+            // Doesn't overlap the horse, so it's ignored
+            track(3, "person", 0.9, Rect::new(0.0, 0.0, 0.125, 0.125)),
+        ];
+
+        let target = selector.select(&tracks).unwrap();
+        assert_eq!(target.track_id, 1);
+        assert_eq!(target.label, "horse");
+        assert_eq!(target.bounds, Rect::new(0.25, 0.25, 0.5, 0.5));
+
+        // Without a horse, riders alone aren't targets
+        assert_eq!(selected_id(&mut selector, &tracks[1..]), None);
+    }
+}
@@ -0,0 +1,403 @@
+use std::sync::Mutex;
+
+use anyhow::Result;
+use glib::ObjectExt;
+use gst::ClockTime;
+
+use super::selection::TargetSelector;
+use super::CAT;
+use crate::config::{Config, TrackingConfig};
+use crate::foundation::geom::{Point, Rect, Size};
+use crate::logging::*;
+use crate::message::{AAMessage, DetectionDetails, TrackDetails};
+
+/// How strongly a matched detection pulls a track's position toward it (0..=1)
+const POSITION_GAIN: f64 = 0.7;
+/// How strongly a matched detection corrects a track's velocity (0..=1)
+const VELOCITY_GAIN: f64 = 0.3;
+
+struct State {
+    tracker: MultiObjectTracker,
+    selector: TargetSelector,
+    frame_dts: Option<ClockTime>,
+    frame_detections: Vec<DetectionDetails>,
+}
+
+/// Connects a multi-object tracker to `bus`.
+///
+/// The tracker consumes the `InferObjectDetection` messages belonging to each inference
+/// frame, and once the frame is done, posts `TrackCreated`, `TrackUpdated` and
+/// `TrackLost` messages back onto the bus. These are followed by a `TargetSelected`
+/// message if the target selection policy found a subject among the frame's tracks.
+pub fn connect_multi_object_tracker(bus: &gst::Bus, config: &Config) -> Result<()> {
+    info!(CAT, "Connecting multi-object tracker");
+
+    let state = Mutex::new(State {
+        tracker: MultiObjectTracker::new(config.tracking.clone()),
+        selector: TargetSelector::new(config),
+        frame_dts: None,
+        frame_detections: vec![],
+    });
+
+    bus.connect("message", true, move |args| {
+        let bus = args[0].get::<gst::Bus>().unwrap();
+        let msg = args[1].get::<gst::Message>().unwrap();
+        let app_msg = if let Ok(msg) = AAMessage::from_gst_message(&msg) {
+            msg
+        } else {
+            return None;
+        };
+
+        let state_guard = &mut state.lock().unwrap();
+        match app_msg {
+            AAMessage::InferFrameStart { dts } => {
+                state_guard.frame_dts = Some(dts);
+                state_guard.frame_detections.clear();
+            }
+            AAMessage::InferObjectDetection(details) => {
+                if state_guard.frame_dts == Some(details.pts) {
+                    state_guard.frame_detections.push(details);
+                }
+            }
+            AAMessage::InferFrameDone { dts, .. } => {
+                if state_guard.frame_dts.take() != Some(dts) {
+                    warning!(CAT, "Frame done without matching start, dts={:?}", dts);
+                    return None;
+                }
+
+                let detections = std::mem::take(&mut state_guard.frame_detections);
+                let mut messages = state_guard.tracker.update(dts, &detections);
+
+                let live_tracks: Vec<TrackDetails> = messages
+                    .iter()
+                    .filter_map(|msg| match msg {
+                        AAMessage::TrackCreated(track) | AAMessage::TrackUpdated(track) => {
+                            Some(track.clone())
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                if let Some(target) = state_guard.selector.select(&live_tracks) {
+                    messages.push(AAMessage::TargetSelected(target));
+                }
+
+                for event in messages {
+                    let msg = match event.to_gst_message() {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            error!(CAT, "Failed to build track message, {}", err);
+                            continue;
+                        }
+                    };
+                    if let Err(err) = bus.post(msg) {
+                        error!(CAT, "Failed to post track message, {}", err);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        None
+    });
+
+    Ok(())
+}
+
+/// Associates detections across inference frames, assigning each followed object a
+/// stable track ID.
+///
+/// Detections are matched to the predicted positions of existing tracks by
+/// intersection-over-union. Each track carries a constant-velocity model, corrected by
+/// its matched detections, which allows it to be held through short detection dropouts.
+pub struct MultiObjectTracker {
+    config: TrackingConfig,
+    tracks: Vec<Track>,
+    next_track_id: u32,
+}
+
+impl MultiObjectTracker {
+    pub fn new(config: TrackingConfig) -> Self {
+        Self {
+            config,
+            tracks: vec![],
+            next_track_id: 1,
+        }
+    }
+
+    /// Advances the tracker by one inference frame, returning the messages describing
+    /// how the set of tracks has changed.
+    pub fn update(
+        &mut self,
+        pts: ClockTime,
+        detections: &[DetectionDetails],
+    ) -> Vec<AAMessage> {
+        for track in self.tracks.iter_mut() {
+            track.predict();
+        }
+
+        // Gather all plausible track/detection pairs, then greedily accept the best
+        // overlapping pairs first
+        let mut candidates = vec![];
+        for (track_idx, track) in self.tracks.iter().enumerate() {
+            for (detection_idx, detection) in detections.iter().enumerate() {
+                if track.label != detection.label {
+                    continue;
+                }
+                let iou = track.bounds.iou(&detection.bounds);
+                if iou >= self.config.track_min_iou {
+                    candidates.push((iou, track_idx, detection_idx));
+                }
+            }
+        }
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let mut track_matched = vec![false; self.tracks.len()];
+        let mut detection_matched = vec![false; detections.len()];
+        for (_, track_idx, detection_idx) in candidates {
+            if track_matched[track_idx] || detection_matched[detection_idx] {
+                continue;
+            }
+            track_matched[track_idx] = true;
+            detection_matched[detection_idx] = true;
+            self.tracks[track_idx].correct(
+                detections[detection_idx].bounds,
+                detections[detection_idx].score,
+            );
+        }
+
+        let mut messages = vec![];
+
+        // Age out tracks that went unmatched
+        let config = &self.config;
+        let mut track_idx = 0;
+        self.tracks.retain_mut(|track| {
+            let matched = track_matched[track_idx];
+            track_idx += 1;
+            if matched {
+                return true;
+            }
+
+            track.missed_frames += 1;
+            if !track.is_confirmed(config) {
+                // Tentative tracks must be seen on consecutive frames
+                return false;
+            }
+            if track.missed_frames > config.track_max_missed_frames {
+                debug!(CAT, "Track lost, id={} label={}", track.id, track.label);
+                messages.push(AAMessage::TrackLost {
+                    track_id: track.id,
+                    pts,
+                });
+                return false;
+            }
+            true
+        });
+
+        // Report surviving tracks
+        for track in self.tracks.iter_mut() {
+            if !track.is_confirmed(&self.config) {
+                continue;
+            }
+            let details = track.details(pts);
+            if track.reported {
+                messages.push(AAMessage::TrackUpdated(details));
+            } else {
+                debug!(CAT, "Track created, id={} label={}", track.id, track.label);
+                track.reported = true;
+                messages.push(AAMessage::TrackCreated(details));
+            }
+        }
+
+        // Start tentative tracks for detections that matched nothing
+        for (detection_idx, detection) in detections.iter().enumerate() {
+            if detection_matched[detection_idx] {
+                continue;
+            }
+            let mut track = Track {
+                id: self.next_track_id,
+                label: detection.label.clone(),
+                score: detection.score,
+                bounds: detection.bounds,
+                velocity: (0.0, 0.0),
+                hits: 1,
+                missed_frames: 0,
+                reported: false,
+            };
+            self.next_track_id += 1;
+
+            // Allow single-hit configurations to report immediately
+            if track.is_confirmed(&self.config) {
+                debug!(CAT, "Track created, id={} label={}", track.id, track.label);
+                track.reported = true;
+                messages.push(AAMessage::TrackCreated(track.details(pts)));
+            }
+            self.tracks.push(track);
+        }
+
+        messages
+    }
+}
+
+struct Track {
+    id: u32,
+    label: String,
+    score: f32,
+    bounds: Rect,
+    /// The per-frame movement of the bounds' center
+    velocity: (f64, f64),
+    hits: u32,
+    missed_frames: u32,
+    /// `true` if a `TrackCreated` message has been emitted for this track
+    reported: bool,
+}
+
+impl Track {
+    fn is_confirmed(&self, config: &TrackingConfig) -> bool {
+        self.hits >= config.track_min_hits
+    }
+
+    fn predict(&mut self) {
+        self.bounds.x += self.velocity.0;
+        self.bounds.y += self.velocity.1;
+    }
+
+    fn correct(&mut self, measured: Rect, score: f32) {
+        let predicted = self.bounds.center();
+        let (residual_x, residual_y) = (
+            measured.center().x - predicted.x,
+            measured.center().y - predicted.y,
+        );
+
+        let size = Size::new(
+            self.bounds.width + POSITION_GAIN * (measured.width - self.bounds.width),
+            self.bounds.height + POSITION_GAIN * (measured.height - self.bounds.height),
+        );
+        let center = Point::new(
+            predicted.x + POSITION_GAIN * residual_x,
+            predicted.y + POSITION_GAIN * residual_y,
+        );
+        self.bounds = Rect::from_center_size(center, size);
+        self.velocity.0 += VELOCITY_GAIN * residual_x;
+        self.velocity.1 += VELOCITY_GAIN * residual_y;
+
+        self.score = score;
+        self.hits += 1;
+        self.missed_frames = 0;
+    }
+
+    fn details(&self, pts: ClockTime) -> TrackDetails {
+        TrackDetails {
+            track_id: self.id,
+            pts,
+            label: self.label.clone(),
+            score: self.score,
+            bounds: self.bounds,
+            missed_frames: self.missed_frames,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use gst::ClockTime;
+
+    use super::MultiObjectTracker;
+    use crate::config::TrackingConfig;
+    use crate::foundation::geom::Rect;
+    use crate::message::{AAMessage, DetectionDetails};
+
+    fn config() -> TrackingConfig {
+        TrackingConfig {
+            target_labels: vec!["horse".into()],
+            pan_steps_per_frame_width: 400.0,
+            pan_gain: 0.5,
+            pan_dead_zone: 0.05,
+            tilt_steps_per_frame_height: 225.0,
+            tilt_gain: 0.3,
+            tilt_dead_zone: 0.1,
+            track_min_iou: 0.3,
+            track_min_hits: 2,
+            track_max_missed_frames: 2,
+        }
+    }
+
+    fn horse_at(frame: u64, x: f64) -> DetectionDetails {
+        DetectionDetails {
+            pts: ClockTime::from_mseconds(frame * 200),
+            label: "horse".into(),
+            score: 0.8,
+            bounds: Rect::new(x, 0.4, 0.2, 0.3),
+        }
+    }
+
+    #[test]
+    fn test_track_persists_through_dropouts() {
+        gst::init().unwrap();
+
+        let mut tracker = MultiObjectTracker::new(config());
+        let pts = |frame: u64| ClockTime::from_mseconds(frame * 200);
+
+        assert!(tracker.update(pts(0), &[horse_at(0, 0.10)]).is_empty());
+        let created = tracker.update(pts(1), &[horse_at(1, 0.12)]);
+        let track_id = match created.as_slice() {
+            [AAMessage::TrackCreated(details)] => details.track_id,
+            _ => panic!("expected a single TrackCreated"),
+        };
+
+        // Two missed frames are tolerated
+        for frame in 2..4 {
+            match tracker.update(pts(frame), &[]).as_slice() {
+                [AAMessage::TrackUpdated(details)] => {
+                    assert_eq!(details.track_id, track_id);
+                    assert_eq!(details.missed_frames, frame as u32 - 1);
+                }
+                _ => panic!("expected a single TrackUpdated"),
+            }
+        }
+
+        // The detection reappears near its predicted position
+        match tracker.update(pts(4), &[horse_at(4, 0.18)]).as_slice() {
+            [AAMessage::TrackUpdated(details)] => {
+                assert_eq!(details.track_id, track_id);
+                assert_eq!(details.missed_frames, 0);
+            }
+            _ => panic!("expected a single TrackUpdated"),
+        }
+
+        // And is finally lost
+        tracker.update(pts(5), &[]);
+        tracker.update(pts(6), &[]);
+        match tracker.update(pts(7), &[]).as_slice() {
+            [AAMessage::TrackLost {
+                track_id: lost_id, ..
+            }] => assert_eq!(*lost_id, track_id),
+            _ => panic!("expected a single TrackLost"),
+        }
+    }
+
+    #[test]
+    fn test_labels_are_tracked_separately() {
+        gst::init().unwrap();
+
+        let mut tracker = MultiObjectTracker::new(config());
+        let person = |frame| DetectionDetails {
+            label: "person".into(),
+            ..horse_at(frame, 0.1)
+        };
+
+        tracker.update(ClockTime::ZERO, &[horse_at(0, 0.1), person(0)]);
+        let messages = tracker.update(
+            ClockTime::from_mseconds(200),
+            &[horse_at(1, 0.1), person(1)],
+        );
+        let ids: Vec<u32> = messages
+            .iter()
+            .map(|msg| match msg {
+                AAMessage::TrackCreated(details) => details.track_id,
+                _ => panic!("expected only TrackCreated"),
+            })
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+    }
+}
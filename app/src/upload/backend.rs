@@ -0,0 +1,295 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use super::queue::{QueuedChunk, ResumeState};
+use super::s3::S3Backend;
+use crate::config::{UploadBackendKind, UploadConfig};
+
+/// Called with a chunk's new resume state whenever an upload makes progress, so that it
+/// can be persisted. `None` discards any previous progress.
+pub type ProgressFn<'a> = dyn FnMut(Option<&ResumeState>) -> Result<()> + 'a;
+
+/// A destination for finished video chunks
+pub trait UploadBackend: Send {
+    /// Uploads `chunk`, resuming from `chunk.resume` where the backend supports it.
+    fn upload(&self, chunk: &QueuedChunk, on_progress: &mut ProgressFn) -> Result<()>;
+
+    /// Returns `true` if the destination holds a complete copy of `chunk`.
+    fn verify(&self, chunk: &QueuedChunk) -> Result<bool>;
+}
+
+/// Creates the backend described by `config`, or `None` if uploading is disabled.
+pub fn create_backend(config: &UploadConfig) -> Result<Option<Box<dyn UploadBackend>>> {
+    let (kind, destination) = match (config.upload_backend, &config.upload_destination) {
+        (Some(kind), Some(destination)) => (kind, destination),
+        (None, _) => return Ok(None),
+        (Some(kind), None) => return Err(anyhow!("No destination for {:?} uploads", kind)),
+    };
+
+    Ok(Some(match kind {
+        UploadBackendKind::Directory => Box::new(DirectoryBackend::new(destination)),
+        UploadBackendKind::Webdav => Box::new(WebDavBackend::new(
+            destination,
+            config.upload_username.as_deref(),
+            config.upload_password.as_deref(),
+        )?),
+        UploadBackendKind::S3 => Box::new(S3Backend::new(
+            destination,
+            config.upload_username.as_deref().unwrap_or_default(),
+            config.upload_password.as_deref().unwrap_or_default(),
+            &config.upload_s3_region,
+            config.s3_part_size_bytes(),
+        )?),
+    }))
+}
+
+/// Returns a URL guaranteed to end in a slash, so that object names can be joined onto
+/// it rather than replacing its last segment.
+pub(super) fn parse_collection_url(url: &str) -> Result<Url> {
+    let mut url = Url::parse(url)?;
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    Ok(url)
+}
+
+/// Copies chunks into a directory.
+///
+/// Copies are written alongside the destination with a `.partial` extension, and
+/// interrupted copies resume from the end of the partial file.
+pub struct DirectoryBackend {
+    dir: PathBuf,
+}
+
+impl DirectoryBackend {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl UploadBackend for DirectoryBackend {
+    fn upload(&self, chunk: &QueuedChunk, _on_progress: &mut ProgressFn) -> Result<()> {
+        let name = chunk.remote_name()?;
+        let partial_path = self.dir.join(format!("{}.partial", name));
+
+        let mut source = File::open(&chunk.path)?;
+        let mut partial = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&partial_path)?;
+
+        let mut offset = partial.metadata()?.len();
+        if offset > source.metadata()?.len() {
+            // The partial copy can't belong to this chunk, so start again
+            partial.set_len(0)?;
+            offset = 0;
+        }
+        source.seek(SeekFrom::Start(offset))?;
+        io::copy(&mut source, &mut partial)?;
+        partial.sync_all()?;
+
+        fs::rename(&partial_path, self.dir.join(name))?;
+        Ok(())
+    }
+
+    fn verify(&self, chunk: &QueuedChunk) -> Result<bool> {
+        let copy_path = self.dir.join(chunk.remote_name()?);
+        if !copy_path.is_file() {
+            return Ok(false);
+        }
+        Ok(sha256_file(&copy_path)? == sha256_file(&chunk.path)?)
+    }
+}
+
+fn sha256_file(path: &Path) -> Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Uploads chunks into a WebDAV collection.
+///
+/// WebDAV has no standard way of appending to a file, so interrupted uploads start
+/// again from the beginning.
+pub struct WebDavBackend {
+    collection_url: Url,
+    agent: ureq::Agent,
+}
+
+impl WebDavBackend {
+    pub fn new(
+        collection_url: &str,
+        username: Option<&str>,
+        password: Option<&str>,
+    ) -> Result<Self> {
+        let mut collection_url = parse_collection_url(collection_url)?;
+        // ureq sends the URL's credentials using basic auth
+        if let Some(username) = username {
+            collection_url
+                .set_username(username)
+                .map_err(|_| anyhow!("Cannot set a username on the WebDAV URL"))?;
+            collection_url
+                .set_password(password)
+                .map_err(|_| anyhow!("Cannot set a password on the WebDAV URL"))?;
+        }
+
+        Ok(Self {
+            collection_url,
+            agent: ureq::Agent::new(),
+        })
+    }
+
+    fn object_url(&self, chunk: &QueuedChunk) -> Result<Url> {
+        Ok(self.collection_url.join(&chunk.remote_name()?)?)
+    }
+}
+
+impl UploadBackend for WebDavBackend {
+    fn upload(&self, chunk: &QueuedChunk, _on_progress: &mut ProgressFn) -> Result<()> {
+        let file = File::open(&chunk.path)?;
+        let length = file.metadata()?.len();
+        self.agent
+            .request_url("PUT", &self.object_url(chunk)?)
+            .set("Content-Length", &length.to_string())
+            .send(file)?;
+        Ok(())
+    }
+
+    fn verify(&self, chunk: &QueuedChunk) -> Result<bool> {
+        let response = match self
+            .agent
+            .request_url("HEAD", &self.object_url(chunk)?)
+            .call()
+        {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+
+        let remote_length = response
+            .header("Content-Length")
+            .and_then(|length| length.parse::<u64>().ok());
+        Ok(remote_length == Some(fs::metadata(&chunk.path)?.len()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::upload::mock_server::{MockResponse, MockServer};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("aa-upload-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_directory_backend_resumes_partial_copies() {
+        let source_dir = test_dir("source");
+        let dest_dir = test_dir("dest");
+        let chunk_path = source_dir.join("session-0001.mp4");
+        fs::write(&chunk_path, b"0123456789").unwrap();
+        // Simulate an interrupted copy
+        fs::write(dest_dir.join("session-0001.mp4.partial"), b"01234").unwrap();
+
+        let chunk = QueuedChunk {
+            path: chunk_path,
+            attempts: 1,
+            resume: None,
+        };
+        let backend = DirectoryBackend::new(&dest_dir);
+        assert!(!backend.verify(&chunk).unwrap());
+
+        backend.upload(&chunk, &mut |_| Ok(())).unwrap();
+        assert!(backend.verify(&chunk).unwrap());
+        assert_eq!(
+            fs::read(dest_dir.join("session-0001.mp4")).unwrap(),
+            b"0123456789"
+        );
+        assert!(!dest_dir.join("session-0001.mp4.partial").exists());
+    }
+
+    /// Serves a WebDAV collection that reports `stored_length` for every file it's
+    /// sent, or their actual length if `None`.
+    fn webdav_server(stored_length: Option<usize>) -> MockServer {
+        let mut lengths = HashMap::new();
+        MockServer::start(move |request| match request.method.as_str() {
+            "PUT" => {
+                let length = stored_length.unwrap_or(request.body.len());
+                lengths.insert(request.path.clone(), length);
+                MockResponse::status(201)
+            }
+            "HEAD" => match lengths.get(&request.path) {
+                Some(length) => MockResponse::status(200).header("Content-Length", length),
+                None => MockResponse::status(404),
+            },
+            _ => MockResponse::status(405),
+        })
+    }
+
+    fn webdav_chunk(test: &str) -> QueuedChunk {
+        let chunk_path = test_dir(test).join("session-0001.mp4");
+        fs::write(&chunk_path, b"0123456789").unwrap();
+        QueuedChunk {
+            path: chunk_path,
+            attempts: 0,
+            resume: None,
+        }
+    }
+
+    #[test]
+    fn test_webdav_backend_uploads_with_basic_auth() {
+        let server = webdav_server(None);
+        let chunk = webdav_chunk("webdav");
+        let backend = WebDavBackend::new(
+            &format!("{}/dav/chunks", server.url),
+            Some("user"),
+            Some("pass"),
+        )
+        .unwrap();
+
+        assert!(!backend.verify(&chunk).unwrap());
+        backend.upload(&chunk, &mut |_| Ok(())).unwrap();
+        assert!(backend.verify(&chunk).unwrap());
+
+        let requests = server.requests();
+        let put = requests
+            .iter()
+            .find(|request| request.method == "PUT")
+            .unwrap();
+        assert_eq!(put.path, "/dav/chunks/session-0001.mp4");
+        assert_eq!(put.body, b"0123456789");
+        for request in &requests {
+            assert_eq!(
+                request.header("Authorization"),
+                Some("Basic dXNlcjpwYXNz"),
+                "{} request without credentials",
+                request.method
+            );
+        }
+    }
+
+    #[test]
+    fn test_webdav_backend_rejects_truncated_copies() {
+        let server = webdav_server(Some(5));
+        let chunk = webdav_chunk("webdav-truncated");
+        let backend = WebDavBackend::new(&server.url, None, None).unwrap();
+
+        backend.upload(&chunk, &mut |_| Ok(())).unwrap();
+        assert!(!backend.verify(&chunk).unwrap());
+        assert!(server
+            .requests()
+            .iter()
+            .all(|request| request.header("Authorization").is_none()));
+    }
+}
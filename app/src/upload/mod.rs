@@ -0,0 +1,210 @@
+//! Uploads finished video chunks to remote storage, and removes the local copies once
+//! they're safely stored.
+mod backend;
+#[cfg(test)]
+mod mock_server;
+mod queue;
+mod s3;
+
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+pub use backend::*;
+use once_cell::sync::Lazy;
+pub use queue::*;
+
+use crate::config::Config;
+use crate::foundation::gst::{splitmux_fragment_location, FRAGMENT_CLOSED};
+use crate::logging::*;
+
+pub(self) static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "AA_UPLOAD",
+        gst::DebugColorFlags::FG_MAGENTA,
+        Some("Auto-Arena Upload"),
+    )
+});
+
+/// The name of the file in the video storage directory that holds the upload queue
+const QUEUE_FILENAME: &str = "upload-queue.toml";
+
+/// How long the uploader waits before retrying a chunk for the first time. The delay
+/// doubles after each subsequent failure.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Connects the chunk uploader to `bus`, if an upload backend is configured.
+///
+/// Each chunk closed by the persistence sink is added to a queue stored alongside the
+/// chunks, and uploaded in order on a dedicated thread. Chunks that fail to upload are
+/// retried with backoff, and local copies are deleted once the backend has verified the
+/// upload.
+pub fn connect_uploader(bus: &gst::Bus, config: &Config) -> Result<()> {
+    let backend = match create_backend(&config.upload)? {
+        Some(backend) => backend,
+        None => {
+            info!(
+                CAT,
+                "No upload backend configured, chunks will be kept locally"
+            );
+            return Ok(());
+        }
+    };
+    info!(CAT, "Connecting uploader");
+
+    let queue = Arc::new(Mutex::new(UploadQueue::load(&upload_queue_path(config))?));
+    info!(
+        CAT,
+        "Loaded upload queue, {} chunks pending",
+        queue.lock().unwrap().len()
+    );
+
+    let (wake_sender, wake_receiver) = mpsc::channel();
+    let uploader = Uploader {
+        backend,
+        queue: queue.clone(),
+        max_retry_delay: config.upload.max_retry_delay(),
+        keep_uploaded_chunks: config.video_storage.keep_uploaded_chunks,
+    };
+    std::thread::Builder::new()
+        .name("uploader".into())
+        .spawn(move || uploader.run(wake_receiver))?;
+
+    bus.connect("message", true, move |args| {
+        let msg = args[1].get::<gst::Message>().unwrap();
+        if let Some(location) = splitmux_fragment_location(&msg, FRAGMENT_CLOSED) {
+            info!(CAT, "Queueing finished chunk, {}", location);
+            // The queue is persisted here rather than on the upload thread, so that the
+            // final chunk is recorded even if the process exits immediately after
+            if let Err(err) = queue.lock().unwrap().push(PathBuf::from(location)) {
+                error!(CAT, "Failed to queue chunk for upload, {}", err);
+            }
+            let _ = wake_sender.send(());
+        }
+        None
+    });
+
+    Ok(())
+}
+
+/// Returns the path of the file that holds the upload queue.
+pub fn upload_queue_path(config: &Config) -> PathBuf {
+    config
+        .video_storage
+        .temp_dir_path
+        .relative()
+        .join(QUEUE_FILENAME)
+}
+
+struct Uploader {
+    backend: Box<dyn UploadBackend>,
+    queue: Arc<Mutex<UploadQueue>>,
+    max_retry_delay: Duration,
+    /// If true, uploaded chunks are left for the retention manager to delete
+    keep_uploaded_chunks: bool,
+}
+
+impl Uploader {
+    /// Uploads queued chunks until the wake channel is disconnected. A message is sent
+    /// on the channel whenever a chunk is queued.
+    fn run(self, wake: Receiver<()>) {
+        let mut retry_at: Option<Instant> = None;
+        loop {
+            let chunk = match self.queue.lock().unwrap().front().cloned() {
+                Some(chunk) => chunk,
+                None => {
+                    if wake.recv().is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+
+            if let Some(at) = retry_at {
+                let now = Instant::now();
+                if at > now {
+                    if let Err(RecvTimeoutError::Disconnected) = wake.recv_timeout(at - now) {
+                        return;
+                    }
+                    continue;
+                }
+            }
+            retry_at = None;
+
+            if let Err(err) = self.upload_front(&chunk) {
+                let attempts = chunk.attempts + 1;
+                let delay = self.retry_delay(attempts);
+                warning!(
+                    CAT,
+                    "Failed to upload {:?} (attempt {}), retrying in {:?}, {}",
+                    chunk.path,
+                    attempts,
+                    delay,
+                    err
+                );
+                retry_at = Some(Instant::now() + delay);
+
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(mut front) = queue.front().cloned() {
+                    front.attempts = attempts;
+                    if let Err(err) = queue.update_front(front) {
+                        error!(CAT, "Failed to persist upload queue, {}", err);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Uploads `chunk`, which is at the front of the queue, and removes it from the queue
+    /// and the filesystem once the upload is verified.
+    fn upload_front(&self, chunk: &QueuedChunk) -> Result<()> {
+        if !chunk.path.is_file() {
+            warning!(
+                CAT,
+                "Dropping queued chunk that no longer exists, {:?}",
+                chunk.path
+            );
+            self.queue.lock().unwrap().pop_front()?;
+            return Ok(());
+        }
+
+        // A previous run may have finished uploading without dequeuing the chunk
+        if !self.backend.verify(chunk)? {
+            info!(CAT, "Uploading {:?}", chunk.path);
+            let mut progress_chunk = chunk.clone();
+            self.backend.upload(chunk, &mut |resume| {
+                progress_chunk.resume = resume.cloned();
+                self.queue
+                    .lock()
+                    .unwrap()
+                    .update_front(progress_chunk.clone())
+            })?;
+
+            if !self.backend.verify(chunk)? {
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(mut front) = queue.front().cloned() {
+                    front.resume = None;
+                    queue.update_front(front)?;
+                }
+                return Err(anyhow!("Uploaded chunk failed verification"));
+            }
+        }
+
+        if self.keep_uploaded_chunks {
+            info!(CAT, "Uploaded {:?}", chunk.path);
+        } else {
+            info!(CAT, "Uploaded {:?}, removing local copy", chunk.path);
+            std::fs::remove_file(&chunk.path)?;
+        }
+        self.queue.lock().unwrap().pop_front()?;
+        Ok(())
+    }
+
+    fn retry_delay(&self, attempts: u32) -> Duration {
+        INITIAL_RETRY_DELAY
+            .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+            .min(self.max_retry_delay)
+    }
+}
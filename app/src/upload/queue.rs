@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde_derive::{Deserialize, Serialize};
+
+/// A finished video chunk waiting to be uploaded
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct QueuedChunk {
+    pub path: PathBuf,
+    /// The number of failed attempts to upload the chunk
+    pub attempts: u32,
+    /// Backend specific progress, used to resume an interrupted upload
+    pub resume: Option<ResumeState>,
+}
+
+impl QueuedChunk {
+    /// The name the chunk is given by the upload destination
+    pub fn remote_name(&self) -> Result<String> {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or(anyhow!("Chunk path has no file name, {:?}", self.path))
+    }
+}
+
+/// The progress of a multipart upload
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ResumeState {
+    pub upload_id: String,
+    /// The ETags of the parts uploaded so far, in order
+    pub part_etags: Vec<String>,
+    /// The size of every part but the last, in bytes. An upload can only resume with
+    /// parts of the same size.
+    pub part_size: u64,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct QueueFile {
+    chunks: VecDeque<QueuedChunk>,
+}
+
+/// An ordered list of chunks to upload, which is written to disk whenever it changes so
+/// that uploads survive restarts.
+pub struct UploadQueue {
+    path: PathBuf,
+    chunks: VecDeque<QueuedChunk>,
+}
+
+impl UploadQueue {
+    /// Loads the queue stored at `path`, or creates an empty one if there is no file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let chunks = if path.is_file() {
+            toml::from_str::<QueueFile>(&fs::read_to_string(path)?)?.chunks
+        } else {
+            VecDeque::new()
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            chunks,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Adds the chunk at `chunk_path` to the back of the queue, unless it's already
+    /// queued.
+    pub fn push(&mut self, chunk_path: PathBuf) -> Result<()> {
+        if self.chunks.iter().any(|chunk| chunk.path == chunk_path) {
+            return Ok(());
+        }
+
+        self.chunks.push_back(QueuedChunk {
+            path: chunk_path,
+            attempts: 0,
+            resume: None,
+        });
+        self.persist()
+    }
+
+    /// Returns the paths of the queued chunks, in order.
+    pub fn paths(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.chunks.iter().map(|chunk| chunk.path.clone())
+    }
+
+    pub fn front(&self) -> Option<&QueuedChunk> {
+        self.chunks.front()
+    }
+
+    /// Replaces the chunk at the front of the queue.
+    pub fn update_front(&mut self, chunk: QueuedChunk) -> Result<()> {
+        match self.chunks.front_mut() {
+            Some(front) => *front = chunk,
+            None => return Err(anyhow!("Cannot update the front of an empty queue")),
+        }
+        self.persist()
+    }
+
+    pub fn pop_front(&mut self) -> Result<Option<QueuedChunk>> {
+        let chunk = self.chunks.pop_front();
+        self.persist()?;
+        Ok(chunk)
+    }
+
+    /// Writes the queue to disk. The file is replaced atomically, so a crash mid-write
+    /// leaves the previous queue intact.
+    fn persist(&self) -> Result<()> {
+        let contents = toml::to_string(&QueueFile {
+            chunks: self.chunks.clone(),
+        })?;
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
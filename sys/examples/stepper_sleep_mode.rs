@@ -1,5 +1,5 @@
 use aa_foundation::thread::set_thread_timerslack;
-use aa_sys::pantilt::hal::PanStepperPinMapping;
+use aa_sys::pantilt::hal::{StepperPinMapping, PAN_STEPPER_PINS};
 use aa_sys::timer::make_software_timer;
 #[allow(unused)]
 use anyhow::{anyhow, Result};
@@ -10,8 +10,8 @@ fn main() -> Result<()> {
     aa_foundation::tracing::setup_dev_tracing_subscriber();
     set_thread_timerslack(1);
 
-    let PanStepperPinMapping { sleep_pin, .. } =
-        aa_sys::pantilt::hal::get_rpi_stepper_pins()?;
+    let StepperPinMapping { sleep_pin, .. } =
+        aa_sys::pantilt::hal::get_rpi_stepper_pins(&PAN_STEPPER_PINS)?;
 
     let mut timer = make_software_timer();
     let mut stepper = Stepper::from_driver(A4988::new()).enable_sleep_mode_control(sleep_pin);
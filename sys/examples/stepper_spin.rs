@@ -1,5 +1,5 @@
 use aa_foundation::thread::set_thread_timerslack;
-use aa_sys::pantilt::hal::PanStepperPinMapping;
+use aa_sys::pantilt::hal::{StepperPinMapping, PAN_STEPPER_PINS};
 use aa_sys::timer::make_software_timer;
 #[allow(unused)]
 use anyhow::{anyhow, Result};
@@ -11,7 +11,7 @@ fn main() -> Result<()> {
     aa_foundation::tracing::setup_dev_tracing_subscriber();
     set_thread_timerslack(1);
 
-    let PanStepperPinMapping {
+    let StepperPinMapping {
         step_pin,
         direction_pin,
         reset_pin,
@@ -19,7 +19,7 @@ fn main() -> Result<()> {
         ms2_pin,
         ms3_pin,
         ..
-    } = aa_sys::pantilt::hal::get_rpi_stepper_pins()?;
+    } = aa_sys::pantilt::hal::get_rpi_stepper_pins(&PAN_STEPPER_PINS)?;
 
     let mut timer = make_software_timer();
     let _stepper = Stepper::from_driver(A4988::new())
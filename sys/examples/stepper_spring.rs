@@ -1,5 +1,5 @@
 use aa_foundation::tracing::base_macros::*;
-use aa_sys::pantilt::PanTiltSystem;
+use aa_sys::pantilt::{PanTiltConfig, PanTiltSystem};
 use anyhow::Result;
 
 fn main() {
@@ -10,7 +10,7 @@ fn main() {
 }
 
 fn run() -> Result<()> {
-    let pantilt = PanTiltSystem::init_system()?;
-    pantilt.update_target(4000.0)?;
+    let pantilt = PanTiltSystem::init_system(PanTiltConfig::default())?;
+    pantilt.update_target(4000.0, 0.0)?;
     pantilt.join()
 }
@@ -1,9 +1,27 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
 use super::tracing::*;
 
 #[derive(Default)]
 pub struct FakeOutputPin {
     pub pin: u8,
     pub name: Option<String>,
+    /// The simulated motor input this pin is wired to, if any
+    pub motor_input: Option<(Arc<SimulatedMotor>, MotorInput)>,
+}
+
+impl FakeOutputPin {
+    fn write(&self, high: bool) {
+        trace!(
+            "{}: {}",
+            self.name.as_ref().unwrap_or(&self.pin.to_string()),
+            high as u8
+        );
+        if let Some((ref motor, input)) = self.motor_input {
+            motor.set_input(input, high);
+        }
+    }
 }
 
 impl embedded_hal::digital::ErrorType for FakeOutputPin {
@@ -12,12 +30,230 @@ impl embedded_hal::digital::ErrorType for FakeOutputPin {
 
 impl embedded_hal::digital::OutputPin for FakeOutputPin {
     fn set_low(&mut self) -> Result<(), Self::Error> {
-        trace!("{}: 0", self.name.as_ref().unwrap_or(&self.pin.to_string()));
+        self.write(false);
         Ok(())
     }
 
     fn set_high(&mut self) -> Result<(), Self::Error> {
-        trace!("{}: 1", self.name.as_ref().unwrap_or(&self.pin.to_string()));
+        self.write(true);
         Ok(())
     }
 }
+
+/// A simulated input pin.
+///
+/// The pin's level is shared, and can be driven from elsewhere (tests, or simulated
+/// hardware) through the handle returned by [`FakeInputPin::level_handle`].
+pub struct FakeInputPin {
+    pub pin: u8,
+    pub name: Option<String>,
+    high: Arc<AtomicBool>,
+    /// If set, the pin falls low after being read this many more times
+    reads_until_low: Option<AtomicU32>,
+}
+
+impl FakeInputPin {
+    pub fn new(pin: u8, name: Option<String>, high: bool) -> Self {
+        Self {
+            pin,
+            name,
+            high: Arc::new(AtomicBool::new(high)),
+            reads_until_low: None,
+        }
+    }
+
+    /// Simulates a normally-open switch (with a pull-up) that closes after the pin has
+    /// been read `reads` times.
+    ///
+    /// This approximates a limit switch being reached partway through a homing move,
+    /// which polls the switch once per step.
+    pub fn switch_closing_after_reads(pin: u8, name: Option<String>, reads: u32) -> Self {
+        Self {
+            reads_until_low: Some(AtomicU32::new(reads)),
+            ..Self::new(pin, name, true)
+        }
+    }
+
+    /// Returns a handle that can be used to change the pin's level.
+    pub fn level_handle(&self) -> Arc<AtomicBool> {
+        self.high.clone()
+    }
+
+    fn read(&self) -> bool {
+        if let Some(ref reads_until_low) = self.reads_until_low {
+            let remaining = reads_until_low.load(Ordering::Relaxed);
+            if remaining == 0 {
+                self.high.store(false, Ordering::Relaxed);
+            } else {
+                reads_until_low.store(remaining - 1, Ordering::Relaxed);
+            }
+        }
+
+        let high = self.high.load(Ordering::Relaxed);
+        trace!(
+            "{}: read {}",
+            self.name.as_ref().unwrap_or(&self.pin.to_string()),
+            high as u8
+        );
+        high
+    }
+}
+
+impl embedded_hal::digital::ErrorType for FakeInputPin {
+    type Error = core::convert::Infallible;
+}
+
+impl embedded_hal::digital::InputPin for FakeInputPin {
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.read())
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(!self.read())
+    }
+}
+
+/// The A4988 inputs that a [`FakeOutputPin`] can drive on a [`SimulatedMotor`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MotorInput {
+    Step,
+    Direction,
+    Sleep,
+    Reset,
+    Ms1,
+    Ms2,
+    Ms3,
+}
+
+/// The number of full steps in one revolution of the simulated motor's shaft
+pub const SIMULATED_STEPS_PER_REVOLUTION: u32 = 200;
+
+/// The finest microstep resolution supported by the A4988
+const MICROSTEPS_PER_STEP: i64 = 16;
+
+/// A model of a stepper motor attached to an A4988, driven by [`FakeOutputPin`]s.
+///
+/// The model counts step pulses, taking the direction and microstep mode pins into
+/// account, to track the position of a virtual shaft.
+pub struct SimulatedMotor {
+    name: String,
+    state: Mutex<SimulatedMotorState>,
+}
+
+#[derive(Default)]
+struct SimulatedMotorState {
+    step: bool,
+    forward: bool,
+    /// The A4988's sleep and reset inputs are active low
+    sleep: bool,
+    reset: bool,
+    ms1: bool,
+    ms2: bool,
+    ms3: bool,
+    /// The position of the shaft, in sixteenths of a step
+    position: i64,
+}
+
+impl SimulatedMotor {
+    pub fn new(name: &str) -> Arc<Self> {
+        Arc::new(Self {
+            name: name.to_string(),
+            state: Mutex::new(SimulatedMotorState {
+                sleep: true,
+                reset: true,
+                ..Default::default()
+            }),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The shaft's position, in full steps from where it started
+    pub fn position_steps(&self) -> f64 {
+        self.state.lock().unwrap().position as f64 / MICROSTEPS_PER_STEP as f64
+    }
+
+    /// `true` while the driver's sleep input is asserted, which leaves the shaft free
+    pub fn is_asleep(&self) -> bool {
+        !self.state.lock().unwrap().sleep
+    }
+
+    /// The shaft's angle, in degrees from where it started
+    pub fn angle_degrees(&self) -> f64 {
+        self.position_steps() * 360.0 / SIMULATED_STEPS_PER_REVOLUTION as f64
+    }
+
+    fn set_input(&self, input: MotorInput, high: bool) {
+        let mut state = self.state.lock().unwrap();
+        match input {
+            MotorInput::Step => {
+                let rising_edge = high && !state.step;
+                state.step = high;
+                if rising_edge && state.sleep && state.reset {
+                    let delta = MICROSTEPS_PER_STEP / state.microstep_divisor();
+                    state.position += if state.forward { delta } else { -delta };
+                    trace!(motor = %self.name, position = state.position, "step");
+                }
+            }
+            MotorInput::Direction => state.forward = high,
+            MotorInput::Sleep => state.sleep = high,
+            MotorInput::Reset => state.reset = high,
+            MotorInput::Ms1 => state.ms1 = high,
+            MotorInput::Ms2 => state.ms2 = high,
+            MotorInput::Ms3 => state.ms3 = high,
+        }
+    }
+}
+
+impl SimulatedMotorState {
+    /// Decodes the microstep resolution from the MS pins, as described in the A4988's
+    /// datasheet
+    fn microstep_divisor(&self) -> i64 {
+        match (self.ms1, self.ms2, self.ms3) {
+            (false, false, false) => 1,
+            (true, false, false) => 2,
+            (false, true, false) => 4,
+            (true, true, false) => 8,
+            (true, true, true) => 16,
+            // Undefined by the datasheet
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use embedded_hal::digital::OutputPin;
+
+    use super::{FakeOutputPin, MotorInput, SimulatedMotor};
+
+    #[test]
+    fn test_simulated_motor_counts_microsteps() {
+        let motor = SimulatedMotor::new("test");
+        let pin = |input| FakeOutputPin {
+            motor_input: Some((motor.clone(), input)),
+            ..Default::default()
+        };
+        let mut step = pin(MotorInput::Step);
+        let mut direction = pin(MotorInput::Direction);
+        let mut ms1 = pin(MotorInput::Ms1);
+
+        direction.set_high().unwrap();
+        for _ in 0..4 {
+            step.set_high().unwrap();
+            step.set_low().unwrap();
+        }
+        assert_eq!(motor.position_steps(), 4.0);
+
+        // Half steps, backward
+        ms1.set_high().unwrap();
+        direction.set_low().unwrap();
+        step.set_high().unwrap();
+        step.set_high().unwrap(); // Not an edge
+        step.set_low().unwrap();
+        assert_eq!(motor.position_steps(), 3.5);
+        assert_eq!(motor.angle_degrees(), 3.5 * 1.8);
+    }
+}
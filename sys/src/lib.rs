@@ -1,4 +1,5 @@
 pub mod gpio;
 pub mod pantilt;
+pub mod signal;
 pub mod stepper;
 pub mod timer;
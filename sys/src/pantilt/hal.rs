@@ -1,10 +1,116 @@
+use std::sync::Arc;
+
 use anyhow::Result;
 use embedded_hal::digital::OutputPin;
+use once_cell::sync::Lazy;
 use stepper::drivers::a4988::A4988;
 use stepper::traits::*;
 
-pub fn create_pan_stepper() -> Result<A4988<(), Pin, Pin, Pin, Pin, Pin, Pin, Pin>> {
-    let PanStepperPinMapping {
+use crate::gpio::fake::{MotorInput, SimulatedMotor};
+
+/// The BCM pin numbers wired to the pan axis' A4988
+pub const PAN_STEPPER_PINS: StepperPinNumbers = StepperPinNumbers {
+    ms1: 26,
+    ms2: 19,
+    ms3: 13,
+    reset: 16,
+    sleep: 6,
+    step: 20,
+    direction: 21,
+};
+
+/// The BCM pin numbers wired to the tilt axis' A4988
+pub const TILT_STEPPER_PINS: StepperPinNumbers = StepperPinNumbers {
+    ms1: 25,
+    ms2: 24,
+    ms3: 23,
+    reset: 22,
+    sleep: 27,
+    step: 17,
+    direction: 18,
+};
+
+/// The BCM pin number wired to the pan axis' home limit switch. The switch is normally
+/// open, and pulls the pin low when closed.
+pub const PAN_LIMIT_SWITCH_PIN: u8 = 5;
+
+/// The number of times the simulated limit switch is read before it closes.
+#[cfg(target_arch = "x86_64")]
+const FAKE_LIMIT_SWITCH_READS: u32 = 2_000;
+
+static SIMULATED_PAN_MOTOR: Lazy<Arc<SimulatedMotor>> =
+    Lazy::new(|| SimulatedMotor::new("pan"));
+static SIMULATED_TILT_MOTOR: Lazy<Arc<SimulatedMotor>> =
+    Lazy::new(|| SimulatedMotor::new("tilt"));
+
+/// An A4988 whose inputs are driven by pins of type `P`
+pub type A4988Driver<P> = A4988<(), P, P, P, P, P, P, P>;
+
+pub type StepperDriver = A4988Driver<Pin>;
+
+pub fn create_pan_stepper() -> Result<StepperDriver> {
+    create_stepper(&PAN_STEPPER_PINS, simulated_pan_motor())
+}
+
+pub fn create_tilt_stepper() -> Result<StepperDriver> {
+    create_stepper(&TILT_STEPPER_PINS, simulated_tilt_motor())
+}
+
+/// Returns the simulated motor driven by the pan stepper's fake pins, or `None` if
+/// running on real hardware.
+pub fn simulated_pan_motor() -> Option<Arc<SimulatedMotor>> {
+    if cfg!(target_arch = "x86_64") {
+        Some(SIMULATED_PAN_MOTOR.clone())
+    } else {
+        None
+    }
+}
+
+/// Returns the simulated motor driven by the tilt stepper's fake pins, or `None` if
+/// running on real hardware.
+pub fn simulated_tilt_motor() -> Option<Arc<SimulatedMotor>> {
+    if cfg!(target_arch = "x86_64") {
+        Some(SIMULATED_TILT_MOTOR.clone())
+    } else {
+        None
+    }
+}
+
+/// Creates the input pin attached to the pan axis' home limit switch.
+pub fn create_pan_limit_switch() -> Result<LimitSwitchPin> {
+    #[cfg(target_arch = "aarch64")]
+    {
+        use rppal::gpio::Gpio;
+        Ok(Gpio::new()?.get(PAN_LIMIT_SWITCH_PIN)?.into_input_pullup())
+    }
+    #[cfg(target_arch = "x86_64")]
+    {
+        Ok(crate::gpio::fake::FakeInputPin::switch_closing_after_reads(
+            PAN_LIMIT_SWITCH_PIN,
+            Some("pan_limit".to_string()),
+            FAKE_LIMIT_SWITCH_READS,
+        ))
+    }
+}
+
+fn create_stepper(
+    pins: &StepperPinNumbers,
+    simulated_motor: Option<Arc<SimulatedMotor>>,
+) -> Result<StepperDriver> {
+    Ok(build_driver(get_stepper_pins(pins, simulated_motor)?))
+}
+
+/// Creates a stepper driven by fake pins, regardless of the platform. If provided, the
+/// pins drive `simulated_motor`.
+pub fn create_fake_stepper(
+    pins: &StepperPinNumbers,
+    simulated_motor: Option<Arc<SimulatedMotor>>,
+) -> Result<A4988Driver<crate::gpio::fake::FakeOutputPin>> {
+    Ok(build_driver(get_fake_stepper_pins(pins, simulated_motor)?))
+}
+
+fn build_driver<P: OutputPin>(
+    StepperPinMapping {
         ms1_pin,
         ms2_pin,
         ms3_pin,
@@ -12,24 +118,30 @@ pub fn create_pan_stepper() -> Result<A4988<(), Pin, Pin, Pin, Pin, Pin, Pin, Pi
         sleep_pin,
         step_pin,
         direction_pin,
-    } = get_stepper_pins()?;
-
+    }: StepperPinMapping<P>,
+) -> A4988Driver<P> {
     // TODO(shydnman): Set an initial state?
-    Ok(A4988::new()
+    A4988::new()
         .enable_step_control(step_pin)
         .enable_direction_control(direction_pin)
         .enable_step_mode_control((reset_pin, ms1_pin, ms2_pin, ms3_pin))
-        .enable_sleep_mode_control(sleep_pin))
+        .enable_sleep_mode_control(sleep_pin)
 }
 
-pub fn get_stepper_pins() -> Result<PanStepperPinMapping<Pin>> {
+/// Returns the pins of a single stepper. If running without hardware, the fake pins will
+/// drive `simulated_motor`.
+pub fn get_stepper_pins(
+    pins: &StepperPinNumbers,
+    simulated_motor: Option<Arc<SimulatedMotor>>,
+) -> Result<StepperPinMapping<Pin>> {
     #[cfg(target_arch = "aarch64")]
     {
-        get_rpi_stepper_pins()
+        let _ = simulated_motor;
+        get_rpi_stepper_pins(pins)
     }
     #[cfg(target_arch = "x86_64")]
     {
-        get_fake_stepper_pins()
+        get_fake_stepper_pins(pins, simulated_motor)
     }
 }
 
@@ -39,7 +151,24 @@ type Pin = crate::gpio::fake::FakeOutputPin;
 #[cfg(target_arch = "aarch64")]
 type Pin = rppal::gpio::OutputPin;
 
-pub struct PanStepperPinMapping<Pin>
+#[cfg(target_arch = "x86_64")]
+pub type LimitSwitchPin = crate::gpio::fake::FakeInputPin;
+
+#[cfg(target_arch = "aarch64")]
+pub type LimitSwitchPin = rppal::gpio::InputPin;
+
+/// The GPIO pin numbers used to drive a single A4988 stepper driver
+pub struct StepperPinNumbers {
+    pub step: u8,
+    pub direction: u8,
+    pub sleep: u8,
+    pub reset: u8,
+    pub ms1: u8,
+    pub ms2: u8,
+    pub ms3: u8,
+}
+
+pub struct StepperPinMapping<Pin>
 where
     Pin: OutputPin + Sized,
 {
@@ -52,46 +181,61 @@ where
     pub ms3_pin: Pin,
 }
 
-impl<Pin> PanStepperPinMapping<Pin>
+impl<Pin> StepperPinMapping<Pin>
 where
     Pin: OutputPin + Sized,
 {
-    fn new<F>(build_pin: F) -> Self
+    fn new<F>(pins: &StepperPinNumbers, build_pin: F) -> Self
     where
         F: Fn(u8, &'static str) -> Pin,
     {
-        PanStepperPinMapping {
-            ms1_pin: build_pin(26, "ms1"),
-            ms2_pin: build_pin(19, "ms2"),
-            ms3_pin: build_pin(13, "ms3"),
-            reset_pin: build_pin(16, "reset"),
-            sleep_pin: build_pin(6, "sleep"),
-            step_pin: build_pin(20, "step"),
-            direction_pin: build_pin(21, "direction"),
+        StepperPinMapping {
+            ms1_pin: build_pin(pins.ms1, "ms1"),
+            ms2_pin: build_pin(pins.ms2, "ms2"),
+            ms3_pin: build_pin(pins.ms3, "ms3"),
+            reset_pin: build_pin(pins.reset, "reset"),
+            sleep_pin: build_pin(pins.sleep, "sleep"),
+            step_pin: build_pin(pins.step, "step"),
+            direction_pin: build_pin(pins.direction, "direction"),
         }
     }
 }
 
 #[allow(unused)]
-pub fn get_rpi_stepper_pins() -> Result<PanStepperPinMapping<rppal::gpio::OutputPin>> {
+pub fn get_rpi_stepper_pins(
+    pins: &StepperPinNumbers,
+) -> Result<StepperPinMapping<rppal::gpio::OutputPin>> {
     use rppal::gpio::Gpio;
 
     let gpio = Gpio::new()?;
-    Ok(PanStepperPinMapping::new(move |pin, name| {
+    Ok(StepperPinMapping::new(pins, move |pin, name| {
         gpio.get(pin)
             .expect("Could not get pin from GPIO")
             .into_output()
     }))
 }
 
-#[allow(unused)]
-
 pub fn get_fake_stepper_pins(
-) -> Result<PanStepperPinMapping<crate::gpio::fake::FakeOutputPin>> {
+    pins: &StepperPinNumbers,
+    simulated_motor: Option<Arc<SimulatedMotor>>,
+) -> Result<StepperPinMapping<crate::gpio::fake::FakeOutputPin>> {
     use crate::gpio::fake::FakeOutputPin;
 
-    Ok(PanStepperPinMapping::new(|pin, name| FakeOutputPin {
-        pin: 26,
+    Ok(StepperPinMapping::new(pins, |pin, name| FakeOutputPin {
+        pin,
         name: Some(name.to_string()),
+        motor_input: simulated_motor.as_ref().map(|motor| {
+            let input = match name {
+                "step" => MotorInput::Step,
+                "direction" => MotorInput::Direction,
+                "sleep" => MotorInput::Sleep,
+                "reset" => MotorInput::Reset,
+                "ms1" => MotorInput::Ms1,
+                "ms2" => MotorInput::Ms2,
+                "ms3" => MotorInput::Ms3,
+                _ => unreachable!("Unknown stepper pin {}", name),
+            };
+            (motor.clone(), input)
+        }),
     }))
 }
@@ -2,54 +2,214 @@
 
 pub mod hal;
 mod worker;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 #[allow(unused)]
 use aa_foundation::prelude::*;
 use aa_foundation::trace_category;
-use anyhow::{ensure, Result};
+use anyhow::{anyhow, ensure, Result};
 use crossbeam::channel::Sender;
 
 trace_category!("pantilt");
 
 /// Used to instruct the pantilt system where it should be pointing
 pub struct PanTiltSystem {
-    join_handle: Option<JoinHandle<()>>,
+    join_handle: Mutex<Option<JoinHandle<()>>>,
     send_channel: Sender<PanTiltCommand>,
+    /// The most recent (pan, tilt) targets sent to the worker
+    targets: Mutex<(f64, f64)>,
+    positions: Arc<AxisPositions>,
+    pan_soft_limits: Option<(f64, f64)>,
+    tilt_soft_limits: Option<(f64, f64)>,
 }
 
 impl PanTiltSystem {
     /// Note, this method can only be called once.
-    pub fn init_system() -> Result<Self> {
+    pub fn init_system(config: PanTiltConfig) -> Result<Self> {
         static CREATED: AtomicBool = AtomicBool::new(false);
         ensure!(
             !CREATED.fetch_or(true, Ordering::Relaxed),
             "Only one PantiltController can be created per application"
         );
 
-        let (join_handle, send_channel) = worker::start_worker_thread()?;
+        let positions = Arc::new(AxisPositions::default());
+        let (pan_soft_limits, tilt_soft_limits) =
+            (config.pan_soft_limits, config.tilt_soft_limits);
+        let (join_handle, send_channel) =
+            worker::start_worker_thread(config, positions.clone())?;
         Ok(Self {
-            join_handle: Some(join_handle),
+            join_handle: Mutex::new(Some(join_handle)),
             send_channel,
+            targets: Mutex::new((0.0, 0.0)),
+            positions,
+            pan_soft_limits,
+            tilt_soft_limits,
         })
     }
 
-    pub fn update_target(&self, target_value: f64) -> Result<()> {
-        self.send_channel
-            .send(PanTiltCommand::UpdateTarget { target_value })?;
+    /// Instructs the system to point at the provided pan and tilt step targets, clamped
+    /// to the soft limits.
+    pub fn update_target(&self, pan_target: f64, tilt_target: f64) -> Result<()> {
+        let mut targets = self.targets.lock().unwrap();
+        let (pan_target, tilt_target) = self.clamp_targets(pan_target, tilt_target);
+        self.send_channel.send(PanTiltCommand::UpdateTarget {
+            pan_target,
+            tilt_target,
+        })?;
+        *targets = (pan_target, tilt_target);
         Ok(())
     }
 
-    pub fn join(mut self) -> Result<()> {
-        if let Some(handle) = self.join_handle.take() {
-            handle.join().unwrap();
+    /// Moves the targets by the provided pan and tilt steps, clamped to the soft limits,
+    /// returning the new (pan, tilt) targets. The targets are read and replaced
+    /// atomically, so concurrent nudges and updates are never lost.
+    pub fn nudge_target(&self, pan_steps: f64, tilt_steps: f64) -> Result<(f64, f64)> {
+        let mut targets = self.targets.lock().unwrap();
+        let (pan_target, tilt_target) =
+            self.clamp_targets(targets.0 + pan_steps, targets.1 + tilt_steps);
+        self.send_channel.send(PanTiltCommand::UpdateTarget {
+            pan_target,
+            tilt_target,
+        })?;
+        *targets = (pan_target, tilt_target);
+        Ok(*targets)
+    }
+
+    /// Returns the most recent (pan, tilt) step targets provided to `update_target` or
+    /// `nudge_target`, or set by homing.
+    pub fn targets(&self) -> (f64, f64) {
+        *self.targets.lock().unwrap()
+    }
+
+    /// Limits the (pan, tilt) step targets to the soft limits, as the worker does.
+    fn clamp_targets(&self, pan_target: f64, tilt_target: f64) -> (f64, f64) {
+        let clamp = |target: f64, soft_limits: Option<(f64, f64)>| match soft_limits {
+            Some((min, max)) => target.clamp(min, max),
+            None => target,
+        };
+        (
+            clamp(pan_target, self.pan_soft_limits),
+            clamp(tilt_target, self.tilt_soft_limits),
+        )
+    }
+
+    /// Returns the positions of both axes, and the targets they're moving toward.
+    pub fn position(&self) -> PanTiltPosition {
+        let (pan_target, tilt_target) = self.targets();
+        PanTiltPosition {
+            pan_step: self.positions.pan.load(),
+            tilt_step: self.positions.tilt.load(),
+            pan_target,
+            tilt_target,
         }
+    }
+
+    /// Drives the pan axis toward its limit switch, and once reached, declares that
+    /// position to be step zero.
+    ///
+    /// Targets received while homing are ignored, and the pan target is reset to zero.
+    pub fn home(&self) -> Result<()> {
+        let mut targets = self.targets.lock().unwrap();
+        self.send_channel.send(PanTiltCommand::Home)?;
+        targets.0 = 0.0;
         Ok(())
     }
+
+    /// Declares the current position of both axes to be step zero, and resets the
+    /// targets to match.
+    pub fn set_home_here(&self) -> Result<()> {
+        let mut targets = self.targets.lock().unwrap();
+        self.send_channel.send(PanTiltCommand::SetHomeHere)?;
+        *targets = (0.0, 0.0);
+        Ok(())
+    }
+
+    /// Returns the (pan, tilt) positions of the simulated motors, in full steps, or
+    /// `None` if the system is driving real hardware.
+    pub fn simulated_position(&self) -> Option<(f64, f64)> {
+        let pan = hal::simulated_pan_motor()?;
+        let tilt = hal::simulated_tilt_motor()?;
+        Some((pan.position_steps(), tilt.position_steps()))
+    }
+
+    /// Returns the pan axis to its home position, puts its driver to sleep, and waits
+    /// for the worker thread to exit. No further commands are accepted afterward.
+    pub fn park(&self) -> Result<()> {
+        self.send_channel.send(PanTiltCommand::Park)?;
+        self.join()
+    }
+
+    /// Waits for the worker thread to exit.
+    pub fn join(&self) -> Result<()> {
+        let join_handle = self.join_handle.lock().unwrap().take();
+        if let Some(handle) = join_handle {
+            if handle.join().is_err() {
+                return Err(anyhow!("The pantilt worker thread panicked"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot of the pantilt system's axes, in steps relative to home
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PanTiltPosition {
+    pub pan_step: f64,
+    pub tilt_step: f64,
+    pub pan_target: f64,
+    pub tilt_target: f64,
+}
+
+/// The step positions most recently reported by the worker thread
+#[derive(Default)]
+pub(self) struct AxisPositions {
+    pan: AtomicStep,
+    tilt: AtomicStep,
+}
+
+/// An `f64` step position that can be shared between threads
+#[derive(Default)]
+pub(self) struct AtomicStep(AtomicU64);
+
+impl AtomicStep {
+    fn load(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn store(&self, step: f64) {
+        self.0.store(step.to_bits(), Ordering::Relaxed)
+    }
+}
+
+/// Configures the pantilt system's physical constraints
+#[derive(Clone, Debug, Default)]
+pub struct PanTiltConfig {
+    /// The inclusive (min, max) range of steps the pan axis may move within, relative to
+    /// its home position
+    pub pan_soft_limits: Option<(f64, f64)>,
+    /// The inclusive (min, max) range of steps the tilt axis may move within, relative
+    /// to its home position
+    pub tilt_soft_limits: Option<(f64, f64)>,
+    /// If `true`, the pan axis is homed against its limit switch when the system starts
+    pub home_on_start: bool,
+    /// How long an axis may sit at rest before its driver is put to sleep. Sleeping
+    /// drivers don't hold their position. If `None`, drivers are never put to sleep.
+    pub sleep_after_idle: Option<Duration>,
 }
 
 /// The commands sent to the
 pub enum PanTiltCommand {
-    UpdateTarget { target_value: f64 },
+    UpdateTarget {
+        pan_target: f64,
+        tilt_target: f64,
+    },
+    /// Homes the pan axis against its limit switch
+    Home,
+    /// Declares the current position of both axes to be home
+    SetHomeHere,
+    /// Returns the pan axis home and stops the worker
+    Park,
 }
@@ -1,4 +1,6 @@
+use std::sync::Arc;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use aa_foundation::spring::{
     update_spring_system, SpringConfig, SpringSystemState, SpringsUpdateResult,
@@ -6,20 +8,38 @@ use aa_foundation::spring::{
 use aa_foundation::thread::set_thread_timerslack;
 use anyhow::Result;
 use crossbeam::channel::Sender;
+use embedded_hal::digital::InputPin;
+use stepper::traits::{SetDirection, SetSleepMode, SetStepMode, Step};
 
-use super::hal::create_pan_stepper;
+use super::hal::{
+    create_pan_limit_switch, create_pan_stepper, create_tilt_stepper, LimitSwitchPin,
+};
 use super::tracing::*;
-use super::PanTiltCommand;
+use super::{AtomicStep, AxisPositions, PanTiltCommand, PanTiltConfig};
 use crate::stepper::velocity::{FsmStatus, StepperVelocityController};
-use crate::timer::make_software_timer;
+use crate::timer::{make_software_timer, Timer, RATE_1MHZ};
+
+/// The velocity at which an axis moves toward its limit switch, in steps per timer tick.
+/// Homing always moves backward.
+const HOMING_VELOCITY: f64 = -200.0 / RATE_1MHZ as f64;
+
+/// How long the worker waits for a command when neither axis is moving.
+const REST_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long the pan axis is given to return home when parking, before its driver is put
+/// to sleep wherever it happens to be.
+const PARK_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub fn start_worker_thread() -> Result<(JoinHandle<()>, Sender<PanTiltCommand>)> {
+pub fn start_worker_thread(
+    config: PanTiltConfig,
+    positions: Arc<AxisPositions>,
+) -> Result<(JoinHandle<()>, Sender<PanTiltCommand>)> {
     let (send_channel, receive_channel) = crossbeam::channel::unbounded();
     let join_handle =
         std::thread::Builder::new()
             .name("pantilt".into())
             .spawn(move || {
-                thread_main(receive_channel)
+                thread_main(config, positions, receive_channel)
                     .expect("The pantilt control thread encountered an error");
             })?;
     Ok((join_handle, send_channel))
@@ -29,73 +49,459 @@ fn minimize_timerslack() {
     set_thread_timerslack(1);
 }
 
-fn thread_main(cmd_channel: crossbeam::channel::Receiver<PanTiltCommand>) -> Result<()> {
+fn thread_main(
+    config: PanTiltConfig,
+    positions: Arc<AxisPositions>,
+    cmd_channel: crossbeam::channel::Receiver<PanTiltCommand>,
+) -> Result<()> {
     info!("starting pantilt worker thread");
     minimize_timerslack();
 
-    let timer = make_software_timer();
-    let mut spring_state = SpringSystemState::from_time_provider(&timer);
-    spring_state.spring_config = SpringConfig {
-        clamp: false,
-        tension: 200.0,
-        friction: 4.0,
-        mass: 12.0,
-        ..SpringConfig::default()
-    };
-    let mut velocity_ctrl = StepperVelocityController::new(create_pan_stepper()?, timer);
+    let mut pan = Axis::new(
+        "pan",
+        create_pan_stepper()?,
+        &positions.pan,
+        config.pan_soft_limits,
+        Some(create_pan_limit_switch()?),
+        config.sleep_after_idle,
+    );
+    let mut tilt = Axis::new(
+        "tilt",
+        create_tilt_stepper()?,
+        &positions.tilt,
+        config.tilt_soft_limits,
+        None::<LimitSwitchPin>,
+        config.sleep_after_idle,
+    );
+
+    if config.home_on_start {
+        pan.start_homing();
+    }
+
+    // Set when a park command is received. The worker exits once parking completes.
+    let mut parking_since: Option<Instant> = None;
 
     loop {
         // First let's check whether we've received any commands since the previous iteration
-        // of the loop
-        if let Ok(cmd) = cmd_channel.try_recv() {
+        // of the loop. If neither axis is moving, there's nothing to do until a command
+        // arrives, so we block for a while rather than spinning.
+        let cmd = if pan.is_resting() && tilt.is_resting() {
+            cmd_channel.recv_timeout(REST_POLL_INTERVAL).ok()
+        } else {
+            cmd_channel.try_recv().ok()
+        };
+        if let Some(cmd) = cmd {
             match cmd {
-                PanTiltCommand::UpdateTarget { target_value } => {
-                    debug!(target_value, "received target from channel");
-                    spring_state.update_target_value(target_value);
-                    velocity_ctrl.set_target_step(target_value);
+                PanTiltCommand::UpdateTarget {
+                    pan_target,
+                    tilt_target,
+                } => {
+                    debug!(pan_target, tilt_target, "received target from channel");
+                    pan.update_target(pan_target);
+                    tilt.update_target(tilt_target);
+                }
+                PanTiltCommand::Home => {
+                    pan.start_homing();
+                }
+                PanTiltCommand::SetHomeHere => {
+                    pan.set_home_here();
+                    tilt.set_home_here();
+                }
+                PanTiltCommand::Park => {
+                    info!("parking");
+                    pan.park();
+                    parking_since = Some(Instant::now());
+                }
+            }
+        }
+
+        pan.update();
+        tilt.update();
+
+        if let Some(parking_since) = parking_since {
+            if pan.is_resting() || parking_since.elapsed() >= PARK_TIMEOUT {
+                if !pan.is_resting() {
+                    warning!("pan axis did not reach home before the park timeout");
                 }
+                pan.release();
+                info!("parked, stopping pantilt worker thread");
+                return Ok(());
             }
         }
+    }
+}
+
+/// The state required to drive a single stepper motor toward its target
+struct Axis<'a, Driver, Switch>
+where
+    Driver: SetDirection + SetSleepMode + SetStepMode + Step,
+    Switch: InputPin,
+{
+    name: &'static str,
+    /// Where the axis' step position is reported to the rest of the application
+    reported_position: &'a AtomicStep,
+    spring_state: SpringSystemState<RATE_1MHZ>,
+    velocity_ctrl: StepperVelocityController<Driver, Timer<RATE_1MHZ>, RATE_1MHZ>,
+    soft_limits: Option<(f64, f64)>,
+    limit_switch: Option<Switch>,
+    /// `true` while the axis is moving toward its limit switch
+    homing: bool,
+    /// When the spring system last came to rest, or `None` if the axis is moving
+    resting_since: Option<Instant>,
+    /// How long the axis can rest before its driver is put to sleep
+    sleep_after_idle: Option<Duration>,
+}
+
+impl<'a, Driver, Switch> Axis<'a, Driver, Switch>
+where
+    Driver: SetDirection + SetSleepMode + SetStepMode + Step,
+    Switch: InputPin,
+{
+    fn new(
+        name: &'static str,
+        driver: Driver,
+        reported_position: &'a AtomicStep,
+        soft_limits: Option<(f64, f64)>,
+        limit_switch: Option<Switch>,
+        sleep_after_idle: Option<Duration>,
+    ) -> Self {
+        let timer = make_software_timer();
+        let mut spring_state = SpringSystemState::from_time_provider(&timer);
+        spring_state.spring_config = SpringConfig {
+            clamp: false,
+            tension: 200.0,
+            friction: 4.0,
+            mass: 12.0,
+            ..SpringConfig::default()
+        };
+
+        let mut velocity_ctrl = StepperVelocityController::new(driver, timer);
+        velocity_ctrl.set_soft_limits(soft_limits);
+
+        Self {
+            name,
+            reported_position,
+            spring_state,
+            velocity_ctrl,
+            soft_limits,
+            limit_switch,
+            homing: false,
+            resting_since: None,
+            sleep_after_idle,
+        }
+    }
+
+    fn is_resting(&self) -> bool {
+        self.resting_since.is_some()
+    }
+
+    /// Resumes movement if the axis was resting, waking the driver if necessary.
+    fn stop_resting(&mut self) {
+        if self.resting_since.take().is_some() && self.velocity_ctrl.is_asleep() {
+            debug!(axis = self.name, "waking driver");
+            self.velocity_ctrl.wake();
+        }
+    }
+
+    fn update_target(&mut self, target_value: f64) {
+        if self.homing {
+            debug!(
+                axis = self.name,
+                target_value, "ignoring target while homing"
+            );
+            return;
+        }
+
+        let target_value = match self.soft_limits {
+            Some((min, max)) => target_value.clamp(min, max),
+            None => target_value,
+        };
+        self.spring_state.update_target_value(target_value);
+        self.velocity_ctrl.set_target_step(target_value);
+        self.stop_resting();
+    }
+
+    fn start_homing(&mut self) {
+        if self.limit_switch.is_none() {
+            warning!(
+                axis = self.name,
+                "cannot home an axis without a limit switch"
+            );
+            return;
+        }
+
+        info!(axis = self.name, "homing");
+        self.homing = true;
+        self.stop_resting();
+        // Our position is unknown until the switch is reached, so the limits are
+        // meaningless
+        self.velocity_ctrl.set_soft_limits(None);
+    }
+
+    /// Abandons any homing in progress, and returns the axis to step zero.
+    fn park(&mut self) {
+        self.homing = false;
+        self.velocity_ctrl.set_soft_limits(self.soft_limits);
+        self.update_target(0.0);
+    }
+
+    /// Puts the axis' driver to sleep, blocking until the driver has been updated.
+    fn release(&mut self) {
+        self.velocity_ctrl.sleep();
+        loop {
+            match self.velocity_ctrl.update() {
+                Ok(FsmStatus::Ready) => return,
+                Ok(_) => continue,
+                Err(err) => {
+                    error!(axis = self.name, "{:?}", err);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn set_home_here(&mut self) {
+        info!(axis = self.name, "setting home to the current position");
+        self.homing = false;
+        self.velocity_ctrl.set_home();
+        self.velocity_ctrl.set_soft_limits(self.soft_limits);
+        self.reported_position.store(0.0);
+
+        let velocity = self.velocity_ctrl.velocity();
+        self.spring_state.from_value = 0.0;
+        self.spring_state.update_target_value(0.0);
+        self.spring_state.apply_state_updates(0.0, velocity);
+    }
+
+    fn is_limit_switch_closed(&self) -> bool {
+        self.limit_switch
+            .as_ref()
+            .map(|switch| switch.is_low().unwrap_or(false))
+            .unwrap_or(false)
+    }
+
+    fn update(&mut self) {
+        if self.homing {
+            self.update_homing();
+            return;
+        }
+
+        let axis = self.name;
 
         // Attempt to update controller state machine. If the state machine did not complete
-        // its update, or an error occurred, we keep looping.
-        if !match velocity_ctrl.update() {
+        // its update, or an error occurred, we try again on the next iteration.
+        match self.velocity_ctrl.update() {
+            Ok(FsmStatus::Ready) if self.is_resting() => {
+                self.update_resting();
+                return;
+            }
             Ok(FsmStatus::Ready) => {
-                let step = velocity_ctrl.step();
+                let step = self.velocity_ctrl.step();
                 let step_float = *step.numer() as f64 / *step.denom() as f64;
-                let velocity = velocity_ctrl.velocity();
-                spring_state.apply_state_updates(step_float, velocity);
+                let velocity = self.velocity_ctrl.velocity();
+                self.spring_state.apply_state_updates(step_float, velocity);
+                self.reported_position.store(step_float);
 
                 debug!(
+                    axis,
                     value = step_float,
                     velocity,
-                    distance = spring_state.distance_to_target(),
-                    from = spring_state.from_value,
-                    target = spring_state.target_value,
+                    distance = self.spring_state.distance_to_target(),
+                    from = self.spring_state.from_value,
+                    target = self.spring_state.target_value,
                     "step complete. values applied to hardware."
                 );
-
-                true
             }
-            Ok(_) => false,
+            Ok(_) => return,
             Err(err) => {
-                error!("{:?}", err);
-                false
+                error!(axis, "{:?}", err);
+                return;
             }
-        } {
-            continue;
         }
 
-        match update_spring_system(&spring_state) {
+        match update_spring_system(&self.spring_state) {
             SpringsUpdateResult::VelocityChanged { new_velocity } => {
-                trace!(new_velocity, "spring velocity change");
-                velocity_ctrl.move_once_with_velocity(new_velocity);
+                trace!(axis, new_velocity, "spring velocity change");
+                if !self.velocity_ctrl.move_once_with_velocity(new_velocity) {
+                    trace!(axis, new_velocity, "move refused at soft limit");
+                }
             }
             SpringsUpdateResult::Finished { position } => {
-                trace!(position, "spring system is complete for now");
-                panic!();
-                // velocity_ctrl.move_to_rest(position);
+                debug!(axis, position, "spring system at rest, holding position");
+                self.resting_since = Some(Instant::now());
             }
         }
     }
+
+    /// Holds the axis' position, and puts its driver to sleep once it's been idle long
+    /// enough.
+    fn update_resting(&mut self) {
+        let (resting_since, sleep_after_idle) =
+            match (self.resting_since, self.sleep_after_idle) {
+                (Some(resting_since), Some(sleep_after_idle)) => {
+                    (resting_since, sleep_after_idle)
+                }
+                _ => return,
+            };
+
+        if !self.velocity_ctrl.is_asleep() && resting_since.elapsed() >= sleep_after_idle {
+            debug!(
+                axis = self.name,
+                "idle timeout reached, putting driver to sleep"
+            );
+            self.velocity_ctrl.sleep();
+        }
+    }
+
+    /// Steps toward the limit switch until it closes.
+    fn update_homing(&mut self) {
+        match self.velocity_ctrl.update() {
+            Ok(FsmStatus::Ready) => {}
+            Ok(_) => return,
+            Err(err) => {
+                error!(axis = self.name, "{:?}", err);
+                return;
+            }
+        }
+
+        if self.is_limit_switch_closed() {
+            info!(axis = self.name, "limit switch reached");
+            self.set_home_here();
+            return;
+        }
+
+        self.velocity_ctrl.move_once_with_velocity(HOMING_VELOCITY);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gpio::fake::{FakeInputPin, FakeOutputPin, SimulatedMotor};
+    use crate::pantilt::hal::{
+        create_fake_stepper, A4988Driver, PAN_LIMIT_SWITCH_PIN, PAN_STEPPER_PINS,
+    };
+
+    type FakeAxis<'a> = Axis<'a, A4988Driver<FakeOutputPin>, FakeInputPin>;
+
+    const SLEEP_AFTER_IDLE: Duration = Duration::from_millis(50);
+
+    /// Returns an axis without a limit switch, whose springs finish as soon as they're
+    /// within a step of their target.
+    fn resting_axis<'a>(
+        motor: &Arc<SimulatedMotor>,
+        position: &'a AtomicStep,
+        sleep_after_idle: Option<Duration>,
+    ) -> FakeAxis<'a> {
+        let driver = create_fake_stepper(&PAN_STEPPER_PINS, Some(motor.clone())).unwrap();
+        let mut axis = Axis::new("pan", driver, position, None, None, sleep_after_idle);
+        // Scaled down by the timer rate, to a single step
+        axis.spring_state.spring_config.precision = Some(RATE_1MHZ as f64);
+        axis
+    }
+
+    /// Updates `axis` until `done`, failing if it takes too long.
+    fn update_until(axis: &mut FakeAxis, mut done: impl FnMut(&FakeAxis) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(axis) {
+            assert!(
+                Instant::now() < deadline,
+                "axis never reached the expected state"
+            );
+            axis.update();
+        }
+    }
+
+    #[test]
+    fn test_holds_position_then_sleeps_when_idle() {
+        let motor = SimulatedMotor::new("resting");
+        let position = AtomicStep::default();
+        let mut axis = resting_axis(&motor, &position, Some(SLEEP_AFTER_IDLE));
+
+        // Once the springs finish, the driver stays awake to hold the position
+        update_until(&mut axis, |axis| axis.is_resting());
+        let held = motor.position_steps();
+        for _ in 0..100 {
+            axis.update();
+        }
+        assert!(!axis.velocity_ctrl.is_asleep());
+        assert!(!motor.is_asleep());
+        assert_eq!(motor.position_steps(), held);
+
+        std::thread::sleep(SLEEP_AFTER_IDLE);
+        update_until(&mut axis, |_| motor.is_asleep());
+        assert!(axis.velocity_ctrl.is_asleep());
+        assert!(axis.is_resting());
+    }
+
+    #[test]
+    fn test_never_sleeps_without_idle_timeout() {
+        let motor = SimulatedMotor::new("holding");
+        let position = AtomicStep::default();
+        let mut axis = resting_axis(&motor, &position, None);
+
+        update_until(&mut axis, |axis| axis.is_resting());
+        std::thread::sleep(SLEEP_AFTER_IDLE * 2);
+        for _ in 0..100 {
+            axis.update();
+        }
+        assert!(!axis.velocity_ctrl.is_asleep());
+        assert!(!motor.is_asleep());
+    }
+
+    #[test]
+    fn test_new_target_wakes_sleeping_driver() {
+        let motor = SimulatedMotor::new("waking");
+        let position = AtomicStep::default();
+        let mut axis = resting_axis(&motor, &position, Some(SLEEP_AFTER_IDLE));
+        update_until(&mut axis, |axis| axis.is_resting());
+        std::thread::sleep(SLEEP_AFTER_IDLE);
+        update_until(&mut axis, |_| motor.is_asleep());
+
+        axis.update_target(10.0);
+        assert!(!axis.is_resting());
+        assert!(!axis.velocity_ctrl.is_asleep());
+
+        // The simulated motor only counts steps made while its driver is awake
+        update_until(&mut axis, |_| motor.position_steps() > 1.0);
+        assert!(!motor.is_asleep());
+    }
+
+    #[test]
+    fn test_homing_stops_at_limit_switch() {
+        let motor = SimulatedMotor::new("homing");
+        let driver = create_fake_stepper(&PAN_STEPPER_PINS, Some(motor.clone())).unwrap();
+        let switch = FakeInputPin::switch_closing_after_reads(PAN_LIMIT_SWITCH_PIN, None, 10);
+        let position = AtomicStep::default();
+        position.store(42.0);
+        let soft_limits = Some((-100.0, 100.0));
+        let mut axis = Axis::new("pan", driver, &position, soft_limits, Some(switch), None);
+
+        axis.start_homing();
+        assert_eq!(axis.velocity_ctrl.soft_limits(), None);
+        axis.update_target(50.0);
+        assert_eq!(axis.spring_state.target_value, 0.0);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while axis.homing {
+            assert!(Instant::now() < deadline, "limit switch never reached");
+            axis.update();
+        }
+
+        // The motor was driven backward until the switch closed, which became home
+        assert!(motor.position_steps() < 0.0);
+        assert_eq!(position.load(), 0.0);
+        assert_eq!(*axis.velocity_ctrl.step().numer(), 0);
+        assert_eq!(axis.velocity_ctrl.soft_limits(), soft_limits);
+    }
+
+    #[test]
+    fn test_homing_requires_limit_switch() {
+        let driver = create_fake_stepper(&PAN_STEPPER_PINS, None).unwrap();
+        let position = AtomicStep::default();
+        let mut axis = Axis::new("tilt", driver, &position, None, None::<FakeInputPin>, None);
+
+        axis.start_homing();
+        assert!(!axis.homing);
+    }
 }
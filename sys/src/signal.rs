@@ -0,0 +1,44 @@
+//! Handles the signals used to ask the process to terminate.
+
+use aa_foundation::trace_category;
+use anyhow::Result;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use self::tracing::*;
+
+trace_category!("signal");
+
+/// The exit status used when a second termination signal arrives before the process has
+/// finished shutting down.
+const FORCED_EXIT_STATUS: i32 = 130;
+
+/// Calls `on_termination` from a dedicated thread when the process receives SIGINT or
+/// SIGTERM.
+///
+/// `on_termination` is expected to begin a graceful shutdown. If a second signal arrives
+/// before the process exits, the process is terminated immediately.
+pub fn handle_termination_signals<F>(on_termination: F) -> Result<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    std::thread::Builder::new()
+        .name("signals".into())
+        .spawn(move || {
+            let mut on_termination = Some(on_termination);
+            for signal in signals.forever() {
+                match on_termination.take() {
+                    Some(on_termination) => {
+                        info!(signal, "termination signal received, shutting down");
+                        on_termination();
+                    }
+                    None => {
+                        warning!(signal, "termination signal received again, exiting now");
+                        std::process::exit(FORCED_EXIT_STATUS);
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
@@ -26,11 +26,15 @@ where
     next_delay: Option<TimerDurationU32<TIMER_HZ>>,
     next_direction: Option<Direction>,
     next_step_mode: Option<Driver::StepMode>,
+    next_asleep: Option<bool>,
     current_velocity: f64,
     current_direction: Direction,
     current_step: Rational32,
     current_step_mode: Driver::StepMode,
+    current_asleep: bool,
     target_step: Option<f64>,
+    /// The inclusive range of steps the motor is permitted to occupy, if any
+    soft_limits: Option<(f64, f64)>,
     state: State<Driver, Timer, TIMER_HZ>,
 }
 
@@ -45,11 +49,14 @@ where
             next_delay: None,
             next_direction: None,
             next_step_mode: None,
+            next_asleep: None,
             current_velocity: 0.0,
             current_direction: Direction::Forward,
             current_step: Rational32::new_raw(1, Driver::StepMode::MAX_STEP_BASE as i32),
             current_step_mode: 1.try_into().expect("Unable to convert into StepMode"),
+            current_asleep: false,
             target_step: None,
+            soft_limits: None,
             state: State::Idle {
                 driver: driver,
                 timer: timer,
@@ -74,20 +81,71 @@ where
         self.target_step = Some(value);
     }
 
-    pub fn move_once_with_velocity(&mut self, velocity: f64) {
-        self.next_velocity = Some(velocity);
+    /// Restricts the steps the motor can move to, or lifts the restriction if `None`.
+    ///
+    /// Moves that would take the motor outside of the limits are refused by
+    /// [`Self::move_once_with_velocity`].
+    pub fn set_soft_limits(&mut self, limits: Option<(f64, f64)>) {
+        self.soft_limits = limits;
+    }
+
+    pub fn soft_limits(&self) -> Option<(f64, f64)> {
+        self.soft_limits
+    }
+
+    /// `true` if the driver is asleep, or has been asked to go to sleep.
+    pub fn is_asleep(&self) -> bool {
+        self.next_asleep.unwrap_or(self.current_asleep)
+    }
+
+    /// Puts the driver to sleep on the next update. A sleeping driver does not hold the
+    /// motor's position, but draws almost no current.
+    pub fn sleep(&mut self) {
+        self.next_asleep = Some(true);
+    }
+
+    /// Wakes the driver on the next update, before any pending steps are made.
+    pub fn wake(&mut self) {
+        self.next_asleep = Some(false);
+    }
+
+    /// Declares the motor's current position to be step zero.
+    pub fn set_home(&mut self) {
+        self.current_step = Rational32::from_integer(0);
+        self.target_step = None;
+    }
 
+    /// Schedules a single step at the provided velocity.
+    ///
+    /// Returns `false` if the step was refused because it would move the motor outside
+    /// of its soft limits. In that case the motor is brought to a stop.
+    pub fn move_once_with_velocity(&mut self, velocity: f64) -> bool {
         // See whether the direction has changed for this velocity
         let dir = direction_for_velocity(velocity);
-        if dir != self.current_direction {
-            self.next_direction = Some(dir);
-        }
 
         // Determine the full-step delay for this velocity
         let mut delay = TimerDurationU32::<TIMER_HZ>::from_ticks(velocity.inv().abs() as u32);
 
         // See whether we can microstep to smooth things out
         let step_mode = self.find_microstep(delay, dir);
+
+        if let Some((min, max)) = self.soft_limits {
+            let step_denom: u16 = step_mode.into();
+            let next_step = (self.current_step +
+                Rational32::new(dir as i32, step_denom as i32))
+            .to_f64()
+            .unwrap();
+            if next_step < min || next_step > max {
+                self.next_velocity = Some(0.0);
+                self.next_delay = None;
+                return false;
+            }
+        }
+
+        self.next_velocity = Some(velocity);
+        if dir != self.current_direction {
+            self.next_direction = Some(dir);
+        }
         if step_mode != self.current_step_mode {
             self.next_step_mode = Some(step_mode);
             let base = step_mode.into() as u32;
@@ -95,6 +153,7 @@ where
         }
 
         self.next_delay = Some(delay);
+        true
     }
 
     pub fn update(&mut self) -> Result<FsmStatus> {
@@ -103,10 +162,12 @@ where
         let next_delay = &mut self.next_delay;
         let next_direction = &mut self.next_direction;
         let next_step_mode = &mut self.next_step_mode;
+        let next_asleep = &mut self.next_asleep;
         let current_velocity = &mut self.current_velocity;
         let current_direction = &mut self.current_direction;
         let current_step = &mut self.current_step;
         let current_step_mode = &mut self.current_step_mode;
+        let current_asleep = &mut self.current_asleep;
 
         replace_with_and_return(
             &mut self.state,
@@ -118,10 +179,12 @@ where
                     next_delay,
                     next_direction,
                     next_step_mode,
+                    next_asleep,
                     current_velocity,
                     current_direction,
                     current_step,
                     current_step_mode,
+                    current_asleep,
                 )
             },
         )
@@ -181,3 +244,26 @@ fn foo() {
     eprintln!("{:?}", rng.start);
     eprintln!("{:?}", rng.end);
 }
+
+#[test]
+fn test_soft_limits_refuse_moves() {
+    use crate::pantilt::hal::{create_fake_stepper, PAN_STEPPER_PINS};
+
+    aa_foundation::thread::set_thread_timerslack(1);
+
+    // Fake pins, so that the test doesn't touch the GPIO of the hardware it runs on
+    let driver = create_fake_stepper(&PAN_STEPPER_PINS, None).unwrap();
+    let timer = crate::timer::make_software_timer();
+    let mut ctrl = StepperVelocityController::new(driver, timer);
+    ctrl.set_home();
+    ctrl.set_soft_limits(Some((-1.0, 1.0)));
+
+    // One step per second
+    let velocity = 1.0 / 1_000_000.0;
+    assert!(ctrl.move_once_with_velocity(velocity));
+    assert!(ctrl.move_once_with_velocity(-velocity));
+
+    ctrl.set_soft_limits(Some((0.0, 0.0)));
+    assert!(!ctrl.move_once_with_velocity(velocity));
+    assert!(!ctrl.move_once_with_velocity(-velocity));
+}
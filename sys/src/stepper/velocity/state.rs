@@ -6,7 +6,9 @@ use fugit::TimerDurationU32;
 use fugit_timer::Timer as TimerTrait;
 use num_rational::Rational32;
 use stepper::traits::{SetDirection, SetSleepMode, SetStepMode, Step};
-use stepper::{Direction, SetDirectionFuture, SetStepModeFuture, StepFuture};
+use stepper::{
+    Direction, SetDirectionFuture, SetSleepModeFuture, SetStepModeFuture, StepFuture,
+};
 
 pub enum State<Driver, Timer, const TIMER_HZ: u32>
 where
@@ -22,6 +24,10 @@ where
     SetStepMode {
         future: SetStepModeFuture<Driver, Timer, TIMER_HZ>,
     },
+    /// Puts the driver to sleep, or wakes it up
+    SetSleepMode {
+        future: SetSleepModeFuture<Driver, Timer, TIMER_HZ>,
+    },
     /// Delays by `delay` of time, then invokes the [State::Step] stage
     StepDelay {
         driver: Driver,
@@ -52,10 +58,12 @@ pub fn update<Driver, Timer, TimerError, const TIMER_HZ: u32>(
     next_delay: &mut Option<TimerDurationU32<TIMER_HZ>>,
     next_direction: &mut Option<Direction>,
     next_step_mode: &mut Option<Driver::StepMode>,
+    next_asleep: &mut Option<bool>,
     current_velocity: &mut f64,
     current_direction: &mut Direction,
     current_step: &mut Rational32,
     current_step_mode: &mut Driver::StepMode,
+    current_asleep: &mut bool,
 ) -> (Result<FsmStatus>, State<Driver, Timer, TIMER_HZ>)
 where
     Driver: SetDirection + SetSleepMode + SetSleepMode + SetStepMode + Step,
@@ -65,6 +73,18 @@ where
     loop {
         match state {
             State::Idle { driver, mut timer } => {
+                // Sleep mode changes come first, as the driver must be awake before any
+                // other changes can take effect
+                if let Some(asleep) = next_asleep.take() {
+                    if asleep != *current_asleep {
+                        state = State::SetSleepMode {
+                            future: SetSleepModeFuture::new(asleep, driver, timer),
+                        };
+                        *current_asleep = asleep;
+                        continue;
+                    }
+                }
+
                 if let Some(velocity) = next_velocity.take() {
                     *current_velocity = velocity;
                 }
@@ -126,6 +146,19 @@ where
                     return (Ok(FsmStatus::Pending), State::SetStepMode { future });
                 }
             },
+            State::SetSleepMode { mut future } => match future.poll() {
+                Poll::Ready(Ok(())) => {
+                    let (driver, timer) = future.release();
+                    state = State::Idle { driver, timer };
+                    continue;
+                }
+                Poll::Ready(Err(err)) => {
+                    return (Err(anyhow!("{:?}", err)), State::SetSleepMode { future });
+                }
+                Poll::Pending => {
+                    return (Ok(FsmStatus::Pending), State::SetSleepMode { future });
+                }
+            },
             State::StepDelay { driver, mut timer } => {
                 match timer.wait() {
                     Ok(()) => {
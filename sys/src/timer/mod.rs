@@ -1,13 +1,51 @@
 mod timer;
 
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use aa_foundation::thread::get_thread_timerslack;
 use aa_foundation::trace_category;
-use timer::Timer;
+pub use timer::Timer;
 
 trace_category!("timer");
 
 pub const RATE_1MHZ: u32 = 1_000_000;
 
+/// Waits that finish later than this (in µs) are considered late
+const LATE_THRESHOLD_US: i64 = 50;
+
+static WAITS: AtomicU64 = AtomicU64::new(0);
+static LATE_WAITS: AtomicU64 = AtomicU64::new(0);
+static LATENESS_US: AtomicU64 = AtomicU64::new(0);
+
+/// Totals of every software timer's waits since the process started
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimerStats {
+    pub waits: u64,
+    /// The number of waits that finished late
+    pub late_waits: u64,
+    /// The sum of the time by which all waits finished late, in µs
+    pub lateness_us: u64,
+}
+
+pub fn timer_stats() -> TimerStats {
+    TimerStats {
+        waits: WAITS.load(Ordering::Relaxed),
+        late_waits: LATE_WAITS.load(Ordering::Relaxed),
+        lateness_us: LATENESS_US.load(Ordering::Relaxed),
+    }
+}
+
+/// Records a finished wait, which ended `diff_us` after its intended duration.
+fn record_wait(diff_us: i64) {
+    WAITS.fetch_add(1, Ordering::Relaxed);
+    if diff_us > 0 {
+        LATENESS_US.fetch_add(diff_us as u64, Ordering::Relaxed);
+    }
+    if diff_us > LATE_THRESHOLD_US {
+        LATE_WAITS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 pub fn make_software_timer() -> Timer<RATE_1MHZ> {
     assert!(
         get_thread_timerslack() == 1,
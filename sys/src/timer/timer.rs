@@ -6,6 +6,7 @@ use anyhow::anyhow;
 use fugit::RateExtU32;
 
 use super::tracing::*;
+use super::{record_wait, LATE_THRESHOLD_US};
 
 #[derive(Clone)]
 pub struct Timer<const TIMER_HZ: u32> {
@@ -114,7 +115,8 @@ impl<const TIMER_HZ: u32> fugit_timer::Timer<TIMER_HZ> for Timer<TIMER_HZ> {
             duration / 1000,
             diff,
         );
-        if diff > 50 {
+        record_wait(diff);
+        if diff > LATE_THRESHOLD_US {
             warning!("timer off by {:+}µs", diff);
         }
 
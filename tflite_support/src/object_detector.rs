@@ -14,14 +14,26 @@ use crate::bindings::{
 };
 use crate::error::Error;
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct BaseOptions {
     pub model_path: String,
+    /// The number of threads used by the CPU, or -1 to let TFLite decide
     pub num_threads: i32,
     #[cfg(feature = "coral_tpu")]
     pub enable_coral: bool,
 }
 
+impl Default for BaseOptions {
+    fn default() -> Self {
+        Self {
+            model_path: Default::default(),
+            num_threads: -1,
+            #[cfg(feature = "coral_tpu")]
+            enable_coral: false,
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct DetectionOptions {
     pub score_threshold: Option<f32>,
@@ -46,7 +58,7 @@ impl ObjectDetector {
         unsafe {
             let mut native_options: TfLiteObjectDetectorOptions =
                 TfLiteObjectDetectorOptionsCreate();
-            let mut compute_settings = native_options.base_options.compute_settings;
+            let compute_settings = &mut native_options.base_options.compute_settings;
             compute_settings.cpu_settings.num_threads = base_options.num_threads;
             #[cfg(feature = "coral_tpu")]
             {
@@ -0,0 +1,605 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use arena_autocam::config::PreviewMode;
+use arena_autocam::control::{ChunkSummary, Control, PanTiltStatus, SessionStatus};
+use rocket::http::ContentType;
+use rocket::response::content::RawHtml;
+use rocket::response::stream::{ByteStream, Event, EventStream};
+use rocket::response::Redirect;
+use rocket::serde::json::{Json, Value};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::tokio::sync::broadcast::Sender;
+use rocket::tokio::{select, time};
+use rocket::{Route, Shutdown, State};
+
+/// How often the preview stream checks for a new frame
+const PREVIEW_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Separates the frames of the preview stream
+const MJPEG_BOUNDARY: &str = "frame";
+
+type ControlState = State<Arc<dyn Control>>;
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        session_status,
+        start_session,
+        stop_session,
+        get_config,
+        patch_config,
+        list_chunks,
+        pantilt_status,
+        nudge_pan,
+        preview_stream,
+        events,
+    ]
+}
+
+/// An `AAMessage` in its JSON form, as relayed to event streams
+#[derive(Clone, Debug)]
+pub struct RelayedMessage {
+    pub kind: String,
+    pub json: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ErrorBody {
+    error: String,
+}
+
+#[derive(Debug, Responder)]
+pub enum ApiError {
+    /// The request couldn't be applied, like an invalid configuration patch
+    #[response(status = 400)]
+    BadRequest(Json<ErrorBody>),
+    /// The requested resource isn't available, like the preview when it's disabled
+    #[response(status = 404)]
+    NotFound(Json<ErrorBody>),
+    /// The request conflicts with the application's state, like stopping a session when
+    /// none is recording
+    #[response(status = 409)]
+    Conflict(Json<ErrorBody>),
+    #[response(status = 500)]
+    Internal(Json<ErrorBody>),
+}
+
+impl ApiError {
+    fn body(err: anyhow::Error) -> Json<ErrorBody> {
+        Json(ErrorBody {
+            error: format!("{:#}", err),
+        })
+    }
+
+    fn bad_request(err: anyhow::Error) -> Self {
+        Self::BadRequest(Self::body(err))
+    }
+
+    fn not_found(err: anyhow::Error) -> Self {
+        Self::NotFound(Self::body(err))
+    }
+
+    fn conflict(err: anyhow::Error) -> Self {
+        Self::Conflict(Self::body(err))
+    }
+
+    fn internal(err: anyhow::Error) -> Self {
+        Self::Internal(Self::body(err))
+    }
+}
+
+#[get("/session")]
+fn session_status(control: &ControlState) -> Json<SessionStatus> {
+    Json(control.session_status())
+}
+
+#[post("/session/start")]
+fn start_session(control: &ControlState) -> ApiResult<SessionStatus> {
+    let session_id = control.start_session().map_err(ApiError::conflict)?;
+    Ok(Json(SessionStatus {
+        active_session_id: Some(session_id),
+    }))
+}
+
+#[post("/session/stop")]
+fn stop_session(control: &ControlState) -> ApiResult<SessionStatus> {
+    control.stop_session().map_err(ApiError::conflict)?;
+    Ok(Json(control.session_status()))
+}
+
+#[get("/config")]
+fn get_config(control: &ControlState) -> ApiResult<Value> {
+    control.config().map(Json).map_err(ApiError::internal)
+}
+
+#[patch("/config", data = "<patch>")]
+fn patch_config(control: &ControlState, patch: Json<Value>) -> ApiResult<Value> {
+    control
+        .patch_config(patch.into_inner())
+        .map(Json)
+        .map_err(ApiError::bad_request)
+}
+
+#[get("/chunks")]
+fn list_chunks(control: &ControlState) -> ApiResult<Vec<ChunkSummary>> {
+    control.chunks().map(Json).map_err(ApiError::internal)
+}
+
+#[get("/pantilt")]
+fn pantilt_status(control: &ControlState) -> Json<PanTiltStatus> {
+    Json(control.pantilt_status())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Nudge {
+    /// The number of steps to move the pan target. Positive values pan right.
+    pan_steps: f64,
+}
+
+#[post("/pantilt/nudge", data = "<nudge>")]
+fn nudge_pan(control: &ControlState, nudge: Json<Nudge>) -> ApiResult<PanTiltStatus> {
+    control
+        .nudge_pan(nudge.pan_steps)
+        .map(Json)
+        .map_err(ApiError::internal)
+}
+
+/// Streams the preview as Motion JPEG, which browsers can show in an `<img>`.
+#[get("/preview.mjpeg")]
+fn preview_stream(
+    control: &ControlState,
+    mut shutdown: Shutdown,
+) -> Result<(ContentType, ByteStream![Vec<u8>]), ApiError> {
+    let frames = control
+        .preview_frames()
+        .ok_or_else(|| ApiError::not_found(anyhow!("The mjpeg preview is not enabled")))?;
+    let content_type = ContentType::new("multipart", "x-mixed-replace")
+        .with_params(("boundary", MJPEG_BOUNDARY));
+
+    Ok((
+        content_type,
+        ByteStream! {
+            let mut interval = time::interval(PREVIEW_POLL_INTERVAL);
+            let mut last_sequence = 0;
+            loop {
+                select! {
+                    _ = interval.tick() => {},
+                    _ = &mut shutdown => break,
+                }
+                match frames.latest() {
+                    Some(frame) if frame.sequence != last_sequence => {
+                        last_sequence = frame.sequence;
+                        yield mjpeg_part(&frame.jpeg);
+                    }
+                    _ => {}
+                }
+            }
+        },
+    ))
+}
+
+/// Relays the application's messages as server-sent events, named by their kind. If
+/// `kinds` is provided, only messages of those comma-separated kinds are sent.
+#[get("/events?<kinds>")]
+fn events(
+    events: &State<Sender<RelayedMessage>>,
+    kinds: Option<&str>,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let mut receiver = events.subscribe();
+    let kinds: Option<Vec<String>> = kinds.map(|kinds| {
+        kinds
+            .split(',')
+            .map(|kind| kind.trim().to_owned())
+            .collect()
+    });
+
+    EventStream! {
+        loop {
+            let message = select! {
+                message = receiver.recv() => match message {
+                    Ok(message) => message,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(count)) => {
+                        warn_!("Event stream fell behind, skipped {} messages", count);
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+
+            if kinds.as_ref().map_or(true, |kinds| kinds.contains(&message.kind)) {
+                yield Event::data(message.json).event(message.kind);
+            }
+        }
+    }
+}
+
+/// Returns a single frame of a Motion JPEG stream.
+fn mjpeg_part(jpeg: &[u8]) -> Vec<u8> {
+    let mut part = format!(
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        MJPEG_BOUNDARY,
+        jpeg.len()
+    )
+    .into_bytes();
+    part.extend_from_slice(jpeg);
+    part.extend_from_slice(b"\r\n");
+    part
+}
+
+/// Sends visitors to the preview, which is what they're after when browsing to the
+/// camera from a phone.
+#[get("/")]
+pub fn index() -> Redirect {
+    Redirect::to(uri!(preview_page))
+}
+
+/// A page that shows the preview full screen, for watching from a phone. Only the
+/// `mjpeg` preview is served from here, so the `webrtc` page explains where to find it.
+#[get("/preview")]
+pub fn preview_page(control: &ControlState) -> Result<RawHtml<&'static str>, ApiError> {
+    match control.preview_mode() {
+        PreviewMode::Mjpeg => Ok(RawHtml(include_str!("../static/preview.html"))),
+        PreviewMode::Webrtc => Ok(RawHtml(include_str!("../static/preview-webrtc.html"))),
+        PreviewMode::None | PreviewMode::Window => Err(ApiError::not_found(anyhow!(
+            "The network preview is not enabled"
+        ))),
+    }
+}
+
+/// The application's metrics, in the Prometheus text format.
+#[get("/metrics")]
+pub fn metrics(control: &ControlState) -> Result<(ContentType, String), ApiError> {
+    let content_type = ContentType::new("text", "plain").with_params(("version", "0.0.4"));
+    let metrics = control.metrics().map_err(ApiError::internal)?;
+    Ok((content_type, metrics))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::{anyhow, Result};
+    use arena_autocam::config::PreviewMode;
+    use arena_autocam::control::{
+        ChunkSummary, Control, MessageListener, PanTiltStatus, SessionStatus,
+    };
+    use arena_autocam::message::AAMessage;
+    use arena_autocam::preview::PreviewFrames;
+    use rocket::http::{ContentType, Status};
+    use rocket::local::blocking::Client;
+    use rocket::serde::json::{json, Value};
+
+    use crate::build_rocket;
+
+    struct FakeControl {
+        session: Mutex<Option<String>>,
+        config: Mutex<Value>,
+        pantilt: Mutex<PanTiltStatus>,
+        preview_mode: PreviewMode,
+        preview: Option<PreviewFrames>,
+        listeners: Mutex<Vec<MessageListener>>,
+    }
+
+    impl FakeControl {
+        fn post(&self, message: &AAMessage) {
+            for listener in self.listeners.lock().unwrap().iter() {
+                listener(message);
+            }
+        }
+    }
+
+    impl Default for FakeControl {
+        fn default() -> Self {
+            Self {
+                session: Mutex::new(None),
+                config: Mutex::new(json!({ "tracking": { "pan_gain": 0.5 } })),
+                pantilt: Mutex::new(PanTiltStatus::default()),
+                preview_mode: PreviewMode::None,
+                preview: None,
+                listeners: Mutex::new(vec![]),
+            }
+        }
+    }
+
+    impl Control for FakeControl {
+        fn start_session(&self) -> Result<String> {
+            let mut session = self.session.lock().unwrap();
+            if session.is_some() {
+                return Err(anyhow!("A session is already recording"));
+            }
+            *session = Some("session-1".into());
+            Ok("session-1".into())
+        }
+
+        fn stop_session(&self) -> Result<()> {
+            self.session
+                .lock()
+                .unwrap()
+                .take()
+                .map(|_| ())
+                .ok_or(anyhow!("No session is recording"))
+        }
+
+        fn session_status(&self) -> SessionStatus {
+            SessionStatus {
+                active_session_id: self.session.lock().unwrap().clone(),
+            }
+        }
+
+        fn config(&self) -> Result<Value> {
+            Ok(self.config.lock().unwrap().clone())
+        }
+
+        fn patch_config(&self, patch: Value) -> Result<Value> {
+            let gain = patch["tracking"]["pan_gain"]
+                .as_f64()
+                .ok_or(anyhow!("tracking.pan_gain must be a number"))?;
+            let mut config = self.config.lock().unwrap();
+            config["tracking"]["pan_gain"] = json!(gain);
+            Ok(config.clone())
+        }
+
+        fn chunks(&self) -> Result<Vec<ChunkSummary>> {
+            Ok(vec![ChunkSummary {
+                path: PathBuf::from("/tmp/chunk_0001.mp4"),
+                size_bytes: 1024,
+                modified_at: "2022-11-01T12:00:00+00:00".into(),
+                upload_pending: true,
+            }])
+        }
+
+        fn pantilt_status(&self) -> PanTiltStatus {
+            *self.pantilt.lock().unwrap()
+        }
+
+        fn nudge_pan(&self, steps: f64) -> Result<PanTiltStatus> {
+            let mut pantilt = self.pantilt.lock().unwrap();
+            pantilt.pan_target += steps;
+            Ok(*pantilt)
+        }
+
+        fn preview_mode(&self) -> PreviewMode {
+            self.preview_mode
+        }
+
+        fn preview_frames(&self) -> Option<PreviewFrames> {
+            self.preview.clone()
+        }
+
+        fn metrics(&self) -> Result<String> {
+            Ok("aa_inference_frames_total 12\n".into())
+        }
+
+        fn subscribe(&self, listener: MessageListener) {
+            self.listeners.lock().unwrap().push(listener);
+        }
+    }
+
+    fn client() -> Client {
+        client_with(Arc::new(FakeControl::default()))
+    }
+
+    fn client_with(control: Arc<FakeControl>) -> Client {
+        Client::tracked(build_rocket(control)).expect("valid rocket instance")
+    }
+
+    #[test]
+    fn test_session_start_and_stop() {
+        let client = client();
+
+        let response = client.post("/api/session/start").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_json::<Value>().unwrap(),
+            json!({ "active_session_id": "session-1" })
+        );
+        assert_eq!(
+            client.post("/api/session/start").dispatch().status(),
+            Status::Conflict
+        );
+
+        let response = client.post("/api/session/stop").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_json::<Value>().unwrap(),
+            json!({ "active_session_id": null })
+        );
+        assert_eq!(
+            client.post("/api/session/stop").dispatch().status(),
+            Status::Conflict
+        );
+    }
+
+    #[test]
+    fn test_patch_config() {
+        let client = client();
+
+        let response = client
+            .patch("/api/config")
+            .header(ContentType::JSON)
+            .body(r#"{ "tracking": { "pan_gain": 0.75 } }"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            client
+                .get("/api/config")
+                .dispatch()
+                .into_json::<Value>()
+                .unwrap(),
+            json!({ "tracking": { "pan_gain": 0.75 } })
+        );
+
+        let response = client
+            .patch("/api/config")
+            .header(ContentType::JSON)
+            .body(r#"{ "tracking": { "pan_gain": "fast" } }"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(
+            response.into_json::<Value>().unwrap()["error"],
+            "tracking.pan_gain must be a number"
+        );
+    }
+
+    #[test]
+    fn test_list_chunks() {
+        let response = client().get("/api/chunks").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let chunks = response.into_json::<Value>().unwrap();
+        assert_eq!(chunks[0]["path"], "/tmp/chunk_0001.mp4");
+        assert_eq!(chunks[0]["upload_pending"], true);
+    }
+
+    #[test]
+    fn test_nudge_pan() {
+        let client = client();
+
+        let response = client
+            .post("/api/pantilt/nudge")
+            .header(ContentType::JSON)
+            .body(r#"{ "pan_steps": -40.0 }"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            client
+                .get("/api/pantilt")
+                .dispatch()
+                .into_json::<Value>()
+                .unwrap()["pan_target"],
+            -40.0
+        );
+    }
+
+    #[test]
+    fn test_preview_stream() {
+        let frames = PreviewFrames::default();
+        frames.publish(b"jpeg".to_vec());
+        let client = client_with(Arc::new(FakeControl {
+            preview_mode: PreviewMode::Mjpeg,
+            preview: Some(frames),
+            ..FakeControl::default()
+        }));
+
+        let mut response = client.get("/api/preview.mjpeg").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.content_type().unwrap().to_string(),
+            "multipart/x-mixed-replace; boundary=frame"
+        );
+
+        let expected =
+            b"--frame\r\nContent-Type: image/jpeg\r\nContent-Length: 4\r\n\r\njpeg\r\n";
+        let mut part = vec![0; expected.len()];
+        response.read_exact(&mut part).unwrap();
+        assert_eq!(part, expected);
+    }
+
+    #[test]
+    fn test_preview_stream_disabled() {
+        assert_eq!(
+            client().get("/api/preview.mjpeg").dispatch().status(),
+            Status::NotFound
+        );
+    }
+
+    /// Returns a client for an application previewing in `preview_mode`.
+    fn preview_client(preview_mode: PreviewMode) -> Client {
+        client_with(Arc::new(FakeControl {
+            preview_mode,
+            preview: (preview_mode == PreviewMode::Mjpeg).then(PreviewFrames::default),
+            ..FakeControl::default()
+        }))
+    }
+
+    #[test]
+    fn test_index_redirects_to_preview() {
+        for mode in [PreviewMode::None, PreviewMode::Webrtc, PreviewMode::Mjpeg] {
+            let response = preview_client(mode).get("/").dispatch();
+            assert_eq!(response.status(), Status::SeeOther, "{:?}", mode);
+            assert_eq!(response.headers().get_one("Location"), Some("/preview"));
+        }
+    }
+
+    #[test]
+    fn test_preview_page() {
+        let response = preview_client(PreviewMode::Mjpeg)
+            .get("/preview")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains(r#"<img src="/api/preview.mjpeg""#));
+
+        let response = preview_client(PreviewMode::Webrtc)
+            .get("/preview")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        let page = response.into_string().unwrap();
+        assert!(page.contains("WebRTC"), "{}", page);
+        assert!(!page.contains("preview.mjpeg"), "{}", page);
+
+        for mode in [PreviewMode::None, PreviewMode::Window] {
+            let response = preview_client(mode).get("/preview").dispatch();
+            assert_eq!(response.status(), Status::NotFound, "{:?}", mode);
+            assert_eq!(
+                response.into_json::<Value>().unwrap()["error"],
+                "The network preview is not enabled"
+            );
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let response = client().get("/metrics").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.content_type().unwrap().to_string(),
+            "text/plain; version=0.0.4"
+        );
+        assert_eq!(
+            response.into_string().unwrap(),
+            "aa_inference_frames_total 12\n"
+        );
+    }
+
+    #[test]
+    fn test_events() {
+        let control = Arc::new(FakeControl::default());
+        let client = client_with(control.clone());
+
+        let mut response = client.get("/api/events?kinds=session-started").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::EventStream));
+
+        control.post(&AAMessage::SessionStopped {
+            session_id: "session-0".into(),
+        });
+        control.post(&AAMessage::SessionStarted {
+            session_id: "session-1".into(),
+        });
+
+        // Read a single event, which ends with a blank line
+        let mut event = vec![];
+        let mut byte = [0];
+        while !event.ends_with(b"\n\n") {
+            response.read_exact(&mut byte).unwrap();
+            event.push(byte[0]);
+        }
+        let event = String::from_utf8(event).unwrap();
+        assert!(event.contains("session-started"), "{}", event);
+        assert!(event.contains(r#""session_id":"session-1""#), "{}", event);
+        assert!(!event.contains("session-0"), "{}", event);
+    }
+}
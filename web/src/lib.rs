@@ -1 +1,40 @@
+//! Serves an HTTP API for controlling a running Arena Autocam.
+#[macro_use]
+extern crate rocket;
 
+mod api;
+
+use std::sync::Arc;
+
+use arena_autocam::control::Control;
+use rocket::tokio::sync::broadcast;
+use rocket::{Build, Rocket};
+
+use self::api::RelayedMessage;
+
+/// The number of messages held for each event stream. Streams that fall further behind
+/// skip ahead to the most recent messages.
+const EVENT_CAPACITY: usize = 256;
+
+/// Builds the web server, with its API driving `control`.
+pub fn build_rocket(control: Arc<dyn Control>) -> Rocket<Build> {
+    // Messages are serialized once as they're posted, rather than once per event stream
+    let (events, _) = broadcast::channel::<RelayedMessage>(EVENT_CAPACITY);
+    let sender = events.clone();
+    control.subscribe(Box::new(move |message| match message.to_json() {
+        Ok(json) => {
+            // Fails when no streams are connected, which is fine
+            let _ = sender.send(RelayedMessage {
+                kind: message.to_string(),
+                json,
+            });
+        }
+        Err(err) => error!("Failed to serialize {}, {}", message, err),
+    }));
+
+    rocket::build()
+        .manage(control)
+        .manage(events)
+        .mount("/", routes![api::index, api::preview_page, api::metrics])
+        .mount("/api", api::routes())
+}
@@ -1,12 +1,61 @@
-#[macro_use]
-extern crate rocket;
+use std::path::PathBuf;
+use std::sync::Arc;
 
-#[get("/")]
-fn index() -> &'static str {
-    "Hello, world!"
+use aa_foundation::tracing::setup_dev_tracing_subscriber;
+use aa_web::build_rocket;
+use anyhow::{anyhow, Result};
+use arena_autocam::config::Config;
+use arena_autocam::control::AppControl;
+use arena_autocam::pipeline::{configure_pipeline, create_pipeline, run_main_loop};
+use arena_autocam::system::init_hardware_systems;
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// Sets a custom config file
+    #[arg(short, long, value_name = "FILE")]
+    pub config_path: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub config: Config,
 }
 
-#[launch]
-fn rocket() -> _ {
-    rocket::build().mount("/", routes![index])
+/// Runs Arena Autocam, along with a web server for controlling it.
+#[rocket::main]
+async fn main() -> Result<()> {
+    setup_dev_tracing_subscriber();
+    eprintln!("\nStarting Arena Autocam with web control");
+
+    let args = Args::parse();
+    let config = Config::new(args.config_path, args.config)?;
+    let configured = create_pipeline(&config).and_then(|res| {
+        let hardware = init_hardware_systems(&config)?;
+        configure_pipeline(&config, hardware, res)
+    })?;
+
+    // The pipeline handles termination signals itself, so that recordings are finalized
+    // before the server shuts down
+    let figment = rocket::Config::figment()
+        .merge(("shutdown.ctrlc", false))
+        .merge(("shutdown.signals", Vec::<String>::new()));
+    let rocket = build_rocket(Arc::new(AppControl::new(&configured)))
+        .configure(figment)
+        .ignite()
+        .await?;
+
+    let shutdown = rocket.shutdown();
+    let pipeline_thread =
+        std::thread::Builder::new()
+            .name("pipeline".into())
+            .spawn(move || {
+                let result = run_main_loop(configured);
+                shutdown.notify();
+                result
+            })?;
+
+    rocket.launch().await?;
+    pipeline_thread
+        .join()
+        .map_err(|_| anyhow!("The pipeline thread panicked"))?
 }